@@ -0,0 +1,329 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{Metric, METRIC_COUNT};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparator {
+    Above,
+    Below,
+}
+
+/// Fires when `metric` stays on the wrong side of `threshold` for
+/// `duration_secs`, and resolves once it is back by more than `hysteresis`.
+/// Applies to `devices` and the devices of `group`, or to every device when
+/// neither is given.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: Metric,
+    pub comparator: Comparator,
+    pub threshold: f32,
+    #[serde(default)]
+    pub duration_secs: u64,
+    #[serde(default)]
+    pub hysteresis: f32,
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub group: Option<String>,
+}
+
+impl AlertRule {
+    fn breached(&self, value: f32) -> bool {
+        match self.comparator {
+            Comparator::Above => value > self.threshold,
+            Comparator::Below => value < self.threshold,
+        }
+    }
+
+    fn cleared(&self, value: f32) -> bool {
+        match self.comparator {
+            Comparator::Above => value < self.threshold - self.hysteresis,
+            Comparator::Below => value > self.threshold + self.hysteresis,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+impl AlertState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertState::Firing => "firing",
+            AlertState::Resolved => "resolved",
+        }
+    }
+}
+
+/// A rule starting or stopping to fire for a device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertTransition {
+    pub rule: String,
+    pub device_id: String,
+    pub metric: Metric,
+    pub state: AlertState,
+    /// The reading that caused the transition.
+    pub value: f32,
+    pub threshold: f32,
+    pub at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RuleState {
+    /// Breached since then, waiting for the duration to pass.
+    Pending(DateTime<Local>),
+    Firing,
+}
+
+/// Tracks every rule for every device. Rules without state are not breached.
+#[derive(Debug, Default)]
+pub struct AlertEngine {
+    /// Rules with the devices they apply to, `None` for every device.
+    rules: Vec<(AlertRule, Option<HashSet<String>>)>,
+    states: Mutex<HashMap<(usize, String), RuleState>>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: &[AlertRule], groups: &HashMap<String, Vec<String>>) -> Self {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let mut devices = rule.devices.iter().cloned().collect::<HashSet<_>>();
+
+                if let Some(group) = rule.group.as_ref().and_then(|g| groups.get(g)) {
+                    devices.extend(group.iter().cloned());
+                }
+
+                let scoped = !rule.devices.is_empty() || rule.group.is_some();
+
+                (rule.clone(), scoped.then_some(devices))
+            })
+            .collect();
+
+        Self {
+            rules,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Marks rules as firing, for picking up the stored state after a restart.
+    pub fn restore(&self, firing: impl IntoIterator<Item = (String, String)>) {
+        let mut states = self.states.lock().unwrap();
+
+        for (rule, device_id) in firing {
+            if let Some(i) = self.rules.iter().position(|(r, _)| r.name == rule) {
+                states.insert((i, device_id), RuleState::Firing);
+            }
+        }
+    }
+
+    /// Feeds one reading of a device through every rule, returning the rules
+    /// that started or stopped firing. Missing values leave a rule as it is.
+    pub fn evaluate(
+        &self,
+        device_id: &str,
+        values: &[Option<f32>; METRIC_COUNT],
+        now: DateTime<Local>,
+    ) -> Vec<AlertTransition> {
+        let mut states = self.states.lock().unwrap();
+        let mut transitions = Vec::new();
+
+        for (i, (rule, devices)) in self.rules.iter().enumerate() {
+            if devices.as_ref().is_some_and(|d| !d.contains(device_id)) {
+                continue;
+            }
+
+            let Some(value) = values[rule.metric.index()] else {
+                continue;
+            };
+
+            let key = (i, device_id.to_string());
+
+            let fired = match states.get(&key).copied() {
+                None if rule.breached(value) => {
+                    states.insert(key.clone(), RuleState::Pending(now));
+                    rule.duration_secs == 0
+                }
+                Some(RuleState::Pending(since)) if rule.breached(value) => {
+                    now - since >= Duration::seconds(rule.duration_secs as i64)
+                }
+                Some(RuleState::Pending(_)) => {
+                    states.remove(&key);
+                    false
+                }
+                Some(RuleState::Firing) if rule.cleared(value) => {
+                    states.remove(&key);
+                    transitions.push(transition(
+                        rule,
+                        device_id,
+                        AlertState::Resolved,
+                        value,
+                        now,
+                    ));
+                    false
+                }
+                _ => false,
+            };
+
+            if fired {
+                states.insert(key, RuleState::Firing);
+                transitions.push(transition(rule, device_id, AlertState::Firing, value, now));
+            }
+        }
+
+        transitions
+    }
+}
+
+fn transition(
+    rule: &AlertRule,
+    device_id: &str,
+    state: AlertState,
+    value: f32,
+    at: DateTime<Local>,
+) -> AlertTransition {
+    AlertTransition {
+        rule: rule.name.clone(),
+        device_id: device_id.to_string(),
+        metric: rule.metric,
+        state,
+        value,
+        threshold: rule.threshold,
+        at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: &str = "abcdefghijklmno";
+
+    fn rule(duration_secs: u64) -> AlertRule {
+        AlertRule {
+            name: "high-co".to_string(),
+            metric: Metric::Co,
+            comparator: Comparator::Above,
+            threshold: 35.0,
+            duration_secs,
+            hysteresis: 5.0,
+            devices: Vec::new(),
+            group: None,
+        }
+    }
+
+    fn co(value: f32) -> [Option<f32>; METRIC_COUNT] {
+        let mut values = [None; METRIC_COUNT];
+        values[Metric::Co.index()] = Some(value);
+        values
+    }
+
+    fn states(engine: &AlertEngine, readings: &[(i64, f32)]) -> Vec<(i64, AlertState)> {
+        let start = Local::now();
+
+        readings
+            .iter()
+            .flat_map(|&(secs, value)| {
+                engine
+                    .evaluate(DEVICE, &co(value), start + Duration::seconds(secs))
+                    .into_iter()
+                    .map(move |t| (secs, t.state))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fires_immediately_without_duration() {
+        let engine = AlertEngine::new(&[rule(0)], &HashMap::new());
+
+        assert_eq!(
+            states(&engine, &[(0, 10.0), (1, 40.0), (2, 41.0)]),
+            [(1, AlertState::Firing)]
+        );
+    }
+
+    #[test]
+    fn fires_after_duration() {
+        let engine = AlertEngine::new(&[rule(60)], &HashMap::new());
+
+        assert_eq!(
+            states(&engine, &[(0, 40.0), (30, 40.0), (60, 40.0), (90, 40.0)]),
+            [(60, AlertState::Firing)]
+        );
+    }
+
+    #[test]
+    fn short_breach_does_not_fire() {
+        let engine = AlertEngine::new(&[rule(60)], &HashMap::new());
+
+        assert_eq!(
+            states(&engine, &[(0, 40.0), (30, 20.0), (60, 40.0), (90, 40.0)]),
+            []
+        );
+    }
+
+    #[test]
+    fn resolves_past_hysteresis() {
+        let engine = AlertEngine::new(&[rule(0)], &HashMap::new());
+
+        assert_eq!(
+            states(&engine, &[(0, 40.0), (1, 32.0), (2, 29.0), (3, 40.0)]),
+            [
+                (0, AlertState::Firing),
+                (2, AlertState::Resolved),
+                (3, AlertState::Firing)
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_values_keep_state() {
+        let engine = AlertEngine::new(&[rule(0)], &HashMap::new());
+
+        engine.evaluate(DEVICE, &co(40.0), Local::now());
+
+        assert!(engine
+            .evaluate(DEVICE, &[None; METRIC_COUNT], Local::now())
+            .is_empty());
+        assert_eq!(
+            engine.evaluate(DEVICE, &co(10.0), Local::now())[0].state,
+            AlertState::Resolved
+        );
+    }
+
+    #[test]
+    fn scoped_rules_skip_other_devices() {
+        let mut scoped = rule(0);
+        scoped.group = Some("downtown".to_string());
+
+        let groups = HashMap::from([("downtown".to_string(), vec!["other".to_string()])]);
+        let engine = AlertEngine::new(&[scoped], &groups);
+
+        assert!(engine.evaluate(DEVICE, &co(40.0), Local::now()).is_empty());
+        assert_eq!(engine.evaluate("other", &co(40.0), Local::now()).len(), 1);
+    }
+
+    #[test]
+    fn restored_alerts_resolve() {
+        let engine = AlertEngine::new(&[rule(0)], &HashMap::new());
+
+        engine.restore([("high-co".to_string(), DEVICE.to_string())]);
+
+        assert_eq!(
+            states(&engine, &[(0, 40.0), (1, 10.0)]),
+            [(1, AlertState::Resolved)]
+        );
+    }
+}
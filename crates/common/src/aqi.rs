@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::metric::Metric;
+
+/// Pollutants an index is computed from. `pm_100` is PM10 and `co` is in ppm.
+pub const POLLUTANTS: [Metric; 3] = [Metric::Pm25, Metric::Pm100, Metric::Co];
+
+/// Hours PM concentrations are averaged over.
+pub const PM_WINDOW_HOURS: i64 = 24;
+/// Hours CO concentrations are averaged over.
+pub const CO_WINDOW_HOURS: i64 = 8;
+
+/// Converts ppm of CO to mg/m³ at 25 °C and 1 atm.
+const CO_PPM_TO_MG_M3: f64 = 1.145;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AqiStandard {
+    /// US EPA AQI with the 2024 PM2.5 breakpoints.
+    #[default]
+    UsEpa,
+    /// The European Common Air Quality Index, daily grid.
+    EuCaqi,
+    /// India's National Air Quality Index.
+    IndiaNaqi,
+}
+
+/// `(c_lo, c_hi, i_lo, i_hi)`, concentration range mapped onto an index range.
+type Segment = (f64, f64, f64, f64);
+
+impl AqiStandard {
+    /// Breakpoints of a pollutant, in the unit `convert` produces.
+    fn segments(self, metric: Metric) -> &'static [Segment] {
+        match (self, metric) {
+            (AqiStandard::UsEpa, Metric::Pm25) => &[
+                (0.0, 9.0, 0.0, 50.0),
+                (9.1, 35.4, 51.0, 100.0),
+                (35.5, 55.4, 101.0, 150.0),
+                (55.5, 125.4, 151.0, 200.0),
+                (125.5, 225.4, 201.0, 300.0),
+                (225.5, 325.4, 301.0, 500.0),
+            ],
+            (AqiStandard::UsEpa, Metric::Pm100) => &[
+                (0.0, 54.0, 0.0, 50.0),
+                (55.0, 154.0, 51.0, 100.0),
+                (155.0, 254.0, 101.0, 150.0),
+                (255.0, 354.0, 151.0, 200.0),
+                (355.0, 424.0, 201.0, 300.0),
+                (425.0, 604.0, 301.0, 500.0),
+            ],
+            (AqiStandard::UsEpa, _) => &[
+                (0.0, 4.4, 0.0, 50.0),
+                (4.5, 9.4, 51.0, 100.0),
+                (9.5, 12.4, 101.0, 150.0),
+                (12.5, 15.4, 151.0, 200.0),
+                (15.5, 30.4, 201.0, 300.0),
+                (30.5, 50.4, 301.0, 500.0),
+            ],
+            (AqiStandard::EuCaqi, Metric::Pm25) => &[
+                (0.0, 15.0, 0.0, 25.0),
+                (15.0, 30.0, 25.0, 50.0),
+                (30.0, 55.0, 50.0, 75.0),
+                (55.0, 110.0, 75.0, 100.0),
+            ],
+            (AqiStandard::EuCaqi, Metric::Pm100) => &[
+                (0.0, 25.0, 0.0, 25.0),
+                (25.0, 50.0, 25.0, 50.0),
+                (50.0, 90.0, 50.0, 75.0),
+                (90.0, 180.0, 75.0, 100.0),
+            ],
+            (AqiStandard::EuCaqi, _) => &[
+                (0.0, 5000.0, 0.0, 25.0),
+                (5000.0, 7500.0, 25.0, 50.0),
+                (7500.0, 10000.0, 50.0, 75.0),
+                (10000.0, 20000.0, 75.0, 100.0),
+            ],
+            // The top NAQI bands are open ended, they are spread over a range
+            // as wide as the band below them.
+            (AqiStandard::IndiaNaqi, Metric::Pm25) => &[
+                (0.0, 30.0, 0.0, 50.0),
+                (31.0, 60.0, 51.0, 100.0),
+                (61.0, 90.0, 101.0, 200.0),
+                (91.0, 120.0, 201.0, 300.0),
+                (121.0, 250.0, 301.0, 400.0),
+                (251.0, 380.0, 401.0, 500.0),
+            ],
+            (AqiStandard::IndiaNaqi, Metric::Pm100) => &[
+                (0.0, 50.0, 0.0, 50.0),
+                (51.0, 100.0, 51.0, 100.0),
+                (101.0, 250.0, 101.0, 200.0),
+                (251.0, 350.0, 201.0, 300.0),
+                (351.0, 430.0, 301.0, 400.0),
+                (431.0, 510.0, 401.0, 500.0),
+            ],
+            (AqiStandard::IndiaNaqi, _) => &[
+                (0.0, 1.0, 0.0, 50.0),
+                (1.1, 2.0, 51.0, 100.0),
+                (2.1, 10.0, 101.0, 200.0),
+                (10.1, 17.0, 201.0, 300.0),
+                (17.1, 34.0, 301.0, 400.0),
+                (34.1, 51.0, 401.0, 500.0),
+            ],
+        }
+    }
+
+    /// Brings an average into the unit and precision the breakpoints use.
+    fn convert(self, metric: Metric, value: f64) -> f64 {
+        let truncate = |value: f64, decimals: i32| {
+            let factor = 10f64.powi(decimals);
+            (value * factor).trunc() / factor
+        };
+
+        match (self, metric) {
+            (AqiStandard::UsEpa, Metric::Pm100) => truncate(value, 0),
+            (AqiStandard::UsEpa, _) => truncate(value, 1),
+            // CAQI takes CO in µg/m³.
+            (AqiStandard::EuCaqi, Metric::Co) => value * CO_PPM_TO_MG_M3 * 1000.0,
+            (AqiStandard::EuCaqi, _) => value,
+            (AqiStandard::IndiaNaqi, Metric::Co) => truncate(value * CO_PPM_TO_MG_M3, 1),
+            (AqiStandard::IndiaNaqi, _) => truncate(value, 0),
+        }
+    }
+
+    /// Category names with the highest index value each one covers.
+    fn categories(self) -> &'static [(&'static str, f64)] {
+        match self {
+            AqiStandard::UsEpa => &[
+                ("Good", 50.0),
+                ("Moderate", 100.0),
+                ("Unhealthy for Sensitive Groups", 150.0),
+                ("Unhealthy", 200.0),
+                ("Very Unhealthy", 300.0),
+                ("Hazardous", f64::INFINITY),
+            ],
+            AqiStandard::EuCaqi => &[
+                ("Very low", 25.0),
+                ("Low", 50.0),
+                ("Medium", 75.0),
+                ("High", 100.0),
+                ("Very high", f64::INFINITY),
+            ],
+            AqiStandard::IndiaNaqi => &[
+                ("Good", 50.0),
+                ("Satisfactory", 100.0),
+                ("Moderate", 200.0),
+                ("Poor", 300.0),
+                ("Very Poor", 400.0),
+                ("Severe", f64::INFINITY),
+            ],
+        }
+    }
+
+    /// The highest value of the scale, CAQI has none.
+    fn cap(self) -> f64 {
+        match self {
+            AqiStandard::UsEpa | AqiStandard::IndiaNaqi => 500.0,
+            AqiStandard::EuCaqi => f64::INFINITY,
+        }
+    }
+
+    /// Sub-index of one pollutant average.
+    pub fn sub_index(self, metric: Metric, average: f64) -> u32 {
+        let c = self.convert(metric, average.max(0.0));
+        let segments = self.segments(metric);
+
+        // Values past the last breakpoint continue its slope up to the cap.
+        let (c_lo, c_hi, i_lo, i_hi) = segments
+            .iter()
+            .find(|(_, c_hi, _, _)| c <= *c_hi)
+            .unwrap_or(&segments[segments.len() - 1]);
+
+        let index = (i_hi - i_lo) / (c_hi - c_lo) * (c.max(*c_lo) - c_lo) + i_lo;
+
+        index.min(self.cap()).round() as u32
+    }
+
+    pub fn category(self, index: u32) -> &'static str {
+        self.categories()
+            .iter()
+            .find(|(_, max)| index as f64 <= *max)
+            .map(|(name, _)| *name)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Aqi {
+    pub standard: AqiStandard,
+    pub value: u32,
+    pub category: String,
+    /// The pollutant with the highest sub-index.
+    pub dominant: Metric,
+    pub sub_indices: BTreeMap<Metric, u32>,
+}
+
+impl Aqi {
+    /// Computes the index from the averages of the pollutants over their
+    /// windows. `None` when no pollutant has an average.
+    pub fn compute(standard: AqiStandard, averages: &BTreeMap<Metric, f64>) -> Option<Self> {
+        let sub_indices = POLLUTANTS
+            .into_iter()
+            .filter_map(|m| Some((m, standard.sub_index(m, *averages.get(&m)?))))
+            .collect::<BTreeMap<_, _>>();
+
+        let (&dominant, &value) = sub_indices.iter().max_by_key(|(_, i)| **i)?;
+
+        Some(Self {
+            standard,
+            value,
+            category: standard.category(value).to_string(),
+            dominant,
+            sub_indices,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts the sub-index of every `(average, index)` pair.
+    fn assert_indices(standard: AqiStandard, metric: Metric, cases: &[(f64, u32)]) {
+        for &(average, index) in cases {
+            assert_eq!(
+                standard.sub_index(metric, average),
+                index,
+                "{standard:?} {metric} at {average}"
+            );
+        }
+    }
+
+    #[test]
+    fn us_epa_breakpoints() {
+        let us = AqiStandard::UsEpa;
+
+        assert_indices(
+            us,
+            Metric::Pm25,
+            &[
+                (0.0, 0),
+                (9.0, 50),
+                // Truncated to one decimal before the lookup.
+                (9.09, 50),
+                (9.1, 51),
+                (35.4, 100),
+                (35.5, 101),
+                (225.5, 301),
+                (325.4, 500),
+                (1000.0, 500),
+            ],
+        );
+        assert_indices(
+            us,
+            Metric::Pm100,
+            &[
+                (54.0, 50),
+                (54.9, 50),
+                (55.0, 51),
+                (154.0, 100),
+                (604.0, 500),
+            ],
+        );
+        assert_indices(
+            us,
+            Metric::Co,
+            &[(4.4, 50), (4.5, 51), (9.4, 100), (9.5, 101), (50.4, 500)],
+        );
+    }
+
+    #[test]
+    fn eu_caqi_breakpoints() {
+        let eu = AqiStandard::EuCaqi;
+
+        assert_indices(
+            eu,
+            Metric::Pm25,
+            &[
+                (15.0, 25),
+                (30.0, 50),
+                (55.0, 75),
+                (110.0, 100),
+                (220.0, 150),
+            ],
+        );
+        assert_indices(
+            eu,
+            Metric::Pm100,
+            &[(25.0, 25), (50.0, 50), (90.0, 75), (180.0, 100)],
+        );
+        // ppm converted to µg/m³.
+        assert_indices(
+            eu,
+            Metric::Co,
+            &[
+                (5000.0 / 1145.0, 25),
+                (7500.0 / 1145.0, 50),
+                (20000.0 / 1145.0, 100),
+            ],
+        );
+    }
+
+    #[test]
+    fn india_naqi_breakpoints() {
+        let india = AqiStandard::IndiaNaqi;
+
+        assert_indices(
+            india,
+            Metric::Pm25,
+            &[
+                (30.0, 50),
+                (30.9, 50),
+                (31.0, 51),
+                (60.0, 100),
+                (61.0, 101),
+                (380.0, 500),
+                (1000.0, 500),
+            ],
+        );
+        assert_indices(
+            india,
+            Metric::Pm100,
+            &[(100.0, 100), (101.0, 101), (250.0, 200), (251.0, 201)],
+        );
+        // ppm converted to mg/m³.
+        assert_indices(india, Metric::Co, &[(0.8734, 50), (0.961, 51)]);
+    }
+
+    #[test]
+    fn negative_averages_count_as_zero() {
+        for standard in [
+            AqiStandard::UsEpa,
+            AqiStandard::EuCaqi,
+            AqiStandard::IndiaNaqi,
+        ] {
+            assert_eq!(standard.sub_index(Metric::Pm25, -5.0), 0);
+        }
+    }
+
+    #[test]
+    fn categories_change_at_band_edges() {
+        assert_eq!(AqiStandard::UsEpa.category(50), "Good");
+        assert_eq!(AqiStandard::UsEpa.category(51), "Moderate");
+        assert_eq!(AqiStandard::UsEpa.category(500), "Hazardous");
+        assert_eq!(AqiStandard::EuCaqi.category(100), "High");
+        assert_eq!(AqiStandard::EuCaqi.category(150), "Very high");
+        assert_eq!(AqiStandard::IndiaNaqi.category(200), "Moderate");
+        assert_eq!(AqiStandard::IndiaNaqi.category(401), "Severe");
+    }
+
+    #[test]
+    fn index_is_the_highest_sub_index() {
+        let averages = BTreeMap::from([
+            (Metric::Pm25, 35.5),
+            (Metric::Co, 4.5),
+            (Metric::Co2, 5000.0),
+        ]);
+
+        let aqi = Aqi::compute(AqiStandard::UsEpa, &averages).unwrap();
+
+        assert_eq!(aqi.value, 101);
+        assert_eq!(aqi.dominant, Metric::Pm25);
+        assert_eq!(aqi.category, "Unhealthy for Sensitive Groups");
+        assert_eq!(
+            aqi.sub_indices,
+            BTreeMap::from([(Metric::Pm25, 101), (Metric::Co, 51)])
+        );
+    }
+
+    #[test]
+    fn no_pollutant_has_no_index() {
+        let averages = BTreeMap::from([(Metric::Co2, 5000.0)]);
+
+        assert!(Aqi::compute(AqiStandard::UsEpa, &averages).is_none());
+    }
+}
@@ -0,0 +1,605 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use chrono::{DateTime, Duration, Local};
+use diesel::{
+    result::Error,
+    sql_types::{Integer, Text, Timestamptz},
+    BoolExpressionMethods, ExpressionMethods, QueryDsl,
+};
+use diesel_async::{
+    pooled_connection::bb8::PooledConnection, scoped_futures::ScopedFutureExt, AsyncPgConnection,
+    RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::schema::{aggregates, rollup_backfills},
+    metric::Metric,
+};
+
+use super::{
+    pagination::{HistoryCursor, Page},
+    Backend,
+};
+
+/// Bucket sizes of the rollups, in seconds.
+pub const FIVE_MINUTES: i32 = 300;
+pub const HOUR: i32 = 3600;
+pub const DAY: i32 = 86400;
+
+const AGGREGATE_COLUMNS: &str =
+    "fk_device_id, bucket_secs, bucket, metric, count, sum, sum_squares, min, max";
+
+/// Start of the second newest `$1` second bucket, or everything when there
+/// is none yet.
+const RECOMPUTE_SINCE: &str =
+    "SELECT COALESCE(max(bucket) - make_interval(secs => $1), '-infinity')
+    FROM aggregates WHERE bucket_secs = $1";
+
+/// Recomputed buckets replace the stored ones.
+const UPSERT: &str = "ON CONFLICT (fk_device_id, bucket_secs, metric, bucket) DO UPDATE SET
+    count = excluded.count, sum = excluded.sum, sum_squares = excluded.sum_squares,
+    min = excluded.min, max = excluded.max";
+
+/// Start of the UTC aligned `$1` second bucket holding `column`.
+fn bucket_start(column: &str) -> String {
+    format!("to_timestamp((floor(extract(epoch FROM {column}) / $1) * $1)::FLOAT8)")
+}
+
+/// Start of the first `bucket_secs` bucket wholly at or after `at`, buckets
+/// before it may have lost some of what they were computed from.
+fn first_whole_bucket(at: DateTime<Local>, bucket_secs: i32) -> DateTime<Local> {
+    let secs = at.timestamp() + (at.timestamp_subsec_nanos() > 0) as i64;
+    let start = (secs + bucket_secs as i64 - 1).div_euclid(bucket_secs as i64) * bucket_secs as i64;
+
+    DateTime::from_timestamp(start, 0)
+        .map(|t| t.with_timezone(&Local))
+        .unwrap_or(at)
+}
+
+/// Recomputes the `$1` second buckets holding the raw readings `r` matching
+/// `filter`.
+fn raw_rollup(filter: &str) -> String {
+    let columns = Metric::ALL
+        .into_iter()
+        .map(|m| format!("('{m}', r.{m})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "INSERT INTO aggregates ({AGGREGATE_COLUMNS})
+        SELECT r.fk_device_id, $1, {bucket}, m.metric,
+            count(*)::INTEGER, sum(m.value::FLOAT8), sum(m.value::FLOAT8 ^ 2),
+            min(m.value), max(m.value)
+        FROM readings r
+        CROSS JOIN LATERAL (VALUES {columns}) AS m(metric, value)
+        WHERE m.value IS NOT NULL AND {filter}
+        GROUP BY 1, 3, 4
+        {UPSERT}",
+        bucket = bucket_start("r.created_at"),
+    )
+}
+
+/// Recomputes the `$1` second buckets holding the `$2` second buckets
+/// matching `filter`.
+fn bucket_rollup(filter: &str) -> String {
+    format!(
+        "INSERT INTO aggregates ({AGGREGATE_COLUMNS})
+        SELECT fk_device_id, $1, {bucket}, metric,
+            sum(count)::INTEGER, sum(sum), sum(sum_squares), min(min), max(max)
+        FROM aggregates
+        WHERE bucket_secs = $2 AND {filter}
+        GROUP BY 1, 3, 4
+        {UPSERT}",
+        bucket = bucket_start("bucket"),
+    )
+}
+
+/// Size of the buckets a history is returned in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Resolution {
+    #[serde(rename = "raw")]
+    Raw,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    Day,
+}
+
+impl Resolution {
+    /// Bucket size of the rollup holding this resolution, `None` for raw.
+    pub fn bucket_secs(self) -> Option<i32> {
+        match self {
+            Resolution::Raw => None,
+            Resolution::FiveMinutes => Some(FIVE_MINUTES),
+            Resolution::Hour => Some(HOUR),
+            Resolution::Day => Some(DAY),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::FiveMinutes => "5m",
+            Resolution::Hour => "1h",
+            Resolution::Day => "1d",
+        }
+    }
+
+    /// The finest resolution that keeps a chart of the range to a few
+    /// hundred points.
+    pub fn for_range(from: DateTime<Local>, to: DateTime<Local>) -> Self {
+        let span = to - from;
+
+        if span <= Duration::days(1) {
+            Resolution::FiveMinutes
+        } else if span <= Duration::days(31) {
+            Resolution::Hour
+        } else {
+            Resolution::Day
+        }
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Resolution::Raw,
+            Resolution::FiveMinutes,
+            Resolution::Hour,
+            Resolution::Day,
+        ]
+        .into_iter()
+        .find(|r| r.as_str() == s)
+        .ok_or_else(|| format!("unknown resolution '{s}'"))
+    }
+}
+
+/// Summary of every reading of one metric within a bucket.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MetricAggregate {
+    pub count: i32,
+    pub mean: f64,
+    pub stddev: f64,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl MetricAggregate {
+    fn new(count: i32, sum: f64, sum_squares: f64, min: f32, max: f32) -> Self {
+        let n = count as f64;
+        let mean = sum / n;
+
+        Self {
+            count,
+            mean,
+            // Population deviation, rounding can push the variance just below 0.
+            stddev: (sum_squares / n - mean * mean).max(0.0).sqrt(),
+            min,
+            max,
+        }
+    }
+
+    fn single(value: f32) -> Self {
+        Self {
+            count: 1,
+            mean: value as f64,
+            stddev: 0.0,
+            min: value,
+            max: value,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AggregateReadings {
+    /// Start of the bucket.
+    pub bucket: DateTime<Local>,
+    #[serde(flatten)]
+    pub metrics: BTreeMap<Metric, MetricAggregate>,
+}
+
+/// A bucket in the shape of the hourly history from before rollups, the
+/// mean of every metric next to `created_at`. Kept for clients which still
+/// select `24H` or `7D`.
+#[derive(Debug, Serialize)]
+pub struct LegacyReadings {
+    #[serde(flatten)]
+    pub means: BTreeMap<Metric, Option<f64>>,
+    pub created_at: DateTime<Local>,
+}
+
+impl From<AggregateReadings> for LegacyReadings {
+    fn from(readings: AggregateReadings) -> Self {
+        Self {
+            means: Metric::ALL
+                .into_iter()
+                .map(|m| (m, readings.metrics.get(&m).map(|a| a.mean)))
+                .collect(),
+            created_at: readings.bucket,
+        }
+    }
+}
+
+type AggregateSelect = (DateTime<Local>, String, i32, f64, f64, f32, f32);
+
+/// Groups rows ordered by bucket into one entry per bucket. Rows of metrics
+/// this version does not know are left out.
+fn group_buckets(rows: Vec<AggregateSelect>) -> Vec<AggregateReadings> {
+    let mut readings = Vec::<AggregateReadings>::new();
+
+    for (bucket, metric, count, sum, sum_squares, min, max) in rows {
+        let Ok(metric) = metric.parse::<Metric>() else {
+            continue;
+        };
+
+        if readings.last().map(|r| r.bucket) != Some(bucket) {
+            readings.push(AggregateReadings {
+                bucket,
+                metrics: BTreeMap::new(),
+            });
+        }
+
+        readings.last_mut().unwrap().metrics.insert(
+            metric,
+            MetricAggregate::new(count, sum, sum_squares, min, max),
+        );
+    }
+
+    readings
+}
+
+impl Backend {
+    /// Brings every rollup up to date: raw readings into 5 minute buckets,
+    /// those into hourly and the hourly ones into daily buckets.
+    ///
+    /// Only the newest two buckets of each size and anything after them are
+    /// recomputed, so a rollup can run while readings are being written and
+    /// the current, still filling bucket is corrected on the next run.
+    /// Readings stored later than that are marked as backfills, whose
+    /// buckets are recomputed on their own. Raw readings before
+    /// `raw_pruned` and 5 minute buckets before `five_minute_pruned` may be
+    /// gone, buckets computed from them are left as they are.
+    pub async fn roll_up(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        raw_pruned: DateTime<Local>,
+        five_minute_pruned: DateTime<Local>,
+    ) -> Result<(), Error> {
+        // One transaction, so the sizes never disagree after a failed run.
+        connection
+            .build_transaction()
+            .run(|conn| {
+                async move {
+                    diesel::sql_query(raw_rollup(&format!(
+                        "r.created_at >= ({RECOMPUTE_SINCE})"
+                    )))
+                    .bind::<Integer, _>(FIVE_MINUTES)
+                    .execute(conn)
+                    .await?;
+
+                    for (source, target) in [(FIVE_MINUTES, HOUR), (HOUR, DAY)] {
+                        diesel::sql_query(bucket_rollup(&format!(
+                            "bucket >= ({RECOMPUTE_SINCE})"
+                        )))
+                        .bind::<Integer, _>(target)
+                        .bind::<Integer, _>(source)
+                        .execute(conn)
+                        .await?;
+                    }
+
+                    let backfills = diesel::delete(rollup_backfills::table)
+                        .returning((rollup_backfills::fk_device_id, rollup_backfills::since))
+                        .get_results::<(String, DateTime<Local>)>(conn)
+                        .await?;
+
+                    for (id, since) in backfills {
+                        diesel::sql_query(raw_rollup(&format!(
+                            "r.fk_device_id = $2 AND r.created_at >= {}",
+                            bucket_start("$3")
+                        )))
+                        .bind::<Integer, _>(FIVE_MINUTES)
+                        .bind::<Text, _>(&id)
+                        .bind::<Timestamptz, _>(
+                            since.max(first_whole_bucket(raw_pruned, FIVE_MINUTES)),
+                        )
+                        .execute(conn)
+                        .await?;
+
+                        // Hourly buckets never expire, so daily ones can
+                        // always be recomputed.
+                        for (source, target, since) in [
+                            (
+                                FIVE_MINUTES,
+                                HOUR,
+                                since.max(first_whole_bucket(five_minute_pruned, HOUR)),
+                            ),
+                            (HOUR, DAY, since),
+                        ] {
+                            diesel::sql_query(bucket_rollup(&format!(
+                                "fk_device_id = $3 AND bucket >= {}",
+                                bucket_start("$4")
+                            )))
+                            .bind::<Integer, _>(target)
+                            .bind::<Integer, _>(source)
+                            .bind::<Text, _>(&id)
+                            .bind::<Timestamptz, _>(since)
+                            .execute(conn)
+                            .await?;
+                        }
+                    }
+
+                    Result::<(), Error>::Ok(())
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    /// Has the next rollup recompute the buckets of a device from `since`,
+    /// for readings stored after their bucket was rolled up.
+    pub async fn mark_backfill(
+        &self,
+        connection: &mut AsyncPgConnection,
+        id: &str,
+        since: DateTime<Local>,
+    ) -> Result<(), Error> {
+        diesel::sql_query(
+            "INSERT INTO rollup_backfills (fk_device_id, since) VALUES ($1, $2)
+            ON CONFLICT (fk_device_id) DO UPDATE
+            SET since = LEAST(rollup_backfills.since, excluded.since)",
+        )
+        .bind::<Text, _>(id)
+        .bind::<Timestamptz, _>(since)
+        .execute(connection)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Deletes buckets of one size that start before `before`.
+    pub async fn delete_aggregates_before(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        bucket_secs: i32,
+        before: DateTime<Local>,
+    ) -> Result<usize, Error> {
+        diesel::delete(
+            aggregates::table.filter(
+                aggregates::bucket_secs
+                    .eq(bucket_secs)
+                    .and(aggregates::bucket.lt(before)),
+            ),
+        )
+        .execute(connection)
+        .await
+    }
+
+    /// Buckets of a device starting within `[from, to)`, oldest first and
+    /// after `after`. Raw readings come back as single reading buckets. An
+    /// empty `metrics` selects every metric.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn get_device_history(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+        resolution: Resolution,
+        metrics: &[Metric],
+        after: Option<HistoryCursor>,
+        limit: i64,
+    ) -> Result<Page<AggregateReadings>, Error> {
+        let Some(bucket_secs) = resolution.bucket_secs() else {
+            let page = self
+                .get_device_raw_readings(connection, id, from, to, after, limit)
+                .await?;
+
+            return Ok(Page {
+                data: page
+                    .data
+                    .into_iter()
+                    .map(|reading| AggregateReadings {
+                        bucket: reading.created_at,
+                        metrics: Metric::ALL
+                            .into_iter()
+                            .filter(|m| metrics.is_empty() || metrics.contains(m))
+                            .filter_map(|m| {
+                                let value = reading.values()[m.index()]?;
+
+                                Some((m, MetricAggregate::single(value)))
+                            })
+                            .collect(),
+                    })
+                    .filter(|reading| !reading.metrics.is_empty())
+                    .collect(),
+                next_cursor: page.next_cursor,
+            });
+        };
+
+        let metrics = metrics.iter().map(|m| m.as_str()).collect::<Vec<_>>();
+
+        // A bucket spans one row per metric, so the page is cut on buckets
+        // first and their rows are fetched after.
+        let mut query = aggregates::table
+            .filter(
+                aggregates::fk_device_id
+                    .eq(id)
+                    .and(aggregates::bucket_secs.eq(bucket_secs))
+                    .and(aggregates::bucket.ge(from))
+                    .and(aggregates::bucket.lt(to)),
+            )
+            .select(aggregates::bucket)
+            .distinct()
+            .order_by(aggregates::bucket)
+            .limit(limit + 1)
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(aggregates::bucket.gt(after.time));
+        }
+
+        if !metrics.is_empty() {
+            query = query.filter(aggregates::metric.eq_any(&metrics));
+        }
+
+        let buckets = Page::from_rows(
+            query.get_results::<DateTime<Local>>(connection).await?,
+            limit,
+            |time| HistoryCursor { time: *time, id: 0 }.to_string(),
+        );
+
+        let (Some(first), Some(last)) = (<[_]>::first(&buckets.data), <[_]>::last(&buckets.data))
+        else {
+            return Ok(Page {
+                data: Vec::new(),
+                next_cursor: None,
+            });
+        };
+
+        let mut query = aggregates::table
+            .filter(
+                aggregates::fk_device_id
+                    .eq(id)
+                    .and(aggregates::bucket_secs.eq(bucket_secs))
+                    .and(aggregates::bucket.ge(first))
+                    .and(aggregates::bucket.le(last)),
+            )
+            .select((
+                aggregates::bucket,
+                aggregates::metric,
+                aggregates::count,
+                aggregates::sum,
+                aggregates::sum_squares,
+                aggregates::min,
+                aggregates::max,
+            ))
+            .order_by(aggregates::bucket)
+            .into_boxed();
+
+        if !metrics.is_empty() {
+            query = query.filter(aggregates::metric.eq_any(&metrics));
+        }
+
+        let data = query.get_results::<AggregateSelect>(connection).await?;
+
+        Ok(Page {
+            data: group_buckets(data),
+            next_cursor: buckets.next_cursor,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u32) -> DateTime<Local> {
+        DateTime::parse_from_rfc3339(&format!("2026-10-18T{hour:02}:00:00Z"))
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    fn row(hour: u32, metric: &str, values: &[f32]) -> AggregateSelect {
+        let sum = values.iter().map(|&v| v as f64).sum();
+        let sum_squares = values.iter().map(|&v| (v as f64).powi(2)).sum();
+        let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+
+        (
+            at(hour),
+            metric.to_string(),
+            values.len() as i32,
+            sum,
+            sum_squares,
+            min,
+            max,
+        )
+    }
+
+    #[test]
+    fn partly_pruned_buckets_are_skipped() {
+        let five_past = at(3) + Duration::minutes(5);
+
+        assert_eq!(first_whole_bucket(at(3), HOUR), at(3));
+        assert_eq!(
+            first_whole_bucket(at(3) + Duration::seconds(1), HOUR),
+            at(4)
+        );
+        assert_eq!(
+            first_whole_bucket(at(3) + Duration::milliseconds(1), FIVE_MINUTES),
+            five_past
+        );
+        assert_eq!(first_whole_bucket(five_past, FIVE_MINUTES), five_past);
+    }
+
+    #[test]
+    fn range_picks_resolution() {
+        let from = at(0);
+
+        for (span, resolution) in [
+            (Duration::hours(1), Resolution::FiveMinutes),
+            (Duration::days(1), Resolution::FiveMinutes),
+            (Duration::days(1) + Duration::seconds(1), Resolution::Hour),
+            (Duration::days(31), Resolution::Hour),
+            (Duration::days(32), Resolution::Day),
+        ] {
+            assert_eq!(
+                Resolution::for_range(from, from + span),
+                resolution,
+                "{span}"
+            );
+        }
+    }
+
+    #[test]
+    fn resolution_names_round_trip() {
+        for resolution in [
+            Resolution::Raw,
+            Resolution::FiveMinutes,
+            Resolution::Hour,
+            Resolution::Day,
+        ] {
+            assert_eq!(resolution.as_str().parse(), Ok(resolution));
+        }
+
+        assert!("2h".parse::<Resolution>().is_err());
+    }
+
+    #[test]
+    fn rows_are_grouped_by_bucket() {
+        let readings = group_buckets(vec![
+            row(1, "co", &[1.0, 3.0]),
+            row(1, "pm_25", &[10.0]),
+            row(1, "retired_metric", &[5.0]),
+            row(2, "co", &[4.0]),
+        ]);
+
+        assert_eq!(readings.len(), 2);
+        assert_eq!(readings[0].bucket, at(1));
+        assert_eq!(readings[0].metrics.len(), 2);
+
+        let co = readings[0].metrics[&Metric::Co];
+        assert_eq!((co.count, co.mean, co.stddev), (2, 2.0, 1.0));
+        assert_eq!((co.min, co.max), (1.0, 3.0));
+
+        assert_eq!(readings[1].metrics[&Metric::Co].stddev, 0.0);
+    }
+
+    #[test]
+    fn legacy_shape_has_every_metric() {
+        let readings = group_buckets(vec![row(1, "co", &[1.0, 3.0])]);
+        let legacy =
+            serde_json::to_value(LegacyReadings::from(readings.into_iter().next().unwrap()))
+                .unwrap();
+
+        assert_eq!(legacy["co"], 2.0);
+        assert!(legacy["pm_25"].is_null());
+        assert_eq!(legacy.as_object().unwrap().len(), Metric::ALL.len() + 1);
+        assert!(legacy["created_at"].is_string());
+    }
+}
@@ -0,0 +1,60 @@
+use diesel::{result::Error, ExpressionMethods, QueryDsl};
+use diesel_async::{pooled_connection::bb8::PooledConnection, AsyncPgConnection, RunQueryDsl};
+
+use crate::{
+    alerts::{AlertState, AlertTransition},
+    db::schema::alert_events,
+};
+
+use super::Backend;
+
+impl Backend {
+    pub async fn add_alert_event(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        transition: &AlertTransition,
+    ) -> Result<(), Error> {
+        diesel::insert_into(alert_events::table)
+            .values((
+                alert_events::rule.eq(&transition.rule),
+                alert_events::fk_device_id.eq(&transition.device_id),
+                alert_events::metric.eq(transition.metric.as_str()),
+                alert_events::state.eq(transition.state.as_str()),
+                alert_events::value.eq(transition.value),
+                alert_events::threshold.eq(transition.threshold),
+                alert_events::created_at.eq(transition.at),
+            ))
+            .execute(connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// `(rule, device id)` of every alert whose last event is firing.
+    pub async fn get_firing_alerts(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let data = alert_events::table
+            .distinct_on((alert_events::rule, alert_events::fk_device_id))
+            .select((
+                alert_events::rule,
+                alert_events::fk_device_id,
+                alert_events::state,
+            ))
+            .order_by((
+                alert_events::rule,
+                alert_events::fk_device_id,
+                alert_events::created_at.desc(),
+                alert_events::id.desc(),
+            ))
+            .get_results::<(String, String, String)>(connection)
+            .await?;
+
+        Ok(data
+            .into_iter()
+            .filter(|(_, _, state)| state == AlertState::Firing.as_str())
+            .map(|(rule, id, _)| (rule, id))
+            .collect())
+    }
+}
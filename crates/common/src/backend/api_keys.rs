@@ -0,0 +1,129 @@
+use chrono::{DateTime, Local};
+use diesel::{result::Error, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{pooled_connection::bb8::PooledConnection, AsyncPgConnection, RunQueryDsl};
+use nanoid::nanoid;
+use serde::Serialize;
+
+use crate::{
+    auth::{self, Role},
+    db::schema::api_keys,
+};
+
+use super::Backend;
+
+const API_KEY_COLUMNS: (
+    api_keys::id,
+    api_keys::name,
+    api_keys::role,
+    api_keys::created_at,
+    api_keys::revoked_at,
+) = (
+    api_keys::id,
+    api_keys::name,
+    api_keys::role,
+    api_keys::created_at,
+    api_keys::revoked_at,
+);
+
+type ApiKeySelect = (
+    String,
+    String,
+    String,
+    DateTime<Local>,
+    Option<DateTime<Local>>,
+);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub role: Role,
+    pub created_at: DateTime<Local>,
+    pub revoked_at: Option<DateTime<Local>>,
+}
+
+/// Rows naming a role this build does not know are skipped.
+fn api_key((id, name, role, created_at, revoked_at): ApiKeySelect) -> Option<ApiKey> {
+    Some(ApiKey {
+        role: role.parse::<Role>().ok()?,
+        id,
+        name,
+        created_at,
+        revoked_at,
+    })
+}
+
+impl Backend {
+    /// Creates a key and returns it along with the key itself, which is not
+    /// stored and cannot be shown again.
+    pub async fn create_api_key(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        name: String,
+        role: Role,
+    ) -> Result<(ApiKey, String), Error> {
+        let key = auth::generate_api_key();
+
+        let row = diesel::insert_into(api_keys::table)
+            .values((
+                api_keys::id.eq(nanoid!(15)),
+                api_keys::name.eq(name),
+                api_keys::role.eq(role.as_str()),
+                api_keys::key_hash.eq(auth::hash_api_key(&key)),
+            ))
+            .returning(API_KEY_COLUMNS)
+            .get_result::<ApiKeySelect>(connection)
+            .await?;
+
+        Ok((api_key(row).ok_or(Error::NotFound)?, key))
+    }
+
+    /// Every key, revoked ones included, oldest first.
+    pub async fn list_api_keys(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+    ) -> Result<Vec<ApiKey>, Error> {
+        let rows = api_keys::table
+            .select(API_KEY_COLUMNS)
+            .order_by((api_keys::created_at, api_keys::id))
+            .get_results::<ApiKeySelect>(connection)
+            .await?;
+
+        Ok(rows.into_iter().filter_map(api_key).collect())
+    }
+
+    /// The role of a key that was not revoked, `None` for any other key.
+    pub async fn get_api_key_role(
+        &self,
+        connection: &mut AsyncPgConnection,
+        key: &str,
+    ) -> Result<Option<Role>, Error> {
+        let role = api_keys::table
+            .filter(api_keys::key_hash.eq(auth::hash_api_key(key)))
+            .filter(api_keys::revoked_at.is_null())
+            .select(api_keys::role)
+            .get_result::<String>(connection)
+            .await
+            .optional()?;
+
+        Ok(role.and_then(|r| r.parse::<Role>().ok()))
+    }
+
+    /// Revokes a key, `false` if it does not exist or already was.
+    pub async fn revoke_api_key(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+    ) -> Result<bool, Error> {
+        let updated = diesel::update(
+            api_keys::table
+                .filter(api_keys::id.eq(id))
+                .filter(api_keys::revoked_at.is_null()),
+        )
+        .set(api_keys::revoked_at.eq(Local::now()))
+        .execute(connection)
+        .await?;
+
+        Ok(updated > 0)
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+
+use chrono::{Duration, Local};
+use diesel::{dsl, result::Error, BoolExpressionMethods, ExpressionMethods, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+
+use crate::{
+    aqi::{Aqi, AqiStandard, CO_WINDOW_HOURS, PM_WINDOW_HOURS},
+    db::schema::{aggregates, devices},
+    metric::Metric,
+};
+
+use super::{aggregates::HOUR, Backend};
+
+#[derive(Debug, Serialize)]
+pub struct DeviceAqi {
+    pub id: String,
+    pub aqi: Option<Aqi>,
+}
+
+impl Backend {
+    /// Means of `metrics` over the hourly buckets of the last `hours`, per
+    /// device. Every device when `id` is `None`.
+    async fn hourly_means(
+        &self,
+        connection: &mut AsyncPgConnection,
+        id: Option<&str>,
+        metrics: &[Metric],
+        hours: i64,
+    ) -> Result<Vec<(String, Metric, f64)>, Error> {
+        let since = Local::now() - Duration::hours(hours);
+
+        let mut query = aggregates::table
+            .filter(
+                aggregates::bucket_secs
+                    .eq(HOUR)
+                    .and(aggregates::bucket.gt(since))
+                    .and(aggregates::metric.eq_any(metrics.iter().map(|m| m.as_str()))),
+            )
+            .group_by((aggregates::fk_device_id, aggregates::metric))
+            .select((
+                aggregates::fk_device_id,
+                aggregates::metric,
+                dsl::sum(aggregates::sum),
+                dsl::sum(aggregates::count),
+            ))
+            .into_boxed();
+
+        query = match id {
+            Some(id) => query.filter(aggregates::fk_device_id.eq(id)),
+            // Deleted devices keep their rollups but are not listed.
+            None => query.filter(
+                aggregates::fk_device_id.eq_any(
+                    devices::table
+                        .filter(devices::deleted_at.is_null())
+                        .select(devices::id),
+                ),
+            ),
+        };
+
+        let data = query
+            .get_results::<(String, String, Option<f64>, Option<i64>)>(connection)
+            .await?;
+
+        Ok(data
+            .into_iter()
+            .filter_map(|(id, metric, sum, count)| {
+                Some((id, metric.parse().ok()?, sum? / count? as f64))
+            })
+            .collect())
+    }
+
+    /// Averages of every pollutant over its window, keyed by device.
+    async fn pollutant_averages(
+        &self,
+        connection: &mut AsyncPgConnection,
+        id: Option<&str>,
+    ) -> Result<BTreeMap<String, BTreeMap<Metric, f64>>, Error> {
+        let mut averages = BTreeMap::<String, BTreeMap<Metric, f64>>::new();
+
+        let pm = self
+            .hourly_means(
+                connection,
+                id,
+                &[Metric::Pm25, Metric::Pm100],
+                PM_WINDOW_HOURS,
+            )
+            .await?;
+        let co = self
+            .hourly_means(connection, id, &[Metric::Co], CO_WINDOW_HOURS)
+            .await?;
+
+        for (id, metric, mean) in pm.into_iter().chain(co) {
+            averages.entry(id).or_default().insert(metric, mean);
+        }
+
+        Ok(averages)
+    }
+
+    pub async fn get_device_aqi(
+        &self,
+        connection: &mut AsyncPgConnection,
+        id: &str,
+        standard: AqiStandard,
+    ) -> Result<Option<Aqi>, Error> {
+        let averages = self.pollutant_averages(connection, Some(id)).await?;
+
+        Ok(averages
+            .get(id)
+            .and_then(|averages| Aqi::compute(standard, averages)))
+    }
+
+    /// The index of every device that reported a pollutant recently.
+    pub async fn get_devices_aqi(
+        &self,
+        connection: &mut AsyncPgConnection,
+        standard: AqiStandard,
+    ) -> Result<Vec<DeviceAqi>, Error> {
+        let averages = self.pollutant_averages(connection, None).await?;
+
+        Ok(averages
+            .into_iter()
+            .map(|(id, averages)| DeviceAqi {
+                aqi: Aqi::compute(standard, &averages),
+                id,
+            })
+            .collect())
+    }
+}
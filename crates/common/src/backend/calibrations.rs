@@ -0,0 +1,257 @@
+use chrono::{DateTime, Local};
+use diesel::{result::Error, BoolExpressionMethods, ExpressionMethods, QueryDsl};
+use diesel_async::{
+    pooled_connection::bb8::PooledConnection, scoped_futures::ScopedFutureExt, AsyncPgConnection,
+    RunQueryDsl,
+};
+
+use crate::{
+    calibration::{calibrate, Calibration},
+    db::schema::{calibrations, last_record, readings},
+    metric::{Metric, METRIC_COUNT},
+};
+
+use super::Backend;
+
+/// Readings recalibrated per transaction.
+const RECALIBRATE_BATCH: i64 = 500;
+
+type CalibrationSelect = (String, Vec<Option<f32>>, Option<f32>, DateTime<Local>);
+
+type RecalibrateSelect = (
+    i64,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<Vec<Option<f32>>>,
+    DateTime<Local>,
+);
+
+/// Rows naming a metric this build does not know are skipped.
+fn calibration(
+    (metric, coefficients, humidity_kappa, effective_from): CalibrationSelect,
+) -> Option<Calibration> {
+    Some(Calibration {
+        metric: metric.parse::<Metric>().ok()?,
+        coefficients: coefficients.into_iter().flatten().collect(),
+        humidity_kappa,
+        effective_from,
+    })
+}
+
+impl Backend {
+    /// Adds a version of the calibration of a metric. Readings already
+    /// stored are only corrected with it by [`Backend::recalibrate`].
+    pub async fn add_calibration(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+        calibration: &Calibration,
+    ) -> Result<(), Error> {
+        diesel::insert_into(calibrations::table)
+            .values((
+                calibrations::fk_device_id.eq(id),
+                calibrations::metric.eq(calibration.metric.as_str()),
+                calibrations::coefficients.eq(calibration
+                    .coefficients
+                    .iter()
+                    .copied()
+                    .map(Some)
+                    .collect::<Vec<_>>()),
+                calibrations::humidity_kappa.eq(calibration.humidity_kappa),
+                calibrations::effective_from.eq(calibration.effective_from),
+            ))
+            .execute(connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Every calibration version of a device, oldest first.
+    pub async fn get_device_calibrations(
+        &self,
+        connection: &mut AsyncPgConnection,
+        id: &str,
+    ) -> Result<Vec<Calibration>, Error> {
+        let rows = calibrations::table
+            .filter(calibrations::fk_device_id.eq(id))
+            .select((
+                calibrations::metric,
+                calibrations::coefficients,
+                calibrations::humidity_kappa,
+                calibrations::effective_from,
+            ))
+            .order_by((calibrations::effective_from, calibrations::metric))
+            .get_results::<CalibrationSelect>(connection)
+            .await?;
+
+        Ok(rows.into_iter().filter_map(calibration).collect())
+    }
+
+    /// Corrects the readings of a device taken since `since` again from
+    /// their raw values, with the calibrations as they are now, and has the
+    /// next rollup recompute their buckets. Returns how many readings were
+    /// rewritten.
+    ///
+    /// Runs in batches, an interrupted run is finished by running it again.
+    pub async fn recalibrate(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+        since: DateTime<Local>,
+    ) -> Result<usize, Error> {
+        let history = self.get_device_calibrations(connection, id).await?;
+
+        let mut after = None;
+        let mut newest = None;
+        let mut count = 0;
+
+        loop {
+            let mut query = readings::table
+                .filter(
+                    readings::fk_device_id
+                        .eq(id)
+                        .and(readings::created_at.ge(since)),
+                )
+                .select((
+                    readings::id,
+                    readings::co,
+                    readings::co2,
+                    readings::temperature,
+                    readings::humidity,
+                    readings::noise,
+                    readings::pm_10,
+                    readings::pm_25,
+                    readings::pm_100,
+                    readings::pm_particles_03,
+                    readings::pm_particles_05,
+                    readings::pm_particles_10,
+                    readings::pm_particles_25,
+                    readings::pm_particles_50,
+                    readings::pm_particles_100,
+                    readings::raw,
+                    readings::created_at,
+                ))
+                .order_by((readings::created_at, readings::id))
+                .limit(RECALIBRATE_BATCH)
+                .into_boxed();
+
+            if let Some((time, row)) = after {
+                query = query.filter(
+                    readings::created_at
+                        .gt(time)
+                        .or(readings::created_at.eq(time).and(readings::id.gt(row))),
+                );
+            }
+
+            let batch = query.get_results::<RecalibrateSelect>(connection).await?;
+
+            let Some(last) = batch.last() else {
+                break;
+            };
+
+            after = Some((last.16, last.0));
+
+            let rows = batch
+                .into_iter()
+                .map(|row| {
+                    let stored = [
+                        row.1, row.2, row.3, row.4, row.5, row.6, row.7, row.8, row.9, row.10,
+                        row.11, row.12, row.13, row.14,
+                    ];
+
+                    // Readings stored before any calibration hold raw values.
+                    let raw = row
+                        .15
+                        .and_then(|raw| <[Option<f32>; METRIC_COUNT]>::try_from(raw).ok())
+                        .unwrap_or(stored);
+
+                    (row.0, row.16, raw, calibrate(&raw, &history, row.16))
+                })
+                .collect::<Vec<_>>();
+
+            count += rows.len();
+
+            if let Some((_, time, _, values)) = rows.last() {
+                newest = Some((*time, *values));
+            }
+
+            connection
+                .build_transaction()
+                .run(|conn| {
+                    async move {
+                        for (row, _, raw, values) in rows {
+                            diesel::update(readings::table.filter(readings::id.eq(row)))
+                                .set((
+                                    readings::co.eq(values[0]),
+                                    readings::co2.eq(values[1]),
+                                    readings::temperature.eq(values[2]),
+                                    readings::humidity.eq(values[3]),
+                                    readings::noise.eq(values[4]),
+                                    readings::pm_10.eq(values[5]),
+                                    readings::pm_25.eq(values[6]),
+                                    readings::pm_100.eq(values[7]),
+                                    readings::pm_particles_03.eq(values[8]),
+                                    readings::pm_particles_05.eq(values[9]),
+                                    readings::pm_particles_10.eq(values[10]),
+                                    readings::pm_particles_25.eq(values[11]),
+                                    readings::pm_particles_50.eq(values[12]),
+                                    readings::pm_particles_100.eq(values[13]),
+                                    readings::raw.eq(Some(raw.to_vec())),
+                                ))
+                                .execute(conn)
+                                .await?;
+                        }
+
+                        Result::<(), Error>::Ok(())
+                    }
+                    .scope_boxed()
+                })
+                .await?;
+        }
+
+        // The last record is the newest reading, correct it along with it.
+        if let Some((time, values)) = newest {
+            diesel::update(
+                last_record::table.filter(
+                    last_record::fk_device_id
+                        .eq(id)
+                        .and(last_record::updated_at.eq(time)),
+                ),
+            )
+            .set((
+                last_record::co.eq(values[0]),
+                last_record::co2.eq(values[1]),
+                last_record::temperature.eq(values[2]),
+                last_record::humidity.eq(values[3]),
+                last_record::noise.eq(values[4]),
+                last_record::pm_10.eq(values[5]),
+                last_record::pm_25.eq(values[6]),
+                last_record::pm_100.eq(values[7]),
+                last_record::pm_particles_03.eq(values[8]),
+                last_record::pm_particles_05.eq(values[9]),
+                last_record::pm_particles_10.eq(values[10]),
+                last_record::pm_particles_25.eq(values[11]),
+                last_record::pm_particles_50.eq(values[12]),
+                last_record::pm_particles_100.eq(values[13]),
+            ))
+            .execute(connection)
+            .await?;
+
+            self.mark_backfill(connection, id, since).await?;
+        }
+
+        Ok(count)
+    }
+}
@@ -1,14 +1,39 @@
-use diesel::{dsl::exists, result::Error, ExpressionMethods, QueryDsl};
+use chrono::Local;
+use diesel::{
+    dsl::exists, result::Error, BoolExpressionMethods, ExpressionMethods, OptionalExtension,
+    QueryDsl,
+};
 use diesel_async::AsyncPgConnection;
 use diesel_async::{
     pooled_connection::bb8::PooledConnection, scoped_futures::ScopedFutureExt, RunQueryDsl,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use super::db::schema::devices;
-use super::Backend;
+use super::db::schema::{devices, last_record, rollup_backfills, webhooks};
+use super::{pagination::Page, Backend};
+use crate::{metric::Sensor, signing};
 use nanoid::nanoid;
 
+const DEVICE_COLUMNS: (
+    devices::id,
+    devices::name,
+    devices::box_,
+    devices::lat,
+    devices::long,
+    devices::active,
+    devices::public,
+) = (
+    devices::id,
+    devices::name,
+    devices::box_,
+    devices::lat,
+    devices::long,
+    devices::active,
+    devices::public,
+);
+
+type DeviceSelect = (String, String, String, f32, f32, bool, bool);
+
 #[derive(Debug, Serialize)]
 pub struct Device {
     pub id: String,
@@ -20,9 +45,51 @@ pub struct Device {
     pub long: f32,
 
     pub active: bool,
+    /// Readable without an API key.
+    pub public: bool,
+
+    /// What the device measures, in frame order.
+    pub sensors: Vec<Sensor>,
+}
+
+/// Devices by id, box or area, those matching any of them are taken.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DeviceFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ids: Vec<String>,
+    #[serde(default, rename = "box", skip_serializing_if = "Option::is_none")]
+    pub box_: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounds: Option<Bounds>,
+}
+
+/// Fields of a device to change, those left out are kept.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DeviceUpdate {
+    pub name: Option<String>,
+    #[serde(rename = "box")]
+    pub box_: Option<String>,
+    pub lat: Option<f32>,
+    pub long: Option<f32>,
+}
+
+impl DeviceUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.box_.is_none() && self.lat.is_none() && self.long.is_none()
+    }
+}
+
+/// An area between two corners, in degrees.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Bounds {
+    pub min_lat: f32,
+    pub min_long: f32,
+    pub max_lat: f32,
+    pub max_long: f32,
 }
 
 impl Backend {
+    #[allow(clippy::too_many_arguments)]
     pub async fn create_device(
         &self,
         connection: &mut PooledConnection<'static, AsyncPgConnection>,
@@ -30,12 +97,14 @@ impl Backend {
         box_: String,
         long: f32,
         lat: f32,
-    ) -> Result<String, Error> {
+        public: bool,
+        sensors: &[Sensor],
+    ) -> Result<(String, String), Error> {
         let id = nanoid!(15);
         let id0 = id.clone();
 
-        let long = long.clone();
-        let lat = lat.clone();
+        let secret = signing::generate_secret();
+        let secret0 = secret.clone();
 
         connection
             .build_transaction()
@@ -43,44 +112,73 @@ impl Backend {
                 async move {
                     diesel::insert_into(devices::table)
                         .values((
-                            devices::id.eq(id0),
+                            devices::id.eq(&id0),
                             devices::name.eq(name),
                             devices::box_.eq(box_),
                             devices::long.eq(long),
                             devices::lat.eq(lat),
                             devices::active.eq(true),
+                            devices::public.eq(public),
+                            devices::secret.eq(secret0),
+                            devices::secret_issued_at.eq(Local::now()),
                         ))
                         .execute(conn)
                         .await?;
 
+                    self.add_device_sensors(conn, &id0, sensors).await?;
+
                     Result::<(), Error>::Ok(())
                 }
                 .scope_boxed()
             })
             .await?;
 
-        Ok(id)
+        Ok((id, secret))
     }
 
+    /// Devices ordered by id, starting after the device `after`.
     pub async fn list_devices(
         &self,
         connection: &mut PooledConnection<'static, AsyncPgConnection>,
-    ) -> Result<Vec<Device>, Error> {
-        let data = devices::table
-            .get_results::<(String, String, String, f32, f32, bool)>(connection)
-            .await?;
+        after: Option<&str>,
+        public_only: bool,
+        limit: i64,
+    ) -> Result<Page<Device>, Error> {
+        let mut query = devices::table
+            .filter(devices::deleted_at.is_null())
+            .select(DEVICE_COLUMNS)
+            .order_by(devices::id)
+            .limit(limit + 1)
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(devices::id.gt(after));
+        }
 
-        Ok(data
+        if public_only {
+            query = query.filter(devices::public.eq(true));
+        }
+
+        let data = query.get_results::<DeviceSelect>(connection).await?;
+
+        let ids = data.iter().map(|d| d.0.clone()).collect::<Vec<_>>();
+        let mut sensors = self.get_devices_sensors(connection, &ids).await?;
+
+        let devices = data
             .into_iter()
-            .map(|(id, name, box_, lat, long, active)| Device {
+            .map(|(id, name, box_, lat, long, active, public)| Device {
+                sensors: sensors.remove(&id).unwrap_or_default(),
                 id,
                 name,
                 box_,
                 lat,
                 long,
                 active,
+                public,
             })
-            .collect())
+            .collect();
+
+        Ok(Page::from_rows(devices, limit, |d| d.id.clone()))
     }
 
     pub async fn check_device_exists(
@@ -88,11 +186,176 @@ impl Backend {
         connection: &mut PooledConnection<'static, AsyncPgConnection>,
         id: &str,
     ) -> Result<bool, Error> {
-        Ok(
-            diesel::select(exists(devices::table.filter(devices::id.eq(id))))
-                .get_result::<bool>(connection)
-                .await?,
+        diesel::select(exists(
+            devices::table.filter(devices::id.eq(id).and(devices::deleted_at.is_null())),
+        ))
+        .get_result::<bool>(connection)
+        .await
+    }
+
+    /// Ids of the devices flagged public, ordered by id.
+    pub async fn get_public_devices(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+    ) -> Result<Vec<String>, Error> {
+        devices::table
+            .filter(devices::public.eq(true).and(devices::deleted_at.is_null()))
+            .select(devices::id)
+            .order_by(devices::id)
+            .get_results::<String>(connection)
+            .await
+    }
+
+    /// `false` if the device does not exist.
+    pub async fn check_device_public(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+    ) -> Result<bool, Error> {
+        diesel::select(exists(
+            devices::table.filter(
+                devices::id
+                    .eq(id)
+                    .and(devices::public.eq(true))
+                    .and(devices::deleted_at.is_null()),
+            ),
+        ))
+        .get_result::<bool>(connection)
+        .await
+    }
+
+    /// Flags a device readable without an API key or not, `false` if the
+    /// device does not exist.
+    pub async fn set_device_public(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+        public: bool,
+    ) -> Result<bool, Error> {
+        let updated = diesel::update(
+            devices::table.filter(devices::id.eq(id).and(devices::deleted_at.is_null())),
+        )
+        .set(devices::public.eq(public))
+        .execute(connection)
+        .await?;
+
+        Ok(updated > 0)
+    }
+
+    /// Ids of the devices matching `filter`, ordered by id. With
+    /// `public_only` private devices are left out.
+    pub async fn find_devices(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        filter: &DeviceFilter,
+        public_only: bool,
+    ) -> Result<Vec<String>, Error> {
+        let mut query = devices::table
+            .select(devices::id)
+            .filter(devices::id.eq_any(&filter.ids))
+            .order_by(devices::id)
+            .into_boxed();
+
+        if let Some(box_) = &filter.box_ {
+            query = query.or_filter(devices::box_.eq(box_));
+        }
+
+        if let Some(b) = filter.bounds {
+            query = query.or_filter(
+                devices::lat
+                    .between(b.min_lat, b.max_lat)
+                    .and(devices::long.between(b.min_long, b.max_long)),
+            );
+        }
+
+        if public_only {
+            query = query.filter(devices::public.eq(true));
+        }
+
+        query
+            .filter(devices::deleted_at.is_null())
+            .get_results::<String>(connection)
+            .await
+    }
+
+    /// Changes the fields set in `update`, `false` if the device does not
+    /// exist. Moving a device keeps its readings where they were taken.
+    pub async fn update_device(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+        update: &DeviceUpdate,
+    ) -> Result<bool, Error> {
+        if update.is_empty() {
+            return self.check_device_exists(connection, id).await;
+        }
+
+        let updated = diesel::update(
+            devices::table.filter(devices::id.eq(id).and(devices::deleted_at.is_null())),
         )
+        .set((
+            update.name.as_ref().map(|v| devices::name.eq(v)),
+            update.box_.as_ref().map(|v| devices::box_.eq(v)),
+            update.lat.map(|v| devices::lat.eq(v)),
+            update.long.map(|v| devices::long.eq(v)),
+        ))
+        .execute(connection)
+        .await?;
+
+        Ok(updated > 0)
+    }
+
+    /// Marks a device deleted, `false` if it does not exist or already was.
+    /// Its readings, rollups, alerts and calibrations are kept for the
+    /// history. Its last record, webhooks and pending rollups go, so it
+    /// drops out of the live views and nothing is delivered for it.
+    pub async fn delete_device(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+    ) -> Result<bool, Error> {
+        let id = id.to_string();
+
+        connection
+            .build_transaction()
+            .run(|conn| {
+                async move {
+                    let updated = diesel::update(
+                        devices::table
+                            .filter(devices::id.eq(&id).and(devices::deleted_at.is_null())),
+                    )
+                    .set((
+                        devices::deleted_at.eq(Local::now()),
+                        devices::active.eq(false),
+                        devices::public.eq(false),
+                    ))
+                    .execute(conn)
+                    .await?;
+
+                    if updated == 0 {
+                        return Ok(false);
+                    }
+
+                    diesel::delete(last_record::table.filter(last_record::fk_device_id.eq(&id)))
+                        .execute(conn)
+                        .await?;
+
+                    diesel::delete(
+                        rollup_backfills::table.filter(rollup_backfills::fk_device_id.eq(&id)),
+                    )
+                    .execute(conn)
+                    .await?;
+
+                    // Dead letters go along with their webhook.
+                    diesel::delete(webhooks::table.filter(webhooks::fk_device_id.eq(&id)))
+                        .execute(conn)
+                        .await?;
+
+                    Result::<bool, Error>::Ok(true)
+                }
+                .scope_boxed()
+            })
+            .await
     }
 
     pub async fn get_device(
@@ -100,18 +363,23 @@ impl Backend {
         connection: &mut PooledConnection<'static, AsyncPgConnection>,
         id: &str,
     ) -> Result<Device, Error> {
-        let (id, name, box_, lat, long, active) = devices::table
-            .filter(devices::id.eq(id))
-            .get_result::<(String, String, String, f32, f32, bool)>(connection)
+        let (id, name, box_, lat, long, active, public) = devices::table
+            .filter(devices::id.eq(id).and(devices::deleted_at.is_null()))
+            .select(DEVICE_COLUMNS)
+            .get_result::<DeviceSelect>(connection)
             .await?;
 
+        let sensors = self.get_device_sensors(connection, &id).await?;
+
         Ok(Device {
+            sensors,
             id,
             name,
             box_,
             lat,
             long,
             active,
+            public,
         })
     }
 
@@ -134,10 +402,52 @@ impl Backend {
         connection: &mut AsyncPgConnection,
         id: &str,
     ) -> Result<bool, Error> {
-        Ok(devices::table
+        devices::table
             .filter(devices::id.eq(id))
             .select(devices::active)
             .get_result::<bool>(connection)
-            .await?)
+            .await
+    }
+
+    /// The HMAC key of a device, `None` if the device does not exist, was
+    /// deleted or was never issued its secret.
+    pub async fn get_device_secret(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+    ) -> Result<Option<String>, Error> {
+        devices::table
+            .filter(devices::id.eq(id).and(devices::deleted_at.is_null()))
+            .filter(devices::secret_issued_at.is_not_null())
+            .select(devices::secret)
+            .get_result::<String>(connection)
+            .await
+            .optional()
+    }
+
+    /// Replaces the secret of a device and returns the new one. Frames signed
+    /// with the old secret are rejected from then on.
+    pub async fn rotate_device_secret(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+    ) -> Result<String, Error> {
+        let secret = signing::generate_secret();
+
+        let updated = diesel::update(
+            devices::table.filter(devices::id.eq(id).and(devices::deleted_at.is_null())),
+        )
+        .set((
+            devices::secret.eq(&secret),
+            devices::secret_issued_at.eq(Local::now()),
+        ))
+        .execute(connection)
+        .await?;
+
+        if updated == 0 {
+            return Err(Error::NotFound);
+        }
+
+        Ok(secret)
     }
 }
@@ -5,8 +5,17 @@ use diesel_async::{
 
 use crate::db;
 
-mod device;
+pub mod aggregates;
+mod alerts;
+pub mod api_keys;
+mod aqi;
+mod calibrations;
+pub mod device;
+mod sensors;
+pub mod pagination;
+pub mod readings;
 pub mod records;
+pub mod webhooks;
 
 #[derive(Debug, Clone)]
 pub struct Backend {
@@ -23,6 +32,6 @@ impl Backend {
     pub async fn get_connection(
         &self,
     ) -> Result<PooledConnection<'static, AsyncPgConnection>, RunError> {
-        Ok(self.db.get_owned().await?)
+        self.db.get_owned().await
     }
 }
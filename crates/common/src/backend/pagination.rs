@@ -0,0 +1,121 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+/// One page of a keyset paginated listing. `next_cursor` is set when more
+/// rows follow and is passed back to get the next page.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Builds a page from up to `limit + 1` rows, the extra row only telling
+    /// that there is another page.
+    pub fn from_rows(mut rows: Vec<T>, limit: i64, cursor: impl Fn(&T) -> String) -> Self {
+        let limit = limit as usize;
+
+        let next_cursor = if rows.len() > limit {
+            rows.truncate(limit);
+            rows.last().map(cursor)
+        } else {
+            None
+        };
+
+        Self {
+            data: rows,
+            next_cursor,
+        }
+    }
+}
+
+/// Position in a history, the time of the last returned bucket or reading and
+/// the id of the reading to break ties between raw readings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryCursor {
+    pub time: DateTime<Local>,
+    pub id: i64,
+}
+
+impl fmt::Display for HistoryCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.time.timestamp_micros(), self.id)
+    }
+}
+
+impl FromStr for HistoryCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid cursor '{s}'");
+
+        let (time, id) = s.split_once('_').ok_or_else(invalid)?;
+
+        let time = time
+            .parse::<i64>()
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            time: time.with_timezone(&Local),
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extra_row_is_trimmed_into_a_cursor() {
+        let page = Page::from_rows(vec![1, 2, 3], 2, |n| n.to_string());
+        assert_eq!(page.data, vec![1, 2]);
+        assert_eq!(page.next_cursor.as_deref(), Some("2"));
+
+        let last = Page::from_rows(vec![1, 2], 2, |n| n.to_string());
+        assert_eq!(last.data, vec![1, 2]);
+        assert_eq!(last.next_cursor, None);
+
+        let empty = Page::from_rows(Vec::<i32>::new(), 2, |n| n.to_string());
+        assert!(empty.data.is_empty() && empty.next_cursor.is_none());
+    }
+
+    #[test]
+    fn cursor_round_trips_to_the_microsecond() {
+        let time = DateTime::parse_from_rfc3339("2026-10-18T09:30:15.123456+09:00")
+            .unwrap()
+            .with_timezone(&Local);
+
+        for id in [0, 42, -1] {
+            let cursor = HistoryCursor { time, id };
+            assert_eq!(cursor.to_string().parse::<HistoryCursor>(), Ok(cursor));
+        }
+
+        let before_1970 = HistoryCursor {
+            time: DateTime::from_timestamp_micros(-1_500_000).unwrap().into(),
+            id: 7,
+        };
+        assert_eq!(before_1970.to_string(), "-1500000_7");
+        assert_eq!("-1500000_7".parse::<HistoryCursor>(), Ok(before_1970));
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        for cursor in [
+            "", "123", "_1", "123_", "abc_1", "123_abc", "1.5_1", "123_1_2",
+        ] {
+            assert_eq!(
+                cursor.parse::<HistoryCursor>(),
+                Err(format!("invalid cursor '{cursor}'")),
+                "{cursor}"
+            );
+        }
+
+        let out_of_range = format!("{}_1", i64::MAX);
+        assert!(out_of_range.parse::<HistoryCursor>().is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use chrono::{DateTime, Local};
+use diesel::{result::Error, BoolExpressionMethods, ExpressionMethods, QueryDsl};
+use diesel_async::{pooled_connection::bb8::PooledConnection, AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+
+use crate::{db::schema::readings, metric::METRIC_COUNT};
+
+use super::{
+    pagination::{HistoryCursor, Page},
+    Backend,
+};
+
+/// A single stored reading, calibrated if the device was at the time.
+#[derive(Debug, Serialize)]
+pub struct RawReading {
+    pub id: i64,
+    pub co: Option<f32>,
+    pub co2: Option<f32>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub noise: Option<f32>,
+    pub pm_10: Option<f32>,
+    pub pm_25: Option<f32>,
+    pub pm_100: Option<f32>,
+    pub pm_particles_03: Option<f32>,
+    pub pm_particles_05: Option<f32>,
+    pub pm_particles_10: Option<f32>,
+    pub pm_particles_25: Option<f32>,
+    pub pm_particles_50: Option<f32>,
+    pub pm_particles_100: Option<f32>,
+    pub quality: i32,
+    pub created_at: DateTime<Local>,
+}
+
+type RawReadingSelect = (
+    i64,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    i32,
+    DateTime<Local>,
+);
+
+fn raw_reading(
+    (
+        id,
+        co,
+        co2,
+        temperature,
+        humidity,
+        noise,
+        pm_10,
+        pm_25,
+        pm_100,
+        pm_particles_03,
+        pm_particles_05,
+        pm_particles_10,
+        pm_particles_25,
+        pm_particles_50,
+        pm_particles_100,
+        quality,
+        created_at,
+    ): RawReadingSelect,
+) -> RawReading {
+    RawReading {
+        id,
+        co,
+        co2,
+        temperature,
+        humidity,
+        noise,
+        pm_10,
+        pm_25,
+        pm_100,
+        pm_particles_03,
+        pm_particles_05,
+        pm_particles_10,
+        pm_particles_25,
+        pm_particles_50,
+        pm_particles_100,
+        quality,
+        created_at,
+    }
+}
+
+impl RawReading {
+    /// The values in frame order.
+    pub fn values(&self) -> [Option<f32>; METRIC_COUNT] {
+        [
+            self.co,
+            self.co2,
+            self.temperature,
+            self.humidity,
+            self.noise,
+            self.pm_10,
+            self.pm_25,
+            self.pm_100,
+            self.pm_particles_03,
+            self.pm_particles_05,
+            self.pm_particles_10,
+            self.pm_particles_25,
+            self.pm_particles_50,
+            self.pm_particles_100,
+        ]
+    }
+}
+
+impl Backend {
+    /// Stores a reading, `raw` holding the values as sent when `values` were
+    /// calibrated.
+    pub async fn add_reading(
+        &self,
+        connection: &mut AsyncPgConnection,
+        id: &str,
+        time: DateTime<Local>,
+        values: &[Option<f32>; METRIC_COUNT],
+        raw: Option<&[Option<f32>; METRIC_COUNT]>,
+        quality: i32,
+    ) -> Result<(), Error> {
+        diesel::insert_into(readings::table)
+            .values((
+                readings::fk_device_id.eq(id),
+                readings::co.eq(values[0]),
+                readings::co2.eq(values[1]),
+                readings::temperature.eq(values[2]),
+                readings::humidity.eq(values[3]),
+                readings::noise.eq(values[4]),
+                readings::pm_10.eq(values[5]),
+                readings::pm_25.eq(values[6]),
+                readings::pm_100.eq(values[7]),
+                readings::pm_particles_03.eq(values[8]),
+                readings::pm_particles_05.eq(values[9]),
+                readings::pm_particles_10.eq(values[10]),
+                readings::pm_particles_25.eq(values[11]),
+                readings::pm_particles_50.eq(values[12]),
+                readings::pm_particles_100.eq(values[13]),
+                readings::quality.eq(quality),
+                readings::created_at.eq(time),
+                readings::raw.eq(raw.map(|r| r.to_vec())),
+            ))
+            .execute(connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Raw readings of a device within `[from, to)`, oldest first, starting
+    /// after `after`.
+    pub async fn get_device_raw_readings(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+        after: Option<HistoryCursor>,
+        limit: i64,
+    ) -> Result<Page<RawReading>, Error> {
+        let mut query = readings::table
+            .filter(
+                readings::fk_device_id
+                    .eq(id)
+                    .and(readings::created_at.ge(from))
+                    .and(readings::created_at.lt(to)),
+            )
+            .select((
+                readings::id,
+                readings::co,
+                readings::co2,
+                readings::temperature,
+                readings::humidity,
+                readings::noise,
+                readings::pm_10,
+                readings::pm_25,
+                readings::pm_100,
+                readings::pm_particles_03,
+                readings::pm_particles_05,
+                readings::pm_particles_10,
+                readings::pm_particles_25,
+                readings::pm_particles_50,
+                readings::pm_particles_100,
+                readings::quality,
+                readings::created_at,
+            ))
+            .order_by((readings::created_at, readings::id))
+            .limit(limit + 1)
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(
+                readings::created_at.gt(after.time).or(readings::created_at
+                    .eq(after.time)
+                    .and(readings::id.gt(after.id))),
+            );
+        }
+
+        let data = query.get_results::<RawReadingSelect>(connection).await?;
+
+        let rows = data.into_iter().map(raw_reading).collect();
+
+        Ok(Page::from_rows(rows, limit, |r| {
+            HistoryCursor {
+                time: r.created_at,
+                id: r.id,
+            }
+            .to_string()
+        }))
+    }
+
+    /// Readings of `ids`, of every device when `None`, taken within
+    /// `[from, to)`, oldest first and at most `limit` of them.
+    pub async fn get_readings_between(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        ids: Option<&[String]>,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+        limit: i64,
+    ) -> Result<Vec<(String, RawReading)>, Error> {
+        let mut query = readings::table
+            .filter(
+                readings::created_at
+                    .ge(from)
+                    .and(readings::created_at.lt(to)),
+            )
+            .select((
+                readings::fk_device_id,
+                (
+                    readings::id,
+                    readings::co,
+                    readings::co2,
+                    readings::temperature,
+                    readings::humidity,
+                    readings::noise,
+                    readings::pm_10,
+                    readings::pm_25,
+                    readings::pm_100,
+                    readings::pm_particles_03,
+                    readings::pm_particles_05,
+                    readings::pm_particles_10,
+                    readings::pm_particles_25,
+                    readings::pm_particles_50,
+                    readings::pm_particles_100,
+                    readings::quality,
+                    readings::created_at,
+                ),
+            ))
+            .order_by((readings::created_at, readings::id))
+            .limit(limit)
+            .into_boxed();
+
+        if let Some(ids) = ids {
+            query = query.filter(readings::fk_device_id.eq_any(ids));
+        }
+
+        let data = query
+            .get_results::<(String, RawReadingSelect)>(connection)
+            .await?;
+
+        Ok(data
+            .into_iter()
+            .map(|(device_id, row)| (device_id, raw_reading(row)))
+            .collect())
+    }
+
+    /// Deletes raw readings older than `before`, returning how many went.
+    pub async fn delete_readings_before(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        before: DateTime<Local>,
+    ) -> Result<usize, Error> {
+        diesel::delete(readings::table.filter(readings::created_at.lt(before)))
+            .execute(connection)
+            .await
+    }
+}
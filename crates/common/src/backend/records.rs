@@ -1,5 +1,4 @@
 use chrono::{DateTime, Duration, Local};
-use diesel::BoolExpressionMethods;
 use diesel::ExpressionMethods;
 use diesel::{result::Error, OptionalExtension, QueryDsl};
 use diesel_async::AsyncPgConnection;
@@ -8,82 +7,98 @@ use diesel_async::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::db::schema::{hour_records, last_record};
+use crate::db::schema::last_record;
 
-use super::Backend;
-
-#[derive(Debug, Serialize)]
-pub struct Readings {
-    co: f32,
-    co2: f32,
-    temperature: f32,
-    humidity: f32,
-    noise: f32,
-    pm_10: f32,
-    pm_25: f32,
-    pm_100: f32,
-    pm_particles_03: f32,
-    pm_particles_05: f32,
-    pm_particles_10: f32,
-    pm_particles_25: f32,
-    pm_particles_50: f32,
-    pm_particles_100: f32,
-    created_at: DateTime<Local>,
-}
+use super::{aggregates::FIVE_MINUTES, Backend};
 
+/// Metrics without a value, as those the device has no sensor for, are left
+/// out when serialized.
 #[derive(Debug, Default, Serialize)]
 pub struct Reading {
-    co: f32,
-    co2: f32,
-    noise: f32,
-    temperature: f32,
-    humidity: f32,
-    pm_10: f32,
-    pm_25: f32,
-    pm_100: f32,
-    pm_particles_03: f32,
-    pm_particles_05: f32,
-    pm_particles_10: f32,
-    pm_particles_25: f32,
-    pm_particles_50: f32,
-    pm_particles_100: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    co: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    co2: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    noise: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    humidity: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_10: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_25: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_100: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_03: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_05: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_10: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_25: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_50: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_100: Option<f32>,
+    quality: i32,
     updated_at: DateTime<Local>,
 }
 
-#[derive(Debug, Default, Serialize)]
+/// Like [`Reading`], metrics without a value are left out.
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct DevicesReading {
     id: String,
-    co: f32,
-    co2: f32,
-    temperature: f32,
-    humidity: f32,
-    noise: f32,
-    pm_10: f32,
-    pm_25: f32,
-    pm_100: f32,
-    pm_particles_03: f32,
-    pm_particles_05: f32,
-    pm_particles_10: f32,
-    pm_particles_25: f32,
-    pm_particles_50: f32,
-    pm_particles_100: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    co: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    co2: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    humidity: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    noise: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_10: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_25: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_100: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_03: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_05: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_10: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_25: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_50: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pm_particles_100: Option<f32>,
+    quality: i32,
+    updated_at: DateTime<Local>,
 }
 
 type ReadingDateSelect = (
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
-    f32,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    Option<f32>,
+    i32,
     DateTime<Local>,
 );
 
@@ -97,17 +112,41 @@ pub enum LastReading {
 }
 
 impl Backend {
+    /// Stores a reading taken at `time`, `raw` holding the values as sent
+    /// when they were calibrated. It becomes the last record unless the
+    /// device already sent a newer one, `true` when it did.
     pub async fn create_record(
         &self,
         connection: &mut PooledConnection<'static, AsyncPgConnection>,
         id: String,
-        values: [f32; 14],
-    ) -> Result<(), Error> {
+        time: DateTime<Local>,
+        values: [Option<f32>; 14],
+        raw: Option<[Option<f32>; 14]>,
+        quality: i32,
+    ) -> Result<bool, Error> {
         connection
             .build_transaction()
             .run(|conn| {
                 async move {
-                    let now = Local::now();
+                    self.add_reading(conn, &id, time, &values, raw.as_ref(), quality)
+                        .await?;
+
+                    if time < Local::now() - Duration::seconds(FIVE_MINUTES.into()) {
+                        self.mark_backfill(conn, &id, time).await?;
+                    }
+
+                    let newest = last_record::table
+                        .filter(last_record::fk_device_id.eq(&id))
+                        .select(last_record::updated_at)
+                        .for_update()
+                        .get_result::<DateTime<Local>>(conn)
+                        .await
+                        .optional()?
+                        .is_none_or(|last| last <= time);
+
+                    if !newest {
+                        return Ok(false);
+                    }
 
                     diesel::insert_into(last_record::table)
                         .values((
@@ -126,7 +165,8 @@ impl Backend {
                             last_record::pm_particles_25.eq(values[11]),
                             last_record::pm_particles_50.eq(values[12]),
                             last_record::pm_particles_100.eq(values[13]),
-                            last_record::updated_at.eq(&now),
+                            last_record::quality.eq(quality),
+                            last_record::updated_at.eq(&time),
                         ))
                         .on_conflict(last_record::fk_device_id)
                         .do_update()
@@ -146,54 +186,15 @@ impl Backend {
                             last_record::pm_particles_25.eq(values[11]),
                             last_record::pm_particles_50.eq(values[12]),
                             last_record::pm_particles_100.eq(values[13]),
-                            last_record::updated_at.eq(&now),
+                            last_record::quality.eq(quality),
+                            last_record::updated_at.eq(&time),
                         ))
                         .execute(conn)
                         .await?;
 
                     self.change_device_active(conn, &id, true).await?;
 
-                    let last_time = hour_records::table
-                        .filter(hour_records::fk_device_id.eq(&id))
-                        .select(hour_records::created_at)
-                        .order_by(hour_records::created_at.desc())
-                        .first::<DateTime<Local>>(conn)
-                        .await
-                        .optional()?;
-
-                    if let Some(time) = last_time {
-                        let now = Local::now();
-
-                        if (now.signed_duration_since(time)) <= Duration::minutes(30) {
-                            dbg!(now.signed_duration_since(time).num_minutes());
-
-                            return Ok(());
-                        }
-                    }
-
-                    diesel::insert_into(hour_records::table)
-                        .values((
-                            hour_records::fk_device_id.eq(&id),
-                            hour_records::co.eq(values[0]),
-                            hour_records::co2.eq(values[1]),
-                            hour_records::temperature.eq(values[2]),
-                            hour_records::humidity.eq(values[3]),
-                            hour_records::noise.eq(values[4]),
-                            hour_records::pm_10.eq(values[5]),
-                            hour_records::pm_25.eq(values[6]),
-                            hour_records::pm_100.eq(values[7]),
-                            hour_records::pm_particles_03.eq(values[8]),
-                            hour_records::pm_particles_05.eq(values[9]),
-                            hour_records::pm_particles_10.eq(values[10]),
-                            hour_records::pm_particles_25.eq(values[11]),
-                            hour_records::pm_particles_50.eq(values[12]),
-                            hour_records::pm_particles_100.eq(values[13]),
-                            hour_records::created_at.eq(Local::now()),
-                        ))
-                        .execute(conn)
-                        .await?;
-
-                    Result::<(), Error>::Ok(())
+                    Result::<bool, Error>::Ok(true)
                 }
                 .scope_boxed()
             })
@@ -222,6 +223,7 @@ impl Backend {
                 last_record::pm_particles_25,
                 last_record::pm_particles_50,
                 last_record::pm_particles_100,
+                last_record::quality,
                 last_record::updated_at,
             ))
             .get_result::<ReadingDateSelect>(connection)
@@ -245,6 +247,7 @@ impl Backend {
                     pm_particles_25,
                     pm_particles_50,
                     pm_particles_100,
+                    quality,
                     updated_at,
                 )| {
                     Reading {
@@ -265,98 +268,21 @@ impl Backend {
                         pm_particles_50,
                         pm_particles_100,
 
+                        quality,
                         updated_at,
                     }
                 },
             )
-            .unwrap_or_else(|| Reading::default()))
-    }
-
-    pub async fn get_device_records(
-        &self,
-        connection: &mut PooledConnection<'static, AsyncPgConnection>,
-        recording: LastReading,
-        id: &str,
-    ) -> Result<Vec<Readings>, Error> {
-        let now = Local::now();
-
-        let now = match recording {
-            LastReading::Days7 => now - Duration::days(7),
-            LastReading::Hours24 => now - Duration::days(1),
-            LastReading::Last => return Err(Error::NotFound),
-        };
-
-        let data = hour_records::table
-            .filter(
-                hour_records::fk_device_id
-                    .eq(id)
-                    .and(hour_records::created_at.gt(now)),
-            )
-            .select((
-                hour_records::co,
-                hour_records::co2,
-                hour_records::temperature,
-                hour_records::humidity,
-                hour_records::noise,
-                hour_records::pm_10,
-                hour_records::pm_25,
-                hour_records::pm_100,
-                hour_records::pm_particles_03,
-                hour_records::pm_particles_05,
-                hour_records::pm_particles_10,
-                hour_records::pm_particles_25,
-                hour_records::pm_particles_50,
-                hour_records::pm_particles_100,
-                hour_records::created_at,
-            ))
-            .get_results::<ReadingDateSelect>(connection)
-            .await?;
-
-        Ok(data
-            .into_iter()
-            .map(
-                |(
-                    co,
-                    co2,
-                    temperature,
-                    humidity,
-                    noise,
-                    pm_10,
-                    pm_25,
-                    pm_100,
-                    pm_particles_03,
-                    pm_particles_05,
-                    pm_particles_10,
-                    pm_particles_25,
-                    pm_particles_50,
-                    pm_particles_100,
-                    created_at,
-                )| Readings {
-                    co,
-                    co2,
-                    temperature,
-                    humidity,
-                    noise,
-                    pm_10,
-                    pm_25,
-                    pm_100,
-                    pm_particles_03,
-                    pm_particles_05,
-                    pm_particles_10,
-                    pm_particles_25,
-                    pm_particles_50,
-                    pm_particles_100,
-                    created_at,
-                },
-            )
-            .collect())
+            .unwrap_or_default())
     }
 
+    /// The last records of `ids`, of every device when `None`.
     pub async fn get_devices_last_records(
         &self,
         connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        ids: Option<&[String]>,
     ) -> Result<Vec<DevicesReading>, Error> {
-        let records = last_record::table
+        let mut query = last_record::table
             .select((
                 last_record::fk_device_id,
                 last_record::co,
@@ -373,23 +299,34 @@ impl Backend {
                 last_record::pm_particles_25,
                 last_record::pm_particles_50,
                 last_record::pm_particles_100,
+                last_record::quality,
+                last_record::updated_at,
             ))
+            .into_boxed();
+
+        if let Some(ids) = ids {
+            query = query.filter(last_record::fk_device_id.eq_any(ids));
+        }
+
+        let records = query
             .get_results::<(
                 String,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
-                f32,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                Option<f32>,
+                i32,
+                DateTime<Local>,
             )>(connection)
             .await?;
 
@@ -412,6 +349,8 @@ impl Backend {
                     pm_particles_25,
                     pm_particles_50,
                     pm_particles_100,
+                    quality,
+                    updated_at,
                 )| DevicesReading {
                     id,
                     co,
@@ -428,6 +367,8 @@ impl Backend {
                     pm_particles_25,
                     pm_particles_50,
                     pm_particles_100,
+                    quality,
+                    updated_at,
                 },
             )
             .collect())
@@ -437,9 +378,9 @@ impl Backend {
         &self,
         connection: &mut PooledConnection<'static, AsyncPgConnection>,
     ) -> Result<Vec<(String, DateTime<Local>)>, Error> {
-        Ok(last_record::table
+        last_record::table
             .select((last_record::fk_device_id, last_record::updated_at))
             .get_results::<(String, DateTime<Local>)>(connection)
-            .await?)
+            .await
     }
 }
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use diesel::{result::Error, ExpressionMethods, QueryDsl};
+use diesel_async::{
+    pooled_connection::bb8::PooledConnection, scoped_futures::ScopedFutureExt, AsyncPgConnection,
+    RunQueryDsl,
+};
+
+use crate::{
+    db::schema::device_sensors,
+    metric::{Metric, Sensor},
+};
+
+use super::Backend;
+
+type SensorSelect = (String, String, String, Option<String>);
+
+/// Rows naming a metric this build does not know are skipped.
+fn sensor((device_id, metric, unit, model): SensorSelect) -> Option<(String, Sensor)> {
+    let metric = metric.parse::<Metric>().ok()?;
+
+    Some((
+        device_id,
+        Sensor {
+            metric,
+            unit,
+            model,
+        },
+    ))
+}
+
+impl Backend {
+    /// The sensors of a device in frame order.
+    pub async fn get_device_sensors(
+        &self,
+        connection: &mut AsyncPgConnection,
+        id: &str,
+    ) -> Result<Vec<Sensor>, Error> {
+        let mut sensors = self
+            .get_devices_sensors(connection, &[id.to_string()])
+            .await?
+            .remove(id)
+            .unwrap_or_default();
+
+        sensors.sort_by_key(|s| s.metric);
+
+        Ok(sensors)
+    }
+
+    /// The sensors of every device in `ids`, devices without any are left
+    /// out.
+    pub async fn get_devices_sensors(
+        &self,
+        connection: &mut AsyncPgConnection,
+        ids: &[String],
+    ) -> Result<HashMap<String, Vec<Sensor>>, Error> {
+        let rows = device_sensors::table
+            .filter(device_sensors::fk_device_id.eq_any(ids))
+            .select((
+                device_sensors::fk_device_id,
+                device_sensors::metric,
+                device_sensors::unit,
+                device_sensors::model,
+            ))
+            .get_results::<SensorSelect>(connection)
+            .await?;
+
+        let mut sensors = HashMap::<String, Vec<Sensor>>::new();
+
+        for (id, sensor) in rows.into_iter().filter_map(sensor) {
+            sensors.entry(id).or_default().push(sensor);
+        }
+
+        for list in sensors.values_mut() {
+            list.sort_by_key(|s| s.metric);
+        }
+
+        Ok(sensors)
+    }
+
+    /// Replaces the sensor profile of a device.
+    pub async fn set_device_sensors(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+        sensors: &[Sensor],
+    ) -> Result<(), Error> {
+        connection
+            .build_transaction()
+            .run(|conn| {
+                async move {
+                    diesel::delete(
+                        device_sensors::table.filter(device_sensors::fk_device_id.eq(id)),
+                    )
+                    .execute(conn)
+                    .await?;
+
+                    self.add_device_sensors(conn, id, sensors).await
+                }
+                .scope_boxed()
+            })
+            .await
+    }
+
+    pub(super) async fn add_device_sensors(
+        &self,
+        connection: &mut AsyncPgConnection,
+        id: &str,
+        sensors: &[Sensor],
+    ) -> Result<(), Error> {
+        let rows = sensors
+            .iter()
+            .map(|s| {
+                (
+                    device_sensors::fk_device_id.eq(id),
+                    device_sensors::metric.eq(s.metric.as_str()),
+                    device_sensors::unit.eq(&s.unit),
+                    device_sensors::model.eq(s.model.as_deref()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        diesel::insert_into(device_sensors::table)
+            .values(rows)
+            .execute(connection)
+            .await?;
+
+        Ok(())
+    }
+}
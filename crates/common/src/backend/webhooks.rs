@@ -0,0 +1,197 @@
+use chrono::{DateTime, Local};
+use diesel::{result::Error, ExpressionMethods, QueryDsl};
+use diesel_async::{pooled_connection::bb8::PooledConnection, AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+
+use crate::{
+    db::schema::{webhook_dead_letters, webhooks},
+    signing,
+};
+
+use super::{pagination::Page, Backend};
+
+const WEBHOOK_COLUMNS: (
+    webhooks::id,
+    webhooks::url,
+    webhooks::fk_device_id,
+    webhooks::data,
+    webhooks::device_active,
+    webhooks::secret,
+    webhooks::created_at,
+) = (
+    webhooks::id,
+    webhooks::url,
+    webhooks::fk_device_id,
+    webhooks::data,
+    webhooks::device_active,
+    webhooks::secret,
+    webhooks::created_at,
+);
+
+type WebhookSelect = (
+    i32,
+    String,
+    Option<String>,
+    bool,
+    bool,
+    String,
+    DateTime<Local>,
+);
+
+/// A subscription to the events of one device, or of every device when
+/// `device_id` is `None`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub device_id: Option<String>,
+    /// Receives every accepted reading.
+    pub data: bool,
+    /// Receives online and offline changes.
+    pub device_active: bool,
+    /// Key of the HMAC sent with every delivery, only shown on creation.
+    #[serde(skip)]
+    pub secret: String,
+    pub created_at: DateTime<Local>,
+}
+
+impl From<WebhookSelect> for Webhook {
+    fn from((id, url, device_id, data, device_active, secret, created_at): WebhookSelect) -> Self {
+        Self {
+            id,
+            url,
+            device_id,
+            data,
+            device_active,
+            secret,
+            created_at,
+        }
+    }
+}
+
+/// A delivery that failed on every attempt.
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub payload: String,
+    pub attempts: i32,
+    pub error: String,
+    pub created_at: DateTime<Local>,
+}
+
+impl Backend {
+    pub async fn create_webhook(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        url: String,
+        device_id: Option<String>,
+        data: bool,
+        device_active: bool,
+    ) -> Result<Webhook, Error> {
+        let webhook = diesel::insert_into(webhooks::table)
+            .values((
+                webhooks::url.eq(url),
+                webhooks::fk_device_id.eq(device_id),
+                webhooks::data.eq(data),
+                webhooks::device_active.eq(device_active),
+                webhooks::secret.eq(signing::generate_secret()),
+            ))
+            .returning(WEBHOOK_COLUMNS)
+            .get_result::<WebhookSelect>(connection)
+            .await?;
+
+        Ok(webhook.into())
+    }
+
+    pub async fn list_webhooks(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+    ) -> Result<Vec<Webhook>, Error> {
+        let data = webhooks::table
+            .select(WEBHOOK_COLUMNS)
+            .order_by(webhooks::id)
+            .get_results::<WebhookSelect>(connection)
+            .await?;
+
+        Ok(data.into_iter().map(Webhook::from).collect())
+    }
+
+    /// Removes a webhook with its dead letters, `false` if it did not exist.
+    pub async fn delete_webhook(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: i32,
+    ) -> Result<bool, Error> {
+        let deleted = diesel::delete(webhooks::table.filter(webhooks::id.eq(id)))
+            .execute(connection)
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    pub async fn add_webhook_dead_letter(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        webhook_id: i32,
+        payload: &str,
+        attempts: i32,
+        error: &str,
+    ) -> Result<(), Error> {
+        diesel::insert_into(webhook_dead_letters::table)
+            .values((
+                webhook_dead_letters::fk_webhook_id.eq(webhook_id),
+                webhook_dead_letters::payload.eq(payload),
+                webhook_dead_letters::attempts.eq(attempts),
+                webhook_dead_letters::error.eq(error),
+                webhook_dead_letters::created_at.eq(Local::now()),
+            ))
+            .execute(connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Dead letters of a webhook, oldest first, starting after the dead
+    /// letter `after`.
+    pub async fn get_webhook_dead_letters(
+        &self,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+        webhook_id: i32,
+        after: Option<i64>,
+        limit: i64,
+    ) -> Result<Page<DeadLetter>, Error> {
+        let mut query = webhook_dead_letters::table
+            .filter(webhook_dead_letters::fk_webhook_id.eq(webhook_id))
+            .select((
+                webhook_dead_letters::id,
+                webhook_dead_letters::payload,
+                webhook_dead_letters::attempts,
+                webhook_dead_letters::error,
+                webhook_dead_letters::created_at,
+            ))
+            .order_by(webhook_dead_letters::id)
+            .limit(limit + 1)
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(webhook_dead_letters::id.gt(after));
+        }
+
+        let data = query
+            .get_results::<(i64, String, i32, String, DateTime<Local>)>(connection)
+            .await?;
+
+        let rows = data
+            .into_iter()
+            .map(|(id, payload, attempts, error, created_at)| DeadLetter {
+                id,
+                payload,
+                attempts,
+                error,
+                created_at,
+            })
+            .collect();
+
+        Ok(Page::from_rows(rows, limit, |d| d.id.to_string()))
+    }
+}
@@ -0,0 +1,179 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{Metric, METRIC_COUNT};
+
+/// Highest relative humidity the PM correction is computed for, the growth
+/// factor runs off to infinity towards saturation.
+const MAX_CORRECTED_HUMIDITY: f32 = 0.95;
+/// Constant term and up to a cubic one.
+const MAX_COEFFICIENTS: usize = 4;
+
+/// One version of the correction of a metric of a device, in effect from
+/// `effective_from` until the next version of the same metric.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub metric: Metric,
+    /// Polynomial over the raw value, constant term first. `[offset, gain]`
+    /// is a linear correction.
+    pub coefficients: Vec<f32>,
+    /// Hygroscopic growth factor κ of the particles. Particulate values are
+    /// divided by `1 + κ·h / (1 - h)` for the relative humidity `h`, as
+    /// particles swell with water in damp air.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub humidity_kappa: Option<f32>,
+    pub effective_from: DateTime<Local>,
+}
+
+impl Calibration {
+    pub fn check(&self) -> Result<(), String> {
+        if self.coefficients.is_empty() || self.coefficients.len() > MAX_COEFFICIENTS {
+            return Err(format!(
+                "between 1 and {MAX_COEFFICIENTS} coefficients are needed"
+            ));
+        }
+
+        if !self.coefficients.iter().all(|c| c.is_finite()) {
+            return Err("coefficients must be finite".to_string());
+        }
+
+        match self.humidity_kappa {
+            Some(_) if !self.metric.is_particulate() => Err(format!(
+                "humidity correction only applies to particulates, not {}",
+                self.metric
+            )),
+            Some(kappa) if !(kappa.is_finite() && kappa >= 0.0) => {
+                Err("kappa must be a positive number".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The corrected value, `humidity` in percent being the already corrected
+    /// one of the same reading.
+    pub fn apply(&self, value: f32, humidity: Option<f32>) -> f32 {
+        let value = self
+            .coefficients
+            .iter()
+            .rev()
+            .fold(0.0, |acc, c| acc * value + c);
+
+        match (self.humidity_kappa, humidity) {
+            (Some(kappa), Some(humidity)) => {
+                let h = (humidity / 100.0).clamp(0.0, MAX_CORRECTED_HUMIDITY);
+
+                value / (1.0 + kappa * h / (1.0 - h))
+            }
+            _ => value,
+        }
+    }
+}
+
+/// Corrects `values` of a reading taken at `at` with the newest version of
+/// each metric in `calibrations` effective by then. Metrics without one are
+/// left as they are.
+pub fn calibrate(
+    values: &[Option<f32>; METRIC_COUNT],
+    calibrations: &[Calibration],
+    at: DateTime<Local>,
+) -> [Option<f32>; METRIC_COUNT] {
+    let mut corrected = *values;
+
+    // Frame order, humidity comes before the particulates corrected with it.
+    for metric in Metric::ALL {
+        let calibration = calibrations
+            .iter()
+            .filter(|c| c.metric == metric && c.effective_from <= at)
+            .max_by_key(|c| c.effective_from);
+
+        let (Some(calibration), Some(value)) = (calibration, values[metric.index()]) else {
+            continue;
+        };
+
+        corrected[metric.index()] =
+            Some(calibration.apply(value, corrected[Metric::Humidity.index()]));
+    }
+
+    corrected
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn calibration(metric: Metric, coefficients: &[f32], days_ago: i64) -> Calibration {
+        Calibration {
+            metric,
+            coefficients: coefficients.to_vec(),
+            humidity_kappa: None,
+            effective_from: Local::now() - Duration::days(days_ago),
+        }
+    }
+
+    fn reading(co: f32, humidity: f32, pm_25: f32) -> [Option<f32>; METRIC_COUNT] {
+        let mut values = [None; METRIC_COUNT];
+        values[Metric::Co.index()] = Some(co);
+        values[Metric::Humidity.index()] = Some(humidity);
+        values[Metric::Pm25.index()] = Some(pm_25);
+        values
+    }
+
+    #[test]
+    fn polynomial_is_applied() {
+        let linear = calibration(Metric::Co, &[1.0, 2.0], 1);
+        let quadratic = calibration(Metric::Co, &[1.0, 0.0, 0.5], 1);
+
+        assert_eq!(linear.apply(3.0, None), 7.0);
+        assert_eq!(quadratic.apply(4.0, None), 9.0);
+    }
+
+    #[test]
+    fn newest_effective_version_is_used() {
+        let calibrations = [
+            calibration(Metric::Co, &[0.0, 2.0], 10),
+            calibration(Metric::Co, &[0.0, 3.0], 5),
+            calibration(Metric::Co, &[0.0, 4.0], -1),
+        ];
+
+        let now = calibrate(&reading(1.0, 50.0, 10.0), &calibrations, Local::now());
+        let earlier = calibrate(
+            &reading(1.0, 50.0, 10.0),
+            &calibrations,
+            Local::now() - Duration::days(7),
+        );
+
+        assert_eq!(now[Metric::Co.index()], Some(3.0));
+        assert_eq!(earlier[Metric::Co.index()], Some(2.0));
+        assert_eq!(now[Metric::Pm25.index()], Some(10.0));
+    }
+
+    #[test]
+    fn particulates_use_corrected_humidity() {
+        let calibrations = [
+            calibration(Metric::Humidity, &[-10.0, 1.0], 1),
+            Calibration {
+                humidity_kappa: Some(0.5),
+                ..calibration(Metric::Pm25, &[0.0, 1.0], 1)
+            },
+        ];
+
+        let values = calibrate(&reading(1.0, 60.0, 20.0), &calibrations, Local::now());
+
+        assert_eq!(values[Metric::Humidity.index()], Some(50.0));
+        assert_eq!(values[Metric::Pm25.index()], Some(20.0 / 1.5));
+    }
+
+    #[test]
+    fn humidity_correction_is_only_for_particulates() {
+        let co = Calibration {
+            humidity_kappa: Some(0.5),
+            ..calibration(Metric::Co, &[0.0, 1.0], 1)
+        };
+
+        assert!(co.check().is_err());
+        assert!(calibration(Metric::Co, &[], 1).check().is_err());
+        assert!(calibration(Metric::Pm25, &[0.0, 1.0], 1).check().is_ok());
+    }
+}
@@ -15,5 +15,5 @@ pub async fn establish_connection() -> Pool<AsyncPgConnection> {
     Pool::builder()
         .build(config)
         .await
-        .expect(&format!("Error connecting to {}", database_url))
+        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
 }
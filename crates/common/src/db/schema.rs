@@ -1,5 +1,80 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    aggregates (fk_device_id, bucket_secs, metric, bucket) {
+        #[max_length = 255]
+        fk_device_id -> Varchar,
+        bucket_secs -> Int4,
+        bucket -> Timestamptz,
+        #[max_length = 32]
+        metric -> Varchar,
+        count -> Int4,
+        sum -> Float8,
+        sum_squares -> Float8,
+        min -> Float4,
+        max -> Float4,
+    }
+}
+
+diesel::table! {
+    alert_events (id) {
+        id -> Int8,
+        #[max_length = 64]
+        rule -> Varchar,
+        #[max_length = 255]
+        fk_device_id -> Varchar,
+        #[max_length = 32]
+        metric -> Varchar,
+        #[max_length = 16]
+        state -> Varchar,
+        value -> Float4,
+        threshold -> Float4,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    api_keys (id) {
+        #[max_length = 25]
+        id -> Varchar,
+        #[max_length = 255]
+        name -> Varchar,
+        #[max_length = 16]
+        role -> Varchar,
+        #[max_length = 64]
+        key_hash -> Varchar,
+        created_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    calibrations (id) {
+        id -> Int4,
+        #[max_length = 255]
+        fk_device_id -> Varchar,
+        #[max_length = 32]
+        metric -> Varchar,
+        coefficients -> Array<Nullable<Float4>>,
+        humidity_kappa -> Nullable<Float4>,
+        effective_from -> Timestamptz,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    device_sensors (fk_device_id, metric) {
+        #[max_length = 255]
+        fk_device_id -> Varchar,
+        #[max_length = 32]
+        metric -> Varchar,
+        #[max_length = 16]
+        unit -> Varchar,
+        #[max_length = 64]
+        model -> Nullable<Varchar>,
+    }
+}
+
 diesel::table! {
     devices (id) {
         #[max_length = 25]
@@ -12,6 +87,11 @@ diesel::table! {
         lat -> Float4,
         long -> Float4,
         active -> Bool,
+        #[max_length = 64]
+        secret -> Varchar,
+        public -> Bool,
+        deleted_at -> Nullable<Timestamptz>,
+        secret_issued_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -20,20 +100,21 @@ diesel::table! {
         id -> Int4,
         #[max_length = 255]
         fk_device_id -> Varchar,
-        co -> Float4,
-        co2 -> Float4,
-        noise -> Float4,
-        temperature -> Float4,
-        humidity -> Float4,
-        pm_10 -> Float4,
-        pm_25 -> Float4,
-        pm_100 -> Float4,
-        pm_particles_03 -> Float4,
-        pm_particles_05 -> Float4,
-        pm_particles_10 -> Float4,
-        pm_particles_25 -> Float4,
-        pm_particles_50 -> Float4,
-        pm_particles_100 -> Float4,
+        co -> Nullable<Float4>,
+        co2 -> Nullable<Float4>,
+        noise -> Nullable<Float4>,
+        temperature -> Nullable<Float4>,
+        humidity -> Nullable<Float4>,
+        pm_10 -> Nullable<Float4>,
+        pm_25 -> Nullable<Float4>,
+        pm_100 -> Nullable<Float4>,
+        pm_particles_03 -> Nullable<Float4>,
+        pm_particles_05 -> Nullable<Float4>,
+        pm_particles_10 -> Nullable<Float4>,
+        pm_particles_25 -> Nullable<Float4>,
+        pm_particles_50 -> Nullable<Float4>,
+        pm_particles_100 -> Nullable<Float4>,
+        quality -> Int4,
         created_at -> Timestamptz,
     }
 }
@@ -42,29 +123,105 @@ diesel::table! {
     last_record (fk_device_id) {
         #[max_length = 255]
         fk_device_id -> Varchar,
-        co -> Float4,
-        co2 -> Float4,
-        noise -> Float4,
-        temperature -> Float4,
-        humidity -> Float4,
-        pm_10 -> Float4,
-        pm_25 -> Float4,
-        pm_100 -> Float4,
-        pm_particles_03 -> Float4,
-        pm_particles_05 -> Float4,
-        pm_particles_10 -> Float4,
-        pm_particles_25 -> Float4,
-        pm_particles_50 -> Float4,
-        pm_particles_100 -> Float4,
+        co -> Nullable<Float4>,
+        co2 -> Nullable<Float4>,
+        noise -> Nullable<Float4>,
+        temperature -> Nullable<Float4>,
+        humidity -> Nullable<Float4>,
+        pm_10 -> Nullable<Float4>,
+        pm_25 -> Nullable<Float4>,
+        pm_100 -> Nullable<Float4>,
+        pm_particles_03 -> Nullable<Float4>,
+        pm_particles_05 -> Nullable<Float4>,
+        pm_particles_10 -> Nullable<Float4>,
+        pm_particles_25 -> Nullable<Float4>,
+        pm_particles_50 -> Nullable<Float4>,
+        pm_particles_100 -> Nullable<Float4>,
+        quality -> Int4,
         updated_at -> Timestamptz,
     }
 }
 
+diesel::table! {
+    readings (id) {
+        id -> Int8,
+        #[max_length = 255]
+        fk_device_id -> Varchar,
+        co -> Nullable<Float4>,
+        co2 -> Nullable<Float4>,
+        temperature -> Nullable<Float4>,
+        humidity -> Nullable<Float4>,
+        noise -> Nullable<Float4>,
+        pm_10 -> Nullable<Float4>,
+        pm_25 -> Nullable<Float4>,
+        pm_100 -> Nullable<Float4>,
+        pm_particles_03 -> Nullable<Float4>,
+        pm_particles_05 -> Nullable<Float4>,
+        pm_particles_10 -> Nullable<Float4>,
+        pm_particles_25 -> Nullable<Float4>,
+        pm_particles_50 -> Nullable<Float4>,
+        pm_particles_100 -> Nullable<Float4>,
+        quality -> Int4,
+        created_at -> Timestamptz,
+        raw -> Nullable<Array<Nullable<Float4>>>,
+    }
+}
+
+diesel::table! {
+    rollup_backfills (fk_device_id) {
+        #[max_length = 255]
+        fk_device_id -> Varchar,
+        since -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    webhook_dead_letters (id) {
+        id -> Int8,
+        fk_webhook_id -> Int4,
+        payload -> Text,
+        attempts -> Int4,
+        error -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int4,
+        url -> Text,
+        #[max_length = 255]
+        fk_device_id -> Nullable<Varchar>,
+        data -> Bool,
+        device_active -> Bool,
+        #[max_length = 64]
+        secret -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::joinable!(aggregates -> devices (fk_device_id));
+diesel::joinable!(alert_events -> devices (fk_device_id));
+diesel::joinable!(calibrations -> devices (fk_device_id));
+diesel::joinable!(device_sensors -> devices (fk_device_id));
 diesel::joinable!(hour_records -> devices (fk_device_id));
 diesel::joinable!(last_record -> devices (fk_device_id));
+diesel::joinable!(readings -> devices (fk_device_id));
+diesel::joinable!(rollup_backfills -> devices (fk_device_id));
+diesel::joinable!(webhook_dead_letters -> webhooks (fk_webhook_id));
+diesel::joinable!(webhooks -> devices (fk_device_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    aggregates,
+    alert_events,
+    api_keys,
+    calibrations,
+    device_sensors,
     devices,
     hour_records,
     last_record,
+    readings,
+    rollup_backfills,
+    webhook_dead_letters,
+    webhooks,
 );
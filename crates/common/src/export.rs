@@ -0,0 +1,488 @@
+use std::{io::Write, str::FromStr, sync::Arc};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use diesel_async::{
+    pooled_connection::bb8::{PooledConnection, RunError},
+    AsyncPgConnection,
+};
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, FloatType, Int64Type},
+    errors::ParquetError,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::{parser::parse_message_type, types::Type},
+};
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::{
+    aggregates::Resolution,
+    metric::{Metric, METRIC_COUNT},
+    pagination::HistoryCursor,
+    Backend,
+};
+
+/// Rows fetched from the database at a time.
+const PAGE_SIZE: i64 = 1000;
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("database error: {0}")]
+    DB(#[from] diesel::result::Error),
+    #[error("connection error: {0}")]
+    Pool(#[from] RunError),
+    #[error("write error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("parquet error: {0}")]
+    Parquet(#[from] ParquetError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            ExportFormat::Csv,
+            ExportFormat::Ndjson,
+            ExportFormat::Parquet,
+        ]
+        .into_iter()
+        .find(|f| f.extension() == s)
+        .ok_or_else(|| format!("unknown format '{s}'"))
+    }
+}
+
+/// One reading, or the means of one bucket, of a device. Columns are named
+/// after the metrics, like the fields of the readings sent to clients.
+#[derive(Debug)]
+pub struct ExportRow {
+    pub device_id: String,
+    pub time: DateTime<Local>,
+    pub values: [Option<f32>; METRIC_COUNT],
+}
+
+impl Serialize for ExportRow {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2 + METRIC_COUNT))?;
+
+        map.serialize_entry("device_id", &self.device_id)?;
+        map.serialize_entry("time", &self.time)?;
+
+        for metric in Metric::ALL {
+            map.serialize_entry(metric.as_str(), &self.values[metric.index()])?;
+        }
+
+        map.end()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportQuery {
+    pub devices: Vec<String>,
+    pub from: DateTime<Local>,
+    pub to: DateTime<Local>,
+    pub resolution: Resolution,
+}
+
+/// Walks the history of every queried device a page at a time, so an export
+/// never holds more than one page in memory.
+#[derive(Debug)]
+pub struct ExportPages {
+    query: ExportQuery,
+    device: usize,
+    cursor: Option<HistoryCursor>,
+}
+
+impl ExportPages {
+    pub fn new(query: ExportQuery) -> Self {
+        Self {
+            query,
+            device: 0,
+            cursor: None,
+        }
+    }
+
+    /// The next page of rows, `None` once every device is done. Streaming
+    /// callers check out a connection per page, so a slow reader does not
+    /// hold one while it catches up.
+    pub async fn next(
+        &mut self,
+        backend: &Backend,
+        connection: &mut PooledConnection<'static, AsyncPgConnection>,
+    ) -> Result<Option<Vec<ExportRow>>, diesel::result::Error> {
+        while let Some(id) = self.query.devices.get(self.device) {
+            let page = backend
+                .get_device_history(
+                    connection,
+                    id,
+                    self.query.from,
+                    self.query.to,
+                    self.query.resolution,
+                    &[],
+                    self.cursor,
+                    PAGE_SIZE,
+                )
+                .await?;
+
+            self.cursor = page
+                .next_cursor
+                .map(|c| c.parse().expect("cursors of a page parse"));
+
+            if self.cursor.is_none() {
+                self.device += 1;
+            }
+
+            if page.data.is_empty() {
+                continue;
+            }
+
+            let rows = page
+                .data
+                .into_iter()
+                .map(|reading| ExportRow {
+                    device_id: id.clone(),
+                    time: reading.bucket,
+                    values: Metric::ALL.map(|m| reading.metrics.get(&m).map(|a| a.mean as f32)),
+                })
+                .collect();
+
+            return Ok(Some(rows));
+        }
+
+        Ok(None)
+    }
+}
+
+/// Writes rows in one of the export formats. Parquet gets a row group per
+/// `write` and its footer on `finish`.
+pub struct Exporter<W: Write + Send> {
+    inner: Inner<W>,
+}
+
+enum Inner<W: Write + Send> {
+    Csv(W),
+    Ndjson(W),
+    Parquet(SerializedFileWriter<W>),
+}
+
+impl<W: Write + Send> Exporter<W> {
+    pub fn new(format: ExportFormat, mut writer: W) -> Result<Self, ExportError> {
+        let inner = match format {
+            ExportFormat::Csv => {
+                write!(writer, "device_id,time")?;
+
+                for metric in Metric::ALL {
+                    write!(writer, ",{metric}")?;
+                }
+
+                writeln!(writer)?;
+
+                Inner::Csv(writer)
+            }
+            ExportFormat::Ndjson => Inner::Ndjson(writer),
+            ExportFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+
+                Inner::Parquet(SerializedFileWriter::new(
+                    writer,
+                    Arc::new(parquet_schema()),
+                    Arc::new(properties),
+                )?)
+            }
+        };
+
+        Ok(Self { inner })
+    }
+
+    pub fn write(&mut self, rows: &[ExportRow]) -> Result<(), ExportError> {
+        match &mut self.inner {
+            Inner::Csv(writer) => {
+                for row in rows {
+                    write!(
+                        writer,
+                        "{},{}",
+                        row.device_id,
+                        row.time.to_rfc3339_opts(SecondsFormat::Micros, true)
+                    )?;
+
+                    for value in row.values {
+                        match value {
+                            Some(v) => write!(writer, ",{v}")?,
+                            None => write!(writer, ",")?,
+                        }
+                    }
+
+                    writeln!(writer)?;
+                }
+            }
+            Inner::Ndjson(writer) => {
+                for row in rows {
+                    serde_json::to_writer(&mut *writer, row)?;
+                    writeln!(writer)?;
+                }
+            }
+            Inner::Parquet(writer) => write_row_group(writer, rows)?,
+        }
+
+        Ok(())
+    }
+
+    /// The underlying writer, for taking out what has been written so far.
+    pub fn get_mut(&mut self) -> &mut W {
+        match &mut self.inner {
+            Inner::Csv(writer) | Inner::Ndjson(writer) => writer,
+            Inner::Parquet(writer) => writer.inner_mut(),
+        }
+    }
+
+    pub fn finish(self) -> Result<W, ExportError> {
+        match self.inner {
+            Inner::Csv(mut writer) | Inner::Ndjson(mut writer) => {
+                writer.flush()?;
+
+                Ok(writer)
+            }
+            Inner::Parquet(writer) => Ok(writer.into_inner()?),
+        }
+    }
+}
+
+fn parquet_schema() -> Type {
+    let metrics = Metric::ALL
+        .iter()
+        .map(|m| format!("OPTIONAL FLOAT {m};"))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    parse_message_type(&format!(
+        "message reading {{
+            REQUIRED BYTE_ARRAY device_id (UTF8);
+            REQUIRED INT64 time (TIMESTAMP(MICROS, true));
+            {metrics}
+        }}"
+    ))
+    .expect("the export schema is valid")
+}
+
+fn write_row_group<W: Write + Send>(
+    writer: &mut SerializedFileWriter<W>,
+    rows: &[ExportRow],
+) -> Result<(), ParquetError> {
+    let mut group = writer.next_row_group()?;
+
+    let mut column = group.next_column()?.expect("device_id column");
+    let ids = rows
+        .iter()
+        .map(|r| ByteArray::from(r.device_id.as_str()))
+        .collect::<Vec<_>>();
+    column
+        .typed::<ByteArrayType>()
+        .write_batch(&ids, None, None)?;
+    column.close()?;
+
+    let mut column = group.next_column()?.expect("time column");
+    let times = rows
+        .iter()
+        .map(|r| r.time.timestamp_micros())
+        .collect::<Vec<_>>();
+    column
+        .typed::<Int64Type>()
+        .write_batch(&times, None, None)?;
+    column.close()?;
+
+    for metric in Metric::ALL {
+        let mut column = group.next_column()?.expect("metric column");
+
+        let values = rows
+            .iter()
+            .filter_map(|r| r.values[metric.index()])
+            .collect::<Vec<_>>();
+        // Definition level 0 marks a missing value.
+        let levels = rows
+            .iter()
+            .map(|r| r.values[metric.index()].is_some() as i16)
+            .collect::<Vec<_>>();
+
+        column
+            .typed::<FloatType>()
+            .write_batch(&values, Some(&levels), None)?;
+        column.close()?;
+    }
+
+    group.close()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::Field,
+    };
+
+    use super::*;
+
+    fn rows() -> Vec<ExportRow> {
+        let time = |s: &str| {
+            DateTime::parse_from_rfc3339(s)
+                .unwrap()
+                .with_timezone(&Local)
+        };
+
+        let mut values = [None; METRIC_COUNT];
+        values[Metric::Co.index()] = Some(1.5);
+        values[Metric::Pm25.index()] = Some(8.0);
+
+        vec![
+            ExportRow {
+                device_id: "abc".to_string(),
+                time: time("2026-10-18T06:00:00.123456Z"),
+                values,
+            },
+            ExportRow {
+                device_id: "def".to_string(),
+                time: time("2026-10-18T07:00:00Z"),
+                values: [None; METRIC_COUNT],
+            },
+        ]
+    }
+
+    /// Writes the rows over two calls, like pages of an export.
+    fn export(format: ExportFormat) -> Vec<u8> {
+        let rows = rows();
+        let mut exporter = Exporter::new(format, Vec::new()).unwrap();
+
+        exporter.write(&rows[..1]).unwrap();
+        exporter.write(&rows[1..]).unwrap();
+        exporter.finish().unwrap()
+    }
+
+    #[test]
+    fn csv_round_trips() {
+        let csv = String::from_utf8(export(ExportFormat::Csv)).unwrap();
+        let lines = csv
+            .lines()
+            .map(|l| l.split(',').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0][..3], ["device_id", "time", "co"]);
+
+        for (line, row) in lines[1..].iter().zip(rows()) {
+            assert_eq!(line.len(), 2 + METRIC_COUNT);
+            assert_eq!(line[0], row.device_id);
+            assert_eq!(DateTime::parse_from_rfc3339(line[1]).unwrap(), row.time);
+
+            for metric in Metric::ALL {
+                let column = lines[0].iter().position(|c| *c == metric.as_str()).unwrap();
+                let value = Some(line[column]).filter(|v| !v.is_empty());
+
+                assert_eq!(
+                    value.map(|v| v.parse::<f32>().unwrap()),
+                    row.values[metric.index()]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ndjson_round_trips() {
+        let ndjson = String::from_utf8(export(ExportFormat::Ndjson)).unwrap();
+        let lines = ndjson
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+
+        for (line, row) in lines.iter().zip(rows()) {
+            assert_eq!(line["device_id"], row.device_id);
+            assert_eq!(
+                line["time"]
+                    .as_str()
+                    .unwrap()
+                    .parse::<DateTime<Local>>()
+                    .unwrap(),
+                row.time
+            );
+
+            for metric in Metric::ALL {
+                let value = line[metric.as_str()].as_f64().map(|v| v as f32);
+                assert_eq!(value, row.values[metric.index()]);
+            }
+        }
+    }
+
+    #[test]
+    fn parquet_round_trips() {
+        // The reader wants a file, it cannot read from a plain buffer.
+        let path = std::env::temp_dir().join(format!("export-test-{}.parquet", std::process::id()));
+        std::fs::write(&path, export(ExportFormat::Parquet)).unwrap();
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // A row group per write.
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+
+        let read = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(read.len(), 2);
+
+        for (read, row) in read.iter().zip(rows()) {
+            let fields = read.get_column_iter().collect::<Vec<_>>();
+
+            assert_eq!(fields[0].1, &Field::Str(row.device_id.clone()));
+            assert_eq!(
+                fields[1].1,
+                &Field::TimestampMicros(row.time.timestamp_micros())
+            );
+
+            for metric in Metric::ALL {
+                let (name, field) = fields[2 + metric.index()];
+                let expected = match row.values[metric.index()] {
+                    Some(v) => Field::Float(v),
+                    None => Field::Null,
+                };
+
+                assert_eq!(name, metric.as_str());
+                assert_eq!(field, &expected);
+            }
+        }
+    }
+}
@@ -1,4 +1,13 @@
+pub mod alerts;
+pub mod aqi;
+pub mod auth;
 pub mod backend;
+pub mod calibration;
+pub mod config;
 pub mod db;
+pub mod export;
+pub mod metric;
+pub mod signing;
+pub mod validation;
 
-pub use backend::*; 
\ No newline at end of file
+pub use backend::*;
@@ -0,0 +1,178 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Every value an ESP reports, in the order they appear in a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Metric {
+    #[serde(rename = "co")]
+    Co,
+    #[serde(rename = "co2")]
+    Co2,
+    #[serde(rename = "temperature")]
+    Temperature,
+    #[serde(rename = "humidity")]
+    Humidity,
+    #[serde(rename = "noise")]
+    Noise,
+    #[serde(rename = "pm_10")]
+    Pm10,
+    #[serde(rename = "pm_25")]
+    Pm25,
+    #[serde(rename = "pm_100")]
+    Pm100,
+    #[serde(rename = "pm_particles_03")]
+    PmParticles03,
+    #[serde(rename = "pm_particles_05")]
+    PmParticles05,
+    #[serde(rename = "pm_particles_10")]
+    PmParticles10,
+    #[serde(rename = "pm_particles_25")]
+    PmParticles25,
+    #[serde(rename = "pm_particles_50")]
+    PmParticles50,
+    #[serde(rename = "pm_particles_100")]
+    PmParticles100,
+}
+
+pub const METRIC_COUNT: usize = 14;
+
+impl Metric {
+    pub const ALL: [Metric; METRIC_COUNT] = [
+        Metric::Co,
+        Metric::Co2,
+        Metric::Temperature,
+        Metric::Humidity,
+        Metric::Noise,
+        Metric::Pm10,
+        Metric::Pm25,
+        Metric::Pm100,
+        Metric::PmParticles03,
+        Metric::PmParticles05,
+        Metric::PmParticles10,
+        Metric::PmParticles25,
+        Metric::PmParticles50,
+        Metric::PmParticles100,
+    ];
+
+    /// Position of the metric in a frame and in `[_; METRIC_COUNT]` arrays.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Column name used in the records tables.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Metric::Co => "co",
+            Metric::Co2 => "co2",
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::Noise => "noise",
+            Metric::Pm10 => "pm_10",
+            Metric::Pm25 => "pm_25",
+            Metric::Pm100 => "pm_100",
+            Metric::PmParticles03 => "pm_particles_03",
+            Metric::PmParticles05 => "pm_particles_05",
+            Metric::PmParticles10 => "pm_particles_10",
+            Metric::PmParticles25 => "pm_particles_25",
+            Metric::PmParticles50 => "pm_particles_50",
+            Metric::PmParticles100 => "pm_particles_100",
+        }
+    }
+
+    /// Unit of the stock sensor of the box: MQ-7, MH-Z19, DHT22, an analog
+    /// microphone and a PMS5003, which counts particles per 0.1 L of air.
+    pub fn default_unit(self) -> &'static str {
+        match self {
+            Metric::Co | Metric::Co2 => "ppm",
+            Metric::Temperature => "°C",
+            Metric::Humidity => "%",
+            Metric::Noise => "dB",
+            Metric::Pm10 | Metric::Pm25 | Metric::Pm100 => "µg/m³",
+            Metric::PmParticles03
+            | Metric::PmParticles05
+            | Metric::PmParticles10
+            | Metric::PmParticles25
+            | Metric::PmParticles50
+            | Metric::PmParticles100 => "/0.1L",
+        }
+    }
+
+    /// Particle mass or count, which reads high in humid air.
+    pub fn is_particulate(self) -> bool {
+        self >= Metric::Pm10
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Metric::ALL
+            .into_iter()
+            .find(|m| m.as_str() == s)
+            .ok_or_else(|| format!("unknown metric '{s}'"))
+    }
+}
+
+/// One metric a device reports, with the unit its values are in and the
+/// sensor measuring it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sensor {
+    pub metric: Metric,
+    pub unit: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+impl Sensor {
+    pub fn new(metric: Metric) -> Self {
+        Self {
+            metric,
+            unit: metric.default_unit().to_string(),
+            model: None,
+        }
+    }
+
+    /// Every metric in its default unit, the profile of the stock box.
+    pub fn all() -> Vec<Self> {
+        Metric::ALL.into_iter().map(Self::new).collect()
+    }
+}
+
+/// `metric[:unit[:model]]`, the unit defaulting to the one of the stock
+/// sensor.
+impl FromStr for Sensor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, ':');
+        let mut sensor = Sensor::new(parts.next().unwrap_or_default().parse()?);
+
+        if let Some(unit) = parts.next().filter(|u| !u.is_empty()) {
+            sensor.unit = unit.to_string();
+        }
+
+        sensor.model = parts.next().filter(|m| !m.is_empty()).map(str::to_string);
+
+        if sensor.unit.chars().count() > 16 {
+            return Err(format!("unit of {} is longer than 16", sensor.metric));
+        }
+
+        if sensor
+            .model
+            .as_ref()
+            .is_some_and(|m| m.chars().count() > 64)
+        {
+            return Err(format!("model of {} is longer than 64", sensor.metric));
+        }
+
+        Ok(sensor)
+    }
+}
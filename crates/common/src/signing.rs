@@ -0,0 +1,87 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use nanoid::nanoid;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The HMAC does not match the payload.
+    BadSignature,
+    /// The timestamp is too far from the server clock.
+    Stale,
+    /// The nonce was already used within the replay window.
+    Replayed,
+}
+
+/// A fresh secret for a device, shared with its firmware.
+pub fn generate_secret() -> String {
+    nanoid!(32)
+}
+
+/// Hex encoded HMAC-SHA256 of `message` keyed with the device secret.
+pub fn sign(secret: &str, message: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key");
+    mac.update(message.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Checks a hex encoded signature in constant time.
+pub fn verify(secret: &str, message: &str, signature: &str) -> Result<(), SignatureError> {
+    let signature = hex::decode(signature).map_err(|_| SignatureError::BadSignature)?;
+
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key");
+    mac.update(message.as_bytes());
+
+    mac.verify_slice(&signature)
+        .map_err(|_| SignatureError::BadSignature)
+}
+
+/// Remembers recently used nonces per device so a captured frame cannot be
+/// sent again. Frames are only accepted while their timestamp is within
+/// `window` of the server clock, so older nonces can be forgotten.
+#[derive(Debug)]
+pub struct ReplayGuard {
+    window: i64,
+    seen: Mutex<HashMap<String, HashMap<String, i64>>>,
+}
+
+impl ReplayGuard {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window: window.as_secs() as i64,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `timestamp` is in unix seconds. Records the nonce when accepted.
+    pub fn check(
+        &self,
+        device_id: &str,
+        timestamp: i64,
+        nonce: &str,
+    ) -> Result<(), SignatureError> {
+        let now = Utc::now().timestamp();
+
+        if (now - timestamp).abs() > self.window {
+            return Err(SignatureError::Stale);
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        let nonces = seen.entry(device_id.to_string()).or_default();
+
+        nonces.retain(|_, t| (now - *t).abs() <= self.window);
+
+        if nonces.contains_key(nonce) {
+            return Err(SignatureError::Replayed);
+        }
+
+        nonces.insert(nonce.to_string(), timestamp);
+
+        Ok(())
+    }
+}
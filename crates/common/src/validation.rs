@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{Metric, METRIC_COUNT};
+
+/// What happens to a value that is unparseable or out of range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidPolicy {
+    /// Refuse the whole frame.
+    Reject,
+    /// Store the rest of the frame with this value left empty.
+    Drop,
+    /// Pull out of range values back to the nearest bound. Values that are
+    /// not numbers at all are dropped.
+    Clamp,
+}
+
+/// The physical range a sensor can report and how to handle anything else.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct MetricRule {
+    pub min: f32,
+    pub max: f32,
+    pub policy: InvalidPolicy,
+}
+
+impl MetricRule {
+    const fn new(min: f32, max: f32, policy: InvalidPolicy) -> Self {
+        Self { min, max, policy }
+    }
+}
+
+/// A frame whose values passed validation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validated {
+    pub values: [Option<f32>; METRIC_COUNT],
+    /// Bit `n` is set when the value for metric `n` was dropped or clamped.
+    pub quality: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Validator {
+    rules: [MetricRule; METRIC_COUNT],
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        use InvalidPolicy::*;
+
+        Self {
+            rules: [
+                // co (ppm)
+                MetricRule::new(0.0, 2000.0, Drop),
+                // co2 (ppm)
+                MetricRule::new(0.0, 10000.0, Drop),
+                // temperature (°C)
+                MetricRule::new(-40.0, 85.0, Drop),
+                // humidity (%)
+                MetricRule::new(0.0, 100.0, Clamp),
+                // noise (dB)
+                MetricRule::new(0.0, 150.0, Drop),
+                // pm_10, pm_25, pm_100 (µg/m³)
+                MetricRule::new(0.0, 1000.0, Drop),
+                MetricRule::new(0.0, 1000.0, Drop),
+                MetricRule::new(0.0, 1000.0, Drop),
+                // particle counts (per 0.1 L)
+                MetricRule::new(0.0, 65535.0, Drop),
+                MetricRule::new(0.0, 65535.0, Drop),
+                MetricRule::new(0.0, 65535.0, Drop),
+                MetricRule::new(0.0, 65535.0, Drop),
+                MetricRule::new(0.0, 65535.0, Drop),
+                MetricRule::new(0.0, 65535.0, Drop),
+            ],
+        }
+    }
+}
+
+impl Validator {
+    pub fn rule(&self, metric: Metric) -> &MetricRule {
+        &self.rules[metric.index()]
+    }
+
+    pub fn set_rule(&mut self, metric: Metric, rule: MetricRule) {
+        self.rules[metric.index()] = rule;
+    }
+
+    /// Applies each metric's rule, returning the first metric that rejected
+    /// the frame if any did. `None` marks a value the device did not send,
+    /// unparseable values should be passed in as `NaN` so they are caught.
+    pub fn validate(&self, raw: [Option<f32>; METRIC_COUNT]) -> Result<Validated, Metric> {
+        let mut values = [None; METRIC_COUNT];
+        let mut quality = 0;
+
+        for metric in Metric::ALL {
+            let i = metric.index();
+            let rule = &self.rules[i];
+
+            let value = match raw[i] {
+                None => None,
+                Some(v) if v.is_finite() && (rule.min..=rule.max).contains(&v) => Some(v),
+                Some(_) if rule.policy == InvalidPolicy::Reject => return Err(metric),
+                Some(v) if v.is_finite() && rule.policy == InvalidPolicy::Clamp => {
+                    Some(v.clamp(rule.min, rule.max))
+                }
+                Some(_) => None,
+            };
+
+            if value != raw[i] {
+                quality |= 1 << i;
+            }
+
+            values[i] = value;
+        }
+
+        Ok(Validated { values, quality })
+    }
+}
@@ -0,0 +1,36 @@
+use clap::Parser;
+use common::{auth::Role, Backend};
+
+#[derive(Debug, Parser)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = "Create a key for the REST API and the WebSocket"
+)]
+struct CliOpts {
+    /// Who or what the key is for.
+    #[clap(short = 'n', long)]
+    pub name: String,
+
+    /// `viewer`, `operator` or `admin`.
+    #[clap(short = 'r', long, default_value = "viewer")]
+    pub role: Role,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = CliOpts::parse();
+
+    let backend = Backend::new().await;
+
+    let mut conn = backend.get_connection().await.unwrap();
+
+    let (api_key, key) = backend
+        .create_api_key(&mut conn, cli.name, cli.role)
+        .await
+        .unwrap();
+
+    println!("Created API Key ID: {}", api_key.id);
+    println!("API Key: {key}");
+}
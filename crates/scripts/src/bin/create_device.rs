@@ -1,5 +1,5 @@
 use clap::Parser;
-use common::Backend;
+use common::{metric::Sensor, Backend};
 
 #[derive(Debug, Parser)]
 #[clap(author, version, about, long_about = "Create a device")]
@@ -14,6 +14,14 @@ struct CliOpts {
     pub long: f32,
     #[clap(short = 'y', long)]
     pub lat: f32,
+
+    /// Comma separated `metric[:unit[:model]]`, every metric by default.
+    #[clap(short = 's', long, value_delimiter = ',')]
+    pub sensors: Vec<Sensor>,
+
+    /// Readable without an API key.
+    #[clap(short = 'p', long)]
+    pub public: bool,
 }
 
 #[tokio::main]
@@ -24,10 +32,18 @@ async fn main() {
 
     let mut conn = backend.get_connection().await.unwrap();
 
-    let id = backend
-        .create_device(&mut conn, cli.name, cli.box_, cli.long, cli.lat)
+    let sensors = match cli.sensors.is_empty() {
+        true => Sensor::all(),
+        false => cli.sensors,
+    };
+
+    let (id, secret) = backend
+        .create_device(
+            &mut conn, cli.name, cli.box_, cli.long, cli.lat, cli.public, &sensors,
+        )
         .await
         .unwrap();
 
     println!("Created Device ID: {id}");
+    println!("Device Secret: {secret}");
 }
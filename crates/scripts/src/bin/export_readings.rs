@@ -0,0 +1,77 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Duration, Local};
+use clap::Parser;
+use common::{
+    aggregates::Resolution,
+    export::{ExportFormat, ExportPages, ExportQuery, Exporter},
+    Backend,
+};
+
+#[derive(Debug, Parser)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = "Export the history of devices as CSV, NDJSON or Parquet"
+)]
+struct CliOpts {
+    /// Device to export, may be repeated.
+    #[clap(short = 'd', long = "device", required = true)]
+    pub devices: Vec<String>,
+
+    /// Start of the range, RFC 3339. Defaults to a day before `to`.
+    #[clap(long)]
+    pub from: Option<DateTime<Local>>,
+    /// End of the range, RFC 3339. Defaults to now.
+    #[clap(long)]
+    pub to: Option<DateTime<Local>>,
+
+    /// One of raw, 5m, 1h or 1d.
+    #[clap(short = 'r', long, default_value = "1h")]
+    pub resolution: Resolution,
+
+    /// One of csv, ndjson or parquet.
+    #[clap(short = 'f', long, default_value = "csv")]
+    pub format: ExportFormat,
+
+    /// File to write to instead of stdout.
+    #[clap(short = 'o', long)]
+    pub output: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = CliOpts::parse();
+
+    let to = cli.to.unwrap_or_else(Local::now);
+    let from = cli.from.unwrap_or(to - Duration::days(1));
+
+    let writer: Box<dyn Write + Send> = match &cli.output {
+        Some(path) => Box::new(File::create(path).expect("Failed creating output file")),
+        None => Box::new(std::io::stdout()),
+    };
+
+    let backend = Backend::new().await;
+
+    let mut conn = backend.get_connection().await.unwrap();
+
+    let mut exporter = Exporter::new(cli.format, BufWriter::new(writer)).unwrap();
+
+    let mut pages = ExportPages::new(ExportQuery {
+        devices: cli.devices,
+        from,
+        to,
+        resolution: cli.resolution,
+    });
+
+    while let Some(rows) = pages.next(&backend, &mut conn).await.unwrap() {
+        exporter.write(&rows).unwrap();
+    }
+
+    exporter.finish().unwrap().flush().unwrap();
+}
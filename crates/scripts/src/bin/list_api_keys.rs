@@ -0,0 +1,28 @@
+use clap::Parser;
+use common::Backend;
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about, long_about = "List the API keys")]
+struct CliOpts {}
+
+#[tokio::main]
+async fn main() {
+    CliOpts::parse();
+
+    let backend = Backend::new().await;
+
+    let mut conn = backend.get_connection().await.unwrap();
+
+    for key in backend.list_api_keys(&mut conn).await.unwrap() {
+        println!(
+            "{} {} ({}) created {}{}",
+            key.id,
+            key.name,
+            key.role,
+            key.created_at.format("%Y-%m-%d %H:%M"),
+            key.revoked_at
+                .map(|t| format!(", revoked {}", t.format("%Y-%m-%d %H:%M")))
+                .unwrap_or_default()
+        );
+    }
+}
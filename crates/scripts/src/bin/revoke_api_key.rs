@@ -0,0 +1,30 @@
+use clap::Parser;
+use common::Backend;
+
+#[derive(Debug, Parser)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = "Revoke an API key, requests using it are rejected from then on"
+)]
+struct CliOpts {
+    #[clap(short = 'i', long)]
+    pub id: String,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = CliOpts::parse();
+
+    let backend = Backend::new().await;
+
+    let mut conn = backend.get_connection().await.unwrap();
+
+    if !backend.revoke_api_key(&mut conn, &cli.id).await.unwrap() {
+        eprintln!("API key {} does not exist or is already revoked", cli.id);
+        std::process::exit(1);
+    }
+
+    println!("Revoked API Key ID: {}", cli.id);
+}
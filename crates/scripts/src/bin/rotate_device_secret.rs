@@ -0,0 +1,30 @@
+use clap::Parser;
+use common::Backend;
+
+#[derive(Debug, Parser)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = "Generate a new signing secret for a device"
+)]
+struct CliOpts {
+    #[clap(short = 'i', long)]
+    pub id: String,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = CliOpts::parse();
+
+    let backend = Backend::new().await;
+
+    let mut conn = backend.get_connection().await.unwrap();
+
+    let secret = backend
+        .rotate_device_secret(&mut conn, &cli.id)
+        .await
+        .unwrap();
+
+    println!("New Device Secret: {secret}");
+}
@@ -0,0 +1,101 @@
+use chrono::{DateTime, Local};
+use clap::Parser;
+use common::{calibration::Calibration, metric::Metric, Backend};
+
+#[derive(Debug, Parser)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = "Add a version of the calibration of a metric of a device, correcting the readings taken since it is effective"
+)]
+struct CliOpts {
+    #[clap(short = 'i', long)]
+    pub id: String,
+
+    #[clap(short = 'm', long)]
+    pub metric: Metric,
+
+    #[clap(
+        short = 'g',
+        long,
+        default_value_t = 1.0,
+        allow_negative_numbers = true
+    )]
+    pub gain: f32,
+    #[clap(
+        short = 'o',
+        long,
+        default_value_t = 0.0,
+        allow_negative_numbers = true
+    )]
+    pub offset: f32,
+
+    /// Comma separated polynomial, constant term first, instead of the gain
+    /// and offset.
+    #[clap(
+        short = 'c',
+        long,
+        value_delimiter = ',',
+        allow_negative_numbers = true,
+        conflicts_with_all = ["gain", "offset"]
+    )]
+    pub coefficients: Vec<f32>,
+
+    /// Hygroscopic growth factor of the particles, corrects particulates for
+    /// humidity.
+    #[clap(short = 'k', long)]
+    pub kappa: Option<f32>,
+
+    /// When the version takes effect, RFC 3339. Defaults to now.
+    #[clap(long)]
+    pub from: Option<DateTime<Local>>,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = CliOpts::parse();
+
+    let calibration = Calibration {
+        metric: cli.metric,
+        coefficients: match cli.coefficients.is_empty() {
+            true => vec![cli.offset, cli.gain],
+            false => cli.coefficients,
+        },
+        humidity_kappa: cli.kappa,
+        effective_from: cli.from.unwrap_or_else(Local::now),
+    };
+
+    if let Err(why) = calibration.check() {
+        eprintln!("Invalid calibration: {why}");
+        std::process::exit(1);
+    }
+
+    let backend = Backend::new().await;
+
+    let mut conn = backend.get_connection().await.unwrap();
+
+    if !backend
+        .check_device_exists(&mut conn, &cli.id)
+        .await
+        .unwrap()
+    {
+        eprintln!("Device {} does not exist", cli.id);
+        std::process::exit(1);
+    }
+
+    backend
+        .add_calibration(&mut conn, &cli.id, &calibration)
+        .await
+        .unwrap();
+
+    let count = backend
+        .recalibrate(&mut conn, &cli.id, calibration.effective_from)
+        .await
+        .unwrap();
+
+    println!(
+        "Calibrated {} from {}, {count} stored readings corrected",
+        calibration.metric, calibration.effective_from
+    );
+}
@@ -0,0 +1,41 @@
+use clap::Parser;
+use common::Backend;
+
+#[derive(Debug, Parser)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = "Make a device readable without an API key, or private again"
+)]
+struct CliOpts {
+    #[clap(short = 'i', long)]
+    pub id: String,
+
+    /// Require a key to read the device.
+    #[clap(long)]
+    pub private: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = CliOpts::parse();
+
+    let backend = Backend::new().await;
+
+    let mut conn = backend.get_connection().await.unwrap();
+
+    if !backend
+        .set_device_public(&mut conn, &cli.id, !cli.private)
+        .await
+        .unwrap()
+    {
+        eprintln!("Device {} does not exist", cli.id);
+        std::process::exit(1);
+    }
+
+    match cli.private {
+        true => println!("Device {} is private", cli.id),
+        false => println!("Device {} is public", cli.id),
+    }
+}
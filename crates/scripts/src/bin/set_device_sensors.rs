@@ -0,0 +1,50 @@
+use clap::Parser;
+use common::{metric::Sensor, Backend};
+
+#[derive(Debug, Parser)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = "Replace the sensors a device reports"
+)]
+struct CliOpts {
+    #[clap(short = 'i', long)]
+    pub id: String,
+
+    /// Comma separated `metric[:unit[:model]]`.
+    #[clap(short = 's', long, value_delimiter = ',', required = true)]
+    pub sensors: Vec<Sensor>,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = CliOpts::parse();
+
+    let backend = Backend::new().await;
+
+    let mut conn = backend.get_connection().await.unwrap();
+
+    if !backend
+        .check_device_exists(&mut conn, &cli.id)
+        .await
+        .unwrap()
+    {
+        eprintln!("Device {} does not exist", cli.id);
+        std::process::exit(1);
+    }
+
+    backend
+        .set_device_sensors(&mut conn, &cli.id, &cli.sensors)
+        .await
+        .unwrap();
+
+    for sensor in cli.sensors {
+        println!(
+            "{} ({}){}",
+            sensor.metric,
+            sensor.unit,
+            sensor.model.map(|m| format!(" {m}")).unwrap_or_default()
+        );
+    }
+}
@@ -0,0 +1,432 @@
+use std::time::Duration;
+
+use common::{
+    alerts::{AlertState, AlertTransition},
+    config::{SmtpSettings, WebhookSettings},
+    Backend,
+};
+use futures::future::BoxFuture;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::{
+    error::{XError, XResult},
+    hub::{Hub, HubEvent},
+};
+
+/// How long a webhook request may take.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Transitions waiting for delivery per sink.
+const SINK_QUEUE: usize = 64;
+
+/// Somewhere alert transitions are delivered to.
+pub trait AlertSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn deliver<'a>(&'a self, transition: &'a AlertTransition) -> BoxFuture<'a, XResult<()>>;
+}
+
+/// Sends transitions to the WebSocket sessions watching the device.
+pub struct WsSink {
+    hub: Hub,
+}
+
+impl WsSink {
+    pub fn new(hub: Hub) -> Self {
+        Self { hub }
+    }
+}
+
+impl AlertSink for WsSink {
+    fn name(&self) -> &'static str {
+        "ws"
+    }
+
+    fn deliver<'a>(&'a self, transition: &'a AlertTransition) -> BoxFuture<'a, XResult<()>> {
+        self.hub.publish(HubEvent::Alert(transition.clone()));
+
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// POSTs every transition as JSON.
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookSink {
+    pub fn new(settings: &WebhookSettings) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .expect("Failed building HTTP client"),
+            url: settings.url.clone(),
+        }
+    }
+}
+
+impl AlertSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn deliver<'a>(&'a self, transition: &'a AlertTransition) -> BoxFuture<'a, XResult<()>> {
+        Box::pin(async move {
+            self.client
+                .post(&self.url)
+                .json(transition)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| XError::Sink(e.to_string()))?;
+
+            Ok(())
+        })
+    }
+}
+
+/// Mails every transition to the configured recipients.
+pub struct SmtpSink {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl SmtpSink {
+    pub fn new(settings: &SmtpSettings) -> XResult<Self> {
+        let sink_error = |e: &dyn std::fmt::Display| XError::Sink(e.to_string());
+
+        let mut builder = if settings.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)
+                .map_err(|e| sink_error(&e))?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host)
+        }
+        .port(settings.port);
+
+        if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: settings.from.parse().map_err(|e| sink_error(&e))?,
+            to: settings
+                .to
+                .iter()
+                .map(|to| to.parse().map_err(|e| sink_error(&e)))
+                .collect::<XResult<_>>()?,
+        })
+    }
+}
+
+impl AlertSink for SmtpSink {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn deliver<'a>(&'a self, transition: &'a AlertTransition) -> BoxFuture<'a, XResult<()>> {
+        Box::pin(async move {
+            let mut message = Message::builder().from(self.from.clone()).subject(format!(
+                "[{}] {} on {}",
+                transition.state.as_str(),
+                transition.rule,
+                transition.device_id
+            ));
+
+            for to in &self.to {
+                message = message.to(to.clone());
+            }
+
+            let body = format!(
+                "Alert {} is {} for device {}.\n\n{} was {} (threshold {}) at {}.\n",
+                transition.rule,
+                transition.state.as_str(),
+                transition.device_id,
+                transition.metric,
+                transition.value,
+                transition.threshold,
+                transition.at.to_rfc3339()
+            );
+
+            let message = message
+                .body(body)
+                .map_err(|e| XError::Sink(e.to_string()))?;
+
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| XError::Sink(e.to_string()))?;
+
+            Ok(())
+        })
+    }
+}
+
+/// Stores every transition and queues it for each sink. Every sink delivers
+/// from its own queue, so a slow or failing one does not hold back the others
+/// nor the dispatcher.
+pub async fn dispatch(
+    backend: Backend,
+    sinks: Vec<Box<dyn AlertSink>>,
+    mut rx: Receiver<AlertTransition>,
+) {
+    let queues = spawn_sinks(sinks);
+
+    while let Some(transition) = rx.recv().await {
+        match transition.state {
+            AlertState::Firing => tracing::warn!(
+                "Alert '{}' firing for device '{}': {} = {}",
+                transition.rule,
+                transition.device_id,
+                transition.metric,
+                transition.value
+            ),
+            AlertState::Resolved => tracing::info!(
+                "Alert '{}' resolved for device '{}'",
+                transition.rule,
+                transition.device_id
+            ),
+        }
+
+        match backend.get_connection().await {
+            Ok(mut conn) => {
+                if let Err(e) = backend.add_alert_event(&mut conn, &transition).await {
+                    tracing::error!("Failed storing alert event: {:?}", e);
+                }
+            }
+            Err(e) => tracing::error!("Failed to get connection: {:?}", e),
+        }
+
+        queue(&queues, &transition);
+    }
+}
+
+/// The queue of each sink, delivered from in order by a task of its own.
+fn spawn_sinks(sinks: Vec<Box<dyn AlertSink>>) -> Vec<(&'static str, Sender<AlertTransition>)> {
+    sinks
+        .into_iter()
+        .map(|sink| {
+            let (tx, mut rx) = channel::<AlertTransition>(SINK_QUEUE);
+            let name = sink.name();
+
+            tokio::spawn(async move {
+                while let Some(transition) = rx.recv().await {
+                    if let Err(e) = sink.deliver(&transition).await {
+                        tracing::error!("Failed delivering alert to {}: {}", sink.name(), e);
+                    }
+                }
+            });
+
+            (name, tx)
+        })
+        .collect()
+}
+
+/// Drops the transition for every sink whose queue is full.
+fn queue(queues: &[(&'static str, Sender<AlertTransition>)], transition: &AlertTransition) {
+    for (name, tx) in queues {
+        if tx.try_send(transition.clone()).is_err() {
+            tracing::error!(
+                "Alert queue of {} is full, dropped '{}' for device '{}'",
+                name,
+                transition.rule,
+                transition.device_id
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Extension, Json, Router};
+    use chrono::Local;
+    use common::metric::Metric;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use crate::hub::Received;
+
+    use super::*;
+
+    fn transition() -> AlertTransition {
+        AlertTransition {
+            rule: "high-co".to_string(),
+            device_id: "abcdefghijklmno".to_string(),
+            metric: Metric::Co,
+            state: AlertState::Firing,
+            value: 40.0,
+            threshold: 35.0,
+            at: Local::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn ws_sink_reaches_watching_sessions() {
+        let hub = Hub::new(1, 0);
+
+        let mut subscriptions = [
+            hub.subscribe_all(),
+            hub.subscribe("abcdefghijklmno"),
+            hub.subscribe("other"),
+        ];
+
+        WsSink::new(hub).deliver(&transition()).await.unwrap();
+
+        let mut delivered = Vec::new();
+
+        for sub in &mut subscriptions {
+            let next = tokio::time::timeout(Duration::from_millis(50), sub.recv()).await;
+            delivered.push(matches!(next, Ok(Some(Received::Event(_)))));
+        }
+
+        assert_eq!(delivered, [true, true, false]);
+    }
+
+    /// Never finishes a delivery.
+    struct StuckSink;
+
+    impl AlertSink for StuckSink {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+
+        fn deliver<'a>(&'a self, _: &'a AlertTransition) -> BoxFuture<'a, XResult<()>> {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    #[tokio::test]
+    async fn stuck_sink_does_not_hold_back_others() {
+        let hub = Hub::new(8, 0);
+        let mut sub = hub.subscribe_all();
+        let queues = spawn_sinks(vec![Box::new(StuckSink), Box::new(WsSink::new(hub))]);
+
+        for _ in 0..3 {
+            queue(&queues, &transition());
+        }
+
+        for _ in 0..3 {
+            let next = tokio::time::timeout(Duration::from_millis(50), sub.recv()).await;
+            assert!(matches!(next, Ok(Some(Received::Event(_)))));
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_sink_posts_json() {
+        let (tx, mut rx) = channel::<AlertTransition>(1);
+
+        let app =
+            Router::new()
+                .route(
+                    "/alerts",
+                    post(
+                        |tx: Extension<Sender<AlertTransition>>,
+                         Json(body): Json<AlertTransition>| async move {
+                            tx.send(body).await.unwrap();
+                        },
+                    ),
+                )
+                .layer(Extension(tx));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let sink = WebhookSink::new(&WebhookSettings {
+            url: format!("http://{addr}/alerts"),
+        });
+
+        let sent = transition();
+        sink.deliver(&sent).await.unwrap();
+
+        assert_eq!(rx.recv().await.unwrap(), sent);
+    }
+
+    #[tokio::test]
+    async fn webhook_sink_reports_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, Router::new()).await.unwrap() });
+
+        let sink = WebhookSink::new(&WebhookSettings {
+            url: format!("http://{addr}/missing"),
+        });
+
+        assert!(sink.deliver(&transition()).await.is_err());
+    }
+
+    /// Accepts one plain SMTP session and returns the message data.
+    async fn smtp_server(listener: TcpListener) -> String {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (read, mut write) = socket.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+        let mut data = String::new();
+
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let reply: &[u8] = match line.split(' ').next().unwrap().to_uppercase().as_str() {
+                "EHLO" => b"250-localhost\r\n250 8BITMIME\r\n",
+                "DATA" => {
+                    write.write_all(b"354 go ahead\r\n").await.unwrap();
+
+                    while let Some(line) = lines.next_line().await.unwrap() {
+                        if line == "." {
+                            break;
+                        }
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+
+                    b"250 queued\r\n"
+                }
+                "QUIT" => {
+                    write.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                }
+                _ => b"250 OK\r\n",
+            };
+
+            write.write_all(reply).await.unwrap();
+        }
+
+        data
+    }
+
+    #[tokio::test]
+    async fn smtp_sink_sends_mail() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(smtp_server(listener));
+
+        let sink = SmtpSink::new(&SmtpSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            username: None,
+            password: None,
+            starttls: false,
+            from: "alerts@example.com".to_string(),
+            to: vec!["ops@example.com".to_string()],
+        })
+        .unwrap();
+
+        sink.deliver(&transition()).await.unwrap();
+        drop(sink);
+
+        let data = server.await.unwrap();
+
+        assert!(data.contains("To: ops@example.com"));
+        assert!(data.contains("Subject: [firing] high-co on abcdefghijklmno"));
+        assert!(data.contains("co was 40 (threshold 35)"));
+    }
+}
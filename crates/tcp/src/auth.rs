@@ -0,0 +1,158 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use common::{auth::Role, Backend};
+use serde_json::json;
+
+/// API key of the caller, instead of an `Authorization: Bearer` header.
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+/// Query parameter holding the API key, browsers cannot set headers when
+/// opening a WebSocket.
+const API_KEY_PARAM: &str = "api_key";
+
+/// What the caller of a request may do, `None` without an API key. Set on
+/// every request by [`authenticate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access(pub Option<Role>);
+
+impl Access {
+    pub fn allows(self, role: Role) -> bool {
+        self.0.is_some_and(|r| r >= role)
+    }
+
+    /// Callers without a key only read the devices flagged public.
+    pub fn public_only(self) -> bool {
+        self.0.is_none()
+    }
+}
+
+fn reject(status: StatusCode, message: &str) -> Response {
+    (
+        status,
+        Json(json!({ "success": false, "message": message })),
+    )
+        .into_response()
+}
+
+/// The API key sent with a request, from the headers or else the query.
+fn presented_key<'a>(headers: &'a HeaderMap, query: Option<&'a str>) -> Option<&'a str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    let header = headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok());
+
+    let param = query.and_then(|q| {
+        q.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == API_KEY_PARAM)
+            .map(|(_, value)| value)
+    });
+
+    bearer.or(header).or(param).map(str::trim)
+}
+
+/// `uri` with the value of an API key in its query hidden, for logging.
+pub fn redacted_uri(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.path().to_string();
+    };
+
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if name == API_KEY_PARAM => format!("{name}=redacted"),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+
+    format!("{}?{}", uri.path(), query)
+}
+
+/// Looks up the API key of the request, if any, and makes its [`Access`]
+/// available to the handlers. An unknown or revoked key is rejected rather
+/// than taken as no key.
+pub async fn authenticate(
+    backend: Extension<Backend>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let access = match presented_key(request.headers(), request.uri().query()) {
+        None => Access(None),
+        Some(key) => {
+            let role = async {
+                let mut conn = backend.get_connection().await.map_err(|e| e.to_string())?;
+
+                backend
+                    .get_api_key_role(&mut conn, key)
+                    .await
+                    .map_err(|e| e.to_string())
+            };
+
+            match role.await {
+                Ok(Some(role)) => Access(Some(role)),
+                Ok(None) => return reject(StatusCode::UNAUTHORIZED, "Invalid API key"),
+                Err(e) => {
+                    tracing::error!("An error has occured: Failed checking API key, {:?}", e);
+                    return reject(StatusCode::INTERNAL_SERVER_ERROR, "Failed checking API key");
+                }
+            }
+        }
+    };
+
+    request.extensions_mut().insert(access);
+
+    next.run(request).await
+}
+
+/// Rejects requests whose key does not have at least the role, for
+/// `from_fn_with_state` on the routes needing it.
+pub async fn require(State(role): State<Role>, request: Request, next: Next) -> Response {
+    match request.extensions().get::<Access>().copied() {
+        Some(access) if access.allows(role) => next.run(request).await,
+        Some(Access(Some(_))) => reject(StatusCode::FORBIDDEN, "Insufficient role"),
+        _ => reject(StatusCode::UNAUTHORIZED, "API key required"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn key_is_taken_from_headers_before_query() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(presented_key(&headers, Some("a=1&api_key=q")), Some("q"));
+        assert_eq!(presented_key(&headers, Some("a=1")), None);
+
+        headers.insert(API_KEY_HEADER, HeaderValue::from_static("h"));
+        assert_eq!(presented_key(&headers, Some("api_key=q")), Some("h"));
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer b"));
+        assert_eq!(presented_key(&headers, Some("api_key=q")), Some("b"));
+    }
+
+    #[test]
+    fn key_in_query_is_redacted() {
+        let uri = "/ws?a=1&api_key=secret".parse::<Uri>().unwrap();
+        assert_eq!(redacted_uri(&uri), "/ws?a=1&api_key=redacted");
+
+        let uri = "/devices/a".parse::<Uri>().unwrap();
+        assert_eq!(redacted_uri(&uri), "/devices/a");
+    }
+
+    #[test]
+    fn roles_include_the_ones_below() {
+        assert!(Access(Some(Role::Admin)).allows(Role::Operator));
+        assert!(!Access(Some(Role::Viewer)).allows(Role::Operator));
+        assert!(!Access(None).allows(Role::Viewer));
+    }
+}
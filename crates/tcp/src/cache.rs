@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use common::{aqi::Aqi, calibration::Calibration, metric::Metric};
+use futures::StreamExt;
+use tokio_postgres::{AsyncMessage, NoTls};
+
+use crate::{error::XResult, process_esp::DeviceStore};
+
+/// Channel the database notifies with the id of a device whose sensors or
+/// calibrations changed.
+const CHANNEL: &str = "device_changed";
+
+/// Wait before listening again after the connection was lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// What ingestion needs to know about a device for every reading.
+#[derive(Debug)]
+pub struct DeviceProfile {
+    /// The metrics the device has a sensor for.
+    pub sensors: Vec<Metric>,
+    /// Every calibration version of the device, oldest first.
+    pub calibrations: Vec<Calibration>,
+}
+
+#[derive(Debug, Default)]
+struct Profiles {
+    /// Bumped whenever entries are forgotten, so a profile loaded meanwhile
+    /// is not kept.
+    generation: u64,
+    entries: HashMap<String, Arc<DeviceProfile>>,
+}
+
+/// Device state kept in memory so a reading only costs the database its
+/// insert. Profiles are loaded on first use and forgotten when
+/// [`listen`] hears they changed, indices are replaced by the retention
+/// task after every rollup.
+#[derive(Debug, Default)]
+pub struct DeviceCache {
+    profiles: Mutex<Profiles>,
+    aqi: RwLock<HashMap<String, Aqi>>,
+}
+
+impl DeviceCache {
+    pub async fn profile<D: DeviceStore>(
+        &self,
+        store: &D,
+        id: &str,
+    ) -> XResult<Arc<DeviceProfile>> {
+        let generation = {
+            let profiles = self.profiles.lock().unwrap();
+
+            if let Some(profile) = profiles.entries.get(id) {
+                return Ok(profile.clone());
+            }
+
+            profiles.generation
+        };
+
+        let profile = Arc::new(DeviceProfile {
+            sensors: store.sensors(id).await?,
+            calibrations: store.calibrations(id).await?,
+        });
+
+        let mut profiles = self.profiles.lock().unwrap();
+
+        if profiles.generation == generation {
+            profiles.entries.insert(id.to_string(), profile.clone());
+        }
+
+        Ok(profile)
+    }
+
+    pub fn forget(&self, id: &str) {
+        let mut profiles = self.profiles.lock().unwrap();
+
+        profiles.generation += 1;
+        profiles.entries.remove(id);
+    }
+
+    pub fn forget_all(&self) {
+        let mut profiles = self.profiles.lock().unwrap();
+
+        profiles.generation += 1;
+        profiles.entries.clear();
+    }
+
+    /// The US EPA index of a device as of the last rollup, `None` without
+    /// recent pollutant data.
+    pub fn aqi(&self, id: &str) -> Option<Aqi> {
+        self.aqi.read().unwrap().get(id).cloned()
+    }
+
+    pub fn set_aqi(&self, aqi: HashMap<String, Aqi>) {
+        *self.aqi.write().unwrap() = aqi;
+    }
+}
+
+/// Forgets the profile of every device the database notifies about, forever.
+/// Changes made while not listening went unnoticed, so everything is
+/// forgotten whenever listening (re)starts.
+pub async fn listen(database_url: String, cache: Arc<DeviceCache>) {
+    loop {
+        if let Err(e) = listen_once(&database_url, &cache).await {
+            tracing::error!("Failed listening for device changes: {:?}", e);
+        }
+
+        cache.forget_all();
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen_once(database_url: &str, cache: &DeviceCache) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) = tokio_postgres::connect(database_url, NoTls).await?;
+    let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+
+    // The connection only makes progress while its messages are polled.
+    let statement = format!("LISTEN {CHANNEL}");
+    let subscribe = client.batch_execute(&statement);
+    tokio::pin!(subscribe);
+    let mut subscribed = false;
+
+    loop {
+        tokio::select! {
+            result = &mut subscribe, if !subscribed => {
+                result?;
+                subscribed = true;
+                cache.forget_all();
+            }
+            message = messages.next() => match message {
+                Some(Ok(AsyncMessage::Notification(n))) => cache.forget(n.payload()),
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::process_esp::tests::MemoryStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn profile_is_loaded_once_until_forgotten() {
+        let cache = DeviceCache::default();
+        let store = MemoryStore {
+            sensors: Some(vec![Metric::Co2]),
+            ..Default::default()
+        };
+
+        let profile = cache.profile(&store, "a").await.unwrap();
+        assert_eq!(profile.sensors, vec![Metric::Co2]);
+
+        let store = MemoryStore::default();
+        let cached = cache.profile(&store, "a").await.unwrap();
+        assert!(Arc::ptr_eq(&profile, &cached));
+
+        cache.forget("a");
+        let reloaded = cache.profile(&store, "a").await.unwrap();
+        assert_eq!(reloaded.sensors, Metric::ALL.to_vec());
+    }
+}
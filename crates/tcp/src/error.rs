@@ -10,13 +10,14 @@ pub enum XError {
     Serde(serde_json::Error),
     #[error("connection broken")]
     ConnectionBroken,
+    #[error("delivery failed: {0}")]
+    Sink(String),
 }
 
-
 pub type XResult<T> = Result<T, XError>;
 
 impl std::convert::From<axum::Error> for XError {
     fn from(value: axum::Error) -> Self {
         Self::Axum(value)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,222 @@
+use std::str::FromStr;
+
+use common::metric::{Metric, METRIC_COUNT};
+
+use crate::models::ESPReply;
+
+/// Device id and the 14 values of firmware from before frames were signed.
+const V1_UNSIGNED_COMPONENTS: usize = 15;
+/// Device id, the 14 values, timestamp, nonce and signature.
+const V1_COMPONENTS: usize = 18;
+/// A v1 frame may also carry when the reading was taken, after the values.
+const V1_TIMED_COMPONENTS: usize = 19;
+/// First component of a frame with named values.
+const V2_PREFIX: &str = "v2";
+/// Version, device id, timestamp, nonce and signature.
+const V2_MIN_COMPONENTS: usize = 5;
+/// Name of the v2 field holding when the reading was taken.
+const TIME_FIELD: &str = "time";
+
+/// A frame split into its parts, before its signature is checked.
+#[derive(Debug, PartialEq)]
+pub struct Frame<'a> {
+    pub device_id: &'a str,
+    pub values: [Option<f32>; METRIC_COUNT],
+    /// Unix time the reading was taken at by the device clock.
+    pub taken_at: Option<&'a str>,
+    pub timestamp: &'a str,
+    pub nonce: &'a str,
+    /// Everything up to the last separator, which the signature covers.
+    pub signed: &'a str,
+    pub signature: &'a str,
+    /// A v1 frame without timestamp, nonce and signature, which leaves them
+    /// empty.
+    pub unsigned: bool,
+}
+
+impl<'a> Frame<'a> {
+    /// Reads either version of the frame:
+    ///
+    /// - v1, `id;v1;...;v14;[taken_at;]timestamp;nonce;hmac`, every value in
+    ///   the fixed order. Firmware from before signing sends only `id;v1;...;v14`.
+    /// - v2, `v2;id;[name=value;...]timestamp;nonce;hmac`, any of the
+    ///   metrics by name and `time` for when the reading was taken.
+    ///
+    /// Garbled values are passed on as NaN for the validator to flag.
+    pub fn parse(msg: &'a str) -> Result<Self, ESPReply> {
+        let data = msg.split(';').collect::<Vec<_>>();
+        let n = data.len();
+
+        if n == V1_UNSIGNED_COMPONENTS && data[0] != V2_PREFIX {
+            return Ok(Self {
+                device_id: data[0],
+                values: v1_values(&data[1..]),
+                taken_at: None,
+                timestamp: "",
+                nonce: "",
+                signed: "",
+                signature: "",
+                unsigned: true,
+            });
+        }
+
+        let (signed, signature) = msg.rsplit_once(';').unwrap_or_default();
+
+        let (device_id, values, taken_at) = match data[0] {
+            V2_PREFIX if n >= V2_MIN_COMPONENTS => {
+                let (values, taken_at) = named_values(&data[2..n - 3])?;
+
+                (data[1], values, taken_at)
+            }
+            V2_PREFIX => return Err(ESPReply::BadFrame(n)),
+            _ if n == V1_COMPONENTS || n == V1_TIMED_COMPONENTS => {
+                let values = v1_values(&data[1..15]);
+
+                let taken_at = match data[15..n - 3] {
+                    [taken_at] if !taken_at.is_empty() => Some(taken_at),
+                    _ => None,
+                };
+
+                (data[0], values, taken_at)
+            }
+            _ => return Err(ESPReply::BadFrame(n)),
+        };
+
+        Ok(Self {
+            device_id,
+            values,
+            taken_at,
+            timestamp: data[n - 3],
+            nonce: data[n - 2],
+            signed,
+            signature,
+            unsigned: false,
+        })
+    }
+}
+
+/// The 14 values of a v1 frame in their fixed order.
+fn v1_values(fields: &[&str]) -> [Option<f32>; METRIC_COUNT] {
+    fields[..METRIC_COUNT]
+        .iter()
+        .map(|x| Some(x.parse::<f32>().unwrap_or(f32::NAN)))
+        .collect::<Vec<_>>()
+        .try_into()
+        .unwrap()
+}
+
+/// The `name=value` fields of a v2 frame. Metrics left out were not measured.
+#[allow(clippy::type_complexity)]
+fn named_values<'a>(
+    fields: &[&'a str],
+) -> Result<([Option<f32>; METRIC_COUNT], Option<&'a str>), ESPReply> {
+    let mut values = [None; METRIC_COUNT];
+    let mut seen = [false; METRIC_COUNT];
+    let mut taken_at = None;
+
+    for field in fields {
+        let bad_field = || ESPReply::BadField(field.to_string());
+
+        let (name, value) = field.split_once('=').ok_or_else(bad_field)?;
+
+        if name == TIME_FIELD {
+            if taken_at.replace(value).is_some() {
+                return Err(bad_field());
+            }
+
+            continue;
+        }
+
+        let metric = Metric::from_str(name).map_err(|_| bad_field())?;
+
+        if std::mem::replace(&mut seen[metric.index()], true) {
+            return Err(bad_field());
+        }
+
+        values[metric.index()] = match value {
+            "" => None,
+            v => Some(v.parse::<f32>().unwrap_or(f32::NAN)),
+        };
+    }
+
+    Ok((values, taken_at.filter(|t| !t.is_empty())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_frame_keeps_fixed_order() {
+        let frame = Frame::parse("dev;1;2;3;4;5;6;7;8;9;10;11;12;13;14;100;1;sig").unwrap();
+
+        assert_eq!(frame.device_id, "dev");
+        assert_eq!(frame.values[0], Some(1.0));
+        assert_eq!(frame.values[13], Some(14.0));
+        assert_eq!(frame.taken_at, None);
+        assert_eq!((frame.timestamp, frame.nonce), ("100", "1"));
+        assert_eq!(frame.signed, "dev;1;2;3;4;5;6;7;8;9;10;11;12;13;14;100;1");
+        assert_eq!(frame.signature, "sig");
+    }
+
+    #[test]
+    fn v1_frame_may_carry_reading_time() {
+        let frame = Frame::parse("dev;1;2;3;4;5;6;7;8;9;10;11;12;13;14;90;100;1;sig").unwrap();
+
+        assert_eq!(frame.taken_at, Some("90"));
+        assert_eq!(frame.timestamp, "100");
+    }
+
+    #[test]
+    fn v1_frame_may_be_unsigned() {
+        let frame = Frame::parse("dev;1;2;3;4;5;6;7;8;9;10;11;12;13;14").unwrap();
+
+        assert!(frame.unsigned);
+        assert_eq!(frame.device_id, "dev");
+        assert_eq!(frame.values[13], Some(14.0));
+        assert_eq!(frame.signature, "");
+    }
+
+    #[test]
+    fn v2_frame_names_its_values() {
+        let frame = Frame::parse("v2;dev;pm_25=8.5;co=1.2;noise=;time=90;100;1;sig").unwrap();
+
+        assert_eq!(frame.device_id, "dev");
+        assert_eq!(frame.values[Metric::Co.index()], Some(1.2));
+        assert_eq!(frame.values[Metric::Pm25.index()], Some(8.5));
+        assert_eq!(frame.values.iter().flatten().count(), 2);
+        assert_eq!(frame.taken_at, Some("90"));
+        assert_eq!(frame.signed, "v2;dev;pm_25=8.5;co=1.2;noise=;time=90;100;1");
+    }
+
+    #[test]
+    fn v2_frame_may_be_empty() {
+        let frame = Frame::parse("v2;dev;100;1;sig").unwrap();
+
+        assert!(frame.values.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn v2_garbled_value_is_nan() {
+        let frame = Frame::parse("v2;dev;co=abc;100;1;sig").unwrap();
+
+        assert!(frame.values[0].unwrap().is_nan());
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let bad_field = |f: &str| Err(ESPReply::BadField(f.to_string()));
+
+        assert_eq!(Frame::parse("dev;1;2;3"), Err(ESPReply::BadFrame(4)));
+        assert_eq!(Frame::parse("v2;dev;1;sig"), Err(ESPReply::BadFrame(4)));
+        assert_eq!(
+            Frame::parse("v2;dev;ozone=1;100;1;sig"),
+            bad_field("ozone=1")
+        );
+        assert_eq!(Frame::parse("v2;dev;co;100;1;sig"), bad_field("co"));
+        assert_eq!(
+            Frame::parse("v2;dev;co=1;co=2;100;1;sig"),
+            bad_field("co=2")
+        );
+    }
+}
@@ -0,0 +1,580 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use chrono::{DateTime, Local};
+use common::alerts::AlertTransition;
+use futures::future;
+use serde::Serialize;
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+use crate::models::{ESPActiveEvent, ESPRecievedEvent, Outgoing, WsMessage};
+
+/// Something that happened to a device, published to whoever watches it.
+#[derive(Clone, Debug)]
+pub enum HubEvent {
+    Data(ESPRecievedEvent),
+    DeviceActive(ESPActiveEvent),
+    Alert(AlertTransition),
+}
+
+impl HubEvent {
+    pub fn device_id(&self) -> &str {
+        match self {
+            Self::Data(e) => &e.id,
+            Self::DeviceActive(e) => &e.id,
+            Self::Alert(t) => &t.device_id,
+        }
+    }
+}
+
+impl From<HubEvent> for WsMessage {
+    fn from(event: HubEvent) -> Self {
+        match event {
+            HubEvent::Data(e) => Self::Data(e),
+            HubEvent::DeviceActive(e) => Self::DeviceActive(e),
+            HubEvent::Alert(t) => Self::Alert(t),
+        }
+    }
+}
+
+/// An event as the hub published it.
+#[derive(Clone, Debug)]
+pub struct Published {
+    /// Place of the event among those published since the server started,
+    /// counting from 1.
+    pub seq: u64,
+    pub at: DateTime<Local>,
+    pub event: HubEvent,
+}
+
+/// Buffered events from some point on.
+#[derive(Debug)]
+pub struct Replay {
+    pub events: Vec<Published>,
+    /// `false` when events after the point were already dropped from the
+    /// buffer.
+    pub complete: bool,
+}
+
+impl From<Published> for Outgoing {
+    fn from(published: Published) -> Self {
+        Self {
+            message: published.event.into(),
+            seq: Some(published.seq),
+        }
+    }
+}
+
+/// Counters of the hub since the server started.
+#[derive(Debug, Default, Serialize)]
+pub struct HubStats {
+    pub published: u64,
+    /// Events subscribers fell too far behind to receive.
+    pub dropped: u64,
+    /// Times a subscriber fell behind.
+    pub lagged: u64,
+    pub subscribers: usize,
+    /// Devices with at least one subscriber of their own.
+    pub topics: usize,
+}
+
+/// The newest events, for sessions to catch up on after reconnecting.
+struct History {
+    next_seq: u64,
+    events: VecDeque<Published>,
+    /// Events published after this are all buffered. The hub starting or,
+    /// later, the newest event dropped from the buffer.
+    covers_after: DateTime<Local>,
+}
+
+struct Inner {
+    capacity: usize,
+    replay: usize,
+    global: broadcast::Sender<Published>,
+    topics: Mutex<HashMap<String, broadcast::Sender<Published>>>,
+    history: Mutex<History>,
+    dropped: AtomicU64,
+    lagged: AtomicU64,
+}
+
+/// Publish/subscribe of device events: a topic per device and a global one
+/// every event goes to. Each subscriber has its own buffer of `capacity`
+/// events, one that falls behind misses the oldest and is told how many.
+/// The last `replay` events are kept for sessions resuming after a
+/// reconnect.
+#[derive(Clone)]
+pub struct Hub {
+    inner: Arc<Inner>,
+}
+
+impl Hub {
+    pub fn new(capacity: usize, replay: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                capacity,
+                replay,
+                global: broadcast::channel(capacity).0,
+                topics: Mutex::new(HashMap::new()),
+                history: Mutex::new(History {
+                    next_seq: 1,
+                    events: VecDeque::new(),
+                    covers_after: Local::now(),
+                }),
+                dropped: AtomicU64::new(0),
+                lagged: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn publish(&self, event: HubEvent) {
+        // Held while sending so every subscriber sees events in order.
+        let mut history = self.inner.history.lock().unwrap();
+
+        let published = Published {
+            seq: history.next_seq,
+            at: Local::now(),
+            event,
+        };
+
+        history.next_seq += 1;
+
+        history.events.push_back(published.clone());
+
+        while history.events.len() > self.inner.replay {
+            if let Some(evicted) = history.events.pop_front() {
+                history.covers_after = evicted.at;
+            }
+        }
+
+        {
+            let mut topics = self.inner.topics.lock().unwrap();
+            let id = published.event.device_id();
+
+            // Sending fails once every subscriber of the device is gone.
+            if let Some(topic) = topics.get(id) {
+                if topic.send(published.clone()).is_err() {
+                    topics.remove(id);
+                }
+            }
+        }
+
+        // No subscribers at all is not an error.
+        self.inner.global.send(published).ok();
+    }
+
+    /// Buffered events published after `seq`, `None` when it is not a
+    /// number handed out since the server started.
+    pub fn replay_after(&self, seq: u64) -> Option<Replay> {
+        let history = self.inner.history.lock().unwrap();
+
+        if seq >= history.next_seq {
+            return None;
+        }
+
+        Some(Replay {
+            events: history
+                .events
+                .iter()
+                .filter(|p| p.seq > seq)
+                .cloned()
+                .collect(),
+            complete: match history.events.front() {
+                Some(first) => first.seq <= seq + 1,
+                None => seq + 1 == history.next_seq,
+            },
+        })
+    }
+
+    /// Buffered events published at or after `since`.
+    pub fn replay_since(&self, since: DateTime<Local>) -> Replay {
+        let history = self.inner.history.lock().unwrap();
+
+        Replay {
+            events: history
+                .events
+                .iter()
+                .filter(|p| p.at >= since)
+                .cloned()
+                .collect(),
+            complete: history.covers_after < since,
+        }
+    }
+
+    /// Events of every device.
+    pub fn subscribe_all(&self) -> Subscription {
+        Subscription {
+            rx: self.inner.global.subscribe(),
+            hub: self.inner.clone(),
+        }
+    }
+
+    /// Events of one device.
+    pub fn subscribe(&self, device_id: &str) -> Subscription {
+        let rx = self
+            .inner
+            .topics
+            .lock()
+            .unwrap()
+            .entry(device_id.to_string())
+            .or_insert_with(|| broadcast::channel(self.inner.capacity).0)
+            .subscribe();
+
+        Subscription {
+            rx,
+            hub: self.inner.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> HubStats {
+        let published = self.inner.history.lock().unwrap().next_seq - 1;
+        let topics = self.inner.topics.lock().unwrap();
+
+        HubStats {
+            published,
+            dropped: self.inner.dropped.load(Ordering::Relaxed),
+            lagged: self.inner.lagged.load(Ordering::Relaxed),
+            subscribers: self.inner.global.receiver_count()
+                + topics.values().map(|t| t.receiver_count()).sum::<usize>(),
+            topics: topics.values().filter(|t| t.receiver_count() > 0).count(),
+        }
+    }
+}
+
+/// What a subscription yields next.
+#[derive(Debug)]
+pub enum Received {
+    Event(Box<Published>),
+    /// The subscriber fell behind and this many events were dropped.
+    Missed(u64),
+}
+
+pub struct Subscription {
+    rx: broadcast::Receiver<Published>,
+    hub: Arc<Inner>,
+}
+
+impl Subscription {
+    /// `None` once the topic is closed.
+    pub async fn recv(&mut self) -> Option<Received> {
+        match self.rx.recv().await {
+            Ok(event) => Some(Received::Event(Box::new(event))),
+            Err(RecvError::Lagged(missed)) => Some(self.missed(missed)),
+            Err(RecvError::Closed) => None,
+        }
+    }
+
+    /// `None` when nothing is waiting or the topic is closed.
+    fn try_recv(&mut self) -> Option<Received> {
+        match self.rx.try_recv() {
+            Ok(event) => Some(Received::Event(Box::new(event))),
+            Err(TryRecvError::Lagged(missed)) => Some(self.missed(missed)),
+            Err(TryRecvError::Empty | TryRecvError::Closed) => None,
+        }
+    }
+
+    fn missed(&self, missed: u64) -> Received {
+        self.hub.dropped.fetch_add(missed, Ordering::Relaxed);
+        self.hub.lagged.fetch_add(1, Ordering::Relaxed);
+
+        Received::Missed(missed)
+    }
+}
+
+/// A subscription of a watcher and the event taken from it but not yet
+/// handed on.
+struct Feed {
+    subscription: Subscription,
+    next: Option<Received>,
+}
+
+impl Feed {
+    fn new(subscription: Subscription) -> Self {
+        Self {
+            subscription,
+            next: None,
+        }
+    }
+
+    /// Where the waiting event goes among those of other feeds, a missed
+    /// notice before any event.
+    fn order(&self) -> Option<u64> {
+        match self.next.as_ref()? {
+            Received::Missed(_) => Some(0),
+            Received::Event(p) => Some(p.seq),
+        }
+    }
+}
+
+/// Events of a set of devices that changes over time, as a session
+/// subscribes and unsubscribes, in the order they were published. A watcher
+/// that is not read falls behind on its topics.
+pub struct Watcher {
+    hub: Hub,
+    /// The global topic, set while watching every device.
+    all: Option<Feed>,
+    /// Devices left out while watching every device.
+    excluded: HashSet<String>,
+    devices: HashMap<String, Feed>,
+}
+
+impl Watcher {
+    pub fn new(hub: Hub) -> Self {
+        Self {
+            hub,
+            all: None,
+            excluded: HashSet::new(),
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn watch_all(&mut self) {
+        self.devices.clear();
+        self.excluded.clear();
+
+        if self.all.is_none() {
+            self.all = Some(Feed::new(self.hub.subscribe_all()));
+        }
+    }
+
+    pub fn watch(&mut self, device_id: &str) {
+        if self.all.is_some() {
+            self.excluded.remove(device_id);
+        } else if !self.devices.contains_key(device_id) {
+            let feed = Feed::new(self.hub.subscribe(device_id));
+            self.devices.insert(device_id.to_string(), feed);
+        }
+    }
+
+    pub fn unwatch(&mut self, device_id: &str) {
+        if self.all.is_some() {
+            self.excluded.insert(device_id.to_string());
+        } else {
+            self.devices.remove(device_id);
+        }
+    }
+
+    pub fn watches(&self, device_id: &str) -> bool {
+        match self.all {
+            Some(_) => !self.excluded.contains(device_id),
+            None => self.devices.contains_key(device_id),
+        }
+    }
+
+    /// The devices watched, `None` when watching every device.
+    pub fn devices(&self) -> Option<Vec<String>> {
+        match self.all {
+            Some(_) => None,
+            None => Some(self.devices.keys().cloned().collect()),
+        }
+    }
+
+    /// Waits while nothing is watched.
+    pub async fn recv(&mut self) -> Received {
+        loop {
+            let received = self.next().await;
+
+            match &received {
+                Received::Event(p) if self.excluded.contains(p.event.device_id()) => continue,
+                _ => return received,
+            }
+        }
+    }
+
+    /// The waiting event with the lowest `seq` among the topics watched.
+    /// Publishing sends an event to its topic before the next is published,
+    /// so once any topic has an event every earlier one is already waiting
+    /// in its own.
+    async fn next(&mut self) -> Received {
+        let mut feeds: Vec<&mut Feed> = self
+            .all
+            .iter_mut()
+            .chain(self.devices.values_mut())
+            .collect();
+
+        if feeds.is_empty() {
+            return future::pending().await;
+        }
+
+        if feeds.iter().all(|f| f.next.is_none()) {
+            let (received, i, _) =
+                future::select_all(feeds.iter_mut().map(|f| Box::pin(f.subscription.recv()))).await;
+
+            // The hub keeps every topic open while it has a subscriber.
+            feeds[i].next = Some(received.expect("Watched topic closed"));
+        }
+
+        for feed in feeds.iter_mut().filter(|f| f.next.is_none()) {
+            feed.next = feed.subscription.try_recv();
+        }
+
+        feeds
+            .into_iter()
+            .filter(|f| f.next.is_some())
+            .min_by_key(|f| f.order())
+            .and_then(|f| f.next.take())
+            .expect("A watched topic has an event waiting")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn active(id: &str) -> HubEvent {
+        HubEvent::DeviceActive(ESPActiveEvent {
+            id: id.to_string(),
+            active: true,
+        })
+    }
+
+    fn next_id(sub: &mut Subscription) -> Option<String> {
+        match sub.rx.try_recv() {
+            Ok(published) => Some(published.event.device_id().to_string()),
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn events_reach_their_topic_and_the_global_one() {
+        let hub = Hub::new(4, 0);
+
+        let mut all = hub.subscribe_all();
+        let mut watching = hub.subscribe("a");
+        let mut other = hub.subscribe("b");
+
+        hub.publish(active("a"));
+
+        assert_eq!(next_id(&mut all).as_deref(), Some("a"));
+        assert_eq!(next_id(&mut watching).as_deref(), Some("a"));
+        assert_eq!(next_id(&mut other), None);
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_is_told_what_it_missed() {
+        let hub = Hub::new(2, 0);
+        let mut sub = hub.subscribe("a");
+
+        for _ in 0..5 {
+            hub.publish(active("a"));
+        }
+
+        assert!(matches!(sub.recv().await, Some(Received::Missed(3))));
+        assert!(matches!(sub.recv().await, Some(Received::Event(_))));
+
+        let stats = hub.stats();
+        assert_eq!((stats.published, stats.dropped, stats.lagged), (5, 3, 1));
+    }
+
+    #[tokio::test]
+    async fn watcher_follows_subscriptions() {
+        let hub = Hub::new(4, 0);
+        let mut watcher = Watcher::new(hub.clone());
+
+        watcher.watch("a");
+        watcher.watch("b");
+        watcher.unwatch("a");
+        tokio::task::yield_now().await;
+
+        for id in ["a", "b", "c"] {
+            hub.publish(active(id));
+        }
+
+        match watcher.recv().await {
+            Received::Event(p) => assert_eq!(p.event.device_id(), "b"),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        assert!(watcher.devices.values().all(|f| f.next.is_none()));
+    }
+
+    #[tokio::test]
+    async fn watcher_keeps_the_published_order_across_devices() {
+        let hub = Hub::new(8, 0);
+        let mut watcher = Watcher::new(hub.clone());
+
+        watcher.watch("a");
+        watcher.watch("b");
+
+        for id in ["a", "b", "b", "a", "b", "a"] {
+            hub.publish(active(id));
+        }
+
+        let mut seqs = Vec::new();
+
+        for _ in 0..6 {
+            match watcher.recv().await {
+                Received::Event(p) => seqs.push(p.seq),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn watching_all_leaves_out_unwatched_devices() {
+        let hub = Hub::new(4, 0);
+        let mut watcher = Watcher::new(hub.clone());
+
+        watcher.watch_all();
+        watcher.unwatch("a");
+        tokio::task::yield_now().await;
+
+        hub.publish(active("a"));
+        hub.publish(active("b"));
+
+        match watcher.recv().await {
+            Received::Event(p) => assert_eq!(p.event.device_id(), "b"),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn recent_events_are_replayed() {
+        let hub = Hub::new(4, 3);
+        let start = Local::now();
+
+        for id in ["a", "b", "c", "d"] {
+            hub.publish(active(id));
+        }
+
+        let seqs = |replay: &Replay| replay.events.iter().map(|p| p.seq).collect::<Vec<_>>();
+
+        let replay = hub.replay_after(2).unwrap();
+        assert_eq!((seqs(&replay), replay.complete), (vec![3, 4], true));
+
+        let replay = hub.replay_after(0).unwrap();
+        assert_eq!((seqs(&replay), replay.complete), (vec![2, 3, 4], false));
+
+        let replay = hub.replay_since(start);
+        assert_eq!((seqs(&replay), replay.complete), (vec![2, 3, 4], false));
+
+        // Nothing from before the hub started is buffered.
+        let fresh = Hub::new(4, 3);
+        assert!(!fresh.replay_since(start).complete);
+        assert!(fresh.replay_since(Local::now()).complete);
+
+        assert!(hub.replay_after(4).unwrap().events.is_empty());
+        assert!(hub.replay_after(5).is_none());
+    }
+
+    #[tokio::test]
+    async fn abandoned_topics_are_removed() {
+        let hub = Hub::new(4, 0);
+
+        drop(hub.subscribe("a"));
+        assert_eq!(hub.inner.topics.lock().unwrap().len(), 1);
+
+        hub.publish(active("a"));
+        assert!(hub.inner.topics.lock().unwrap().is_empty());
+        assert_eq!(hub.stats().topics, 0);
+    }
+}
@@ -1,37 +1,54 @@
-use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
+use alerts::{AlertSink, SmtpSink, WebhookSink, WsSink};
 use axum::{
-    http::Method,
-    routing::{any, get},
+    extract::Request,
+    http::{header, HeaderName, HeaderValue, Method},
+    middleware,
+    routing::{any, delete, get, patch, post},
     Extension, Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
+use cache::DeviceCache;
 use chrono::Local;
-use common::Backend;
-use models::{ESPActiveEvent, ESPRecievedEvent, SessionType};
+use common::{
+    alerts::AlertTransition,
+    auth::Role,
+    config::{Config, TlsSettings},
+    signing::ReplayGuard,
+    Backend,
+};
+use hub::{Hub, HubEvent};
+use models::{ESPActiveEvent, ESPRecievedEvent, WsMessage};
+use process_esp::{ClockMonitor, EspContext};
 use tokio::{
     net::TcpListener,
     sync::mpsc::{channel, Sender},
+    time::timeout,
 };
+use tokio_rustls::TlsAcceptor;
 use tower_http::{
     cors::CorsLayer,
     trace::{self, TraceLayer},
 };
-use tracing::Level;
+use tracing::{Level, Span};
 use tracing_subscriber::layer::SubscriberExt;
+use webhooks::Webhooks;
 
+mod alerts;
+mod auth;
+mod cache;
 mod error;
+mod frame;
+mod hub;
 mod models;
+mod mqtt;
 mod process_esp;
+mod retention;
 mod routes;
 mod session_ws;
-
-const ESP_PORT: u16 = 2442;
-const WS_PORT: u16 = 2443;
+mod tls;
+mod webhooks;
 
 #[tokio::main]
 async fn main() {
@@ -39,56 +56,128 @@ async fn main() {
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    let esp_listener = TcpListener::bind(("0.0.0.0", ESP_PORT)).await.unwrap();
-    tracing::info!("Initialized ESP listener at port {ESP_PORT}");
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("Failed loading config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let esp_listener = TcpListener::bind(("0.0.0.0", config.esp.port))
+        .await
+        .unwrap();
+    tracing::info!("Initialized ESP listener at port {}", config.esp.port);
 
-    let ws_listener = TcpListener::bind(("0.0.0.0", WS_PORT)).await.unwrap();
-    tracing::info!("Initialized WS listener at port {WS_PORT}");
+    let ws_listener = TcpListener::bind(("0.0.0.0", config.ws.port))
+        .await
+        .unwrap();
+    tracing::info!("Initialized WS listener at port {}", config.ws.port);
 
-    let sessions = Arc::new(Mutex::new(HashMap::<
-        u64,
-        (
-            SessionType,
-            (Sender<ESPRecievedEvent>, Sender<ESPActiveEvent>),
-        ),
-    >::new()));
+    let hub = Hub::new(config.ws.event_buffer, config.ws.replay_buffer);
 
     let backend = Backend::new().await;
 
-    let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_origin([
-            "https://aaair.yoon.dev".parse().unwrap(),
-            "http://localhost:3000".parse().unwrap(),
-        ]);
+    let webhooks = Webhooks::new(backend.clone(), config.webhooks.clone());
 
-    let router = Router::new()
-        .route("/ws", any(session_ws::handler))
-        .route("/", get(routes::root))
-        .route(
-            "/devices_last_reading",
-            get(routes::get_devices_last_reading),
-        )
-        .route("/devices", get(routes::get_devices))
-        .route("/devices/:id", get(routes::get_device))
-        .route(
-            "/devices/:id/readings",
-            get(routes::get_device_last_reading),
-        )
-        .layer(Extension(backend.clone()))
-        .layer(Extension(sessions.clone()))
-        .layer(cors)
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+    if let Err(e) = webhooks.reload().await {
+        tracing::error!("Failed loading webhooks: {:?}", e);
+    }
+
+    let esp_tls = config.esp.tls.as_ref().map(|settings| {
+        tracing::info!(
+            "ESP listener uses TLS{}",
+            if settings.client_ca.is_some() {
+                " with client certificates"
+            } else {
+                ""
+            }
         );
 
+        TlsAcceptor::from(tls_config(settings, &[]))
+    });
+
+    let ws_tls = config.ws.tls.as_ref().map(|settings| {
+        tracing::info!("WS listener uses TLS");
+
+        RustlsConfig::from_config(tls_config(settings, &[b"h2", b"http/1.1"]))
+    });
+
+    // ALERTS
+
+    let alert_engine = config.alerts.engine();
+
+    match backend.get_connection().await {
+        Ok(mut conn) => match backend.get_firing_alerts(&mut conn).await {
+            Ok(firing) => alert_engine.restore(firing),
+            Err(e) => tracing::error!("Failed loading firing alerts: {:?}", e),
+        },
+        Err(e) => tracing::error!("Failed to get connection: {:?}", e),
+    }
+
+    let mut sinks: Vec<Box<dyn AlertSink>> = vec![Box::new(WsSink::new(hub.clone()))];
+
+    if let Some(settings) = &config.alerts.webhook {
+        sinks.push(Box::new(WebhookSink::new(settings)));
+    }
+
+    if let Some(settings) = &config.alerts.smtp {
+        sinks.push(Box::new(
+            SmtpSink::new(settings).expect("Invalid SMTP settings"),
+        ));
+    }
+
+    let (alert_tx, alert_rx) = channel::<AlertTransition>(64);
+
+    tokio::spawn(alerts::dispatch(backend.clone(), sinks, alert_rx));
+
     // ESP HANDLING
 
     let (tx, mut rx) = channel::<ESPRecievedEvent>(1);
 
+    // Backend::new loaded the .env file.
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let devices = Arc::new(DeviceCache::default());
+
+    tokio::spawn(cache::listen(database_url, devices.clone()));
+
     let backend0 = backend.clone();
+    let esp_ctx = Arc::new(EspContext {
+        validator: config.validator(),
+        replay: ReplayGuard::new(config.esp.replay_window()),
+        idle_timeout: config.esp.idle_timeout(),
+        max_buffer_age: config.esp.max_buffer_age(),
+        alerts: alert_engine,
+        alert_tx,
+        clocks: ClockMonitor::new(config.esp.max_clock_skew()),
+        unsigned_until: config.esp.unsigned_until,
+        devices: devices.clone(),
+    });
+
+    let router = router(
+        backend.clone(),
+        webhooks.clone(),
+        esp_ctx.clone(),
+        tx.clone(),
+        hub.clone(),
+        &config.ws.cors_origins,
+    );
+
+    // MQTT INGESTION
+
+    if config.mqtt.enabled {
+        if config.mqtt.embedded {
+            mqtt::start_broker(&config.mqtt);
+            tracing::info!("Initialized MQTT broker at port {}", config.mqtt.port);
+        }
+
+        let backend = backend.clone();
+        let esp_ctx = esp_ctx.clone();
+        let mqtt_config = config.mqtt.clone();
+        let tx = tx.clone();
+
+        tokio::spawn(async move { mqtt::run(&backend, &esp_ctx, &mqtt_config, &tx).await });
+    }
 
     tokio::spawn(async move {
         loop {
@@ -105,14 +194,34 @@ async fn main() {
             tracing::info!("ESP socket connected");
 
             let tx = tx.clone();
+            let esp_ctx = esp_ctx.clone();
+            let esp_tls = esp_tls.clone();
 
             tokio::spawn(async move {
-                match process_esp::process(&backend, addr, socket, tx).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        tracing::error!("Failed processing ESP data: {:?}", e);
+                // Client certificates only gate the transport, which device a
+                // frame is from is still decided by its signature.
+                let result = match esp_tls {
+                    Some(acceptor) => {
+                        match timeout(esp_ctx.idle_timeout, acceptor.accept(socket)).await {
+                            Ok(Ok(stream)) => {
+                                process_esp::process(&backend, &esp_ctx, addr, stream, tx).await
+                            }
+                            Ok(Err(e)) => {
+                                tracing::error!("[{}] TLS handshake failed: {:?}", addr, e);
+                                return;
+                            }
+                            Err(_) => {
+                                tracing::error!("[{}] TLS handshake timed out", addr);
+                                return;
+                            }
+                        }
                     }
+                    None => process_esp::process(&backend, &esp_ctx, addr, socket, tx).await,
                 };
+
+                if let Err(e) = result {
+                    tracing::error!("Failed processing ESP data: {:?}", e);
+                }
             });
         }
     });
@@ -120,32 +229,28 @@ async fn main() {
     // SESSION HANDLING
     // SENDING ESP DATA TO ALL OF THE SESSIONS
 
-    let sessions0 = sessions.clone();
+    let hub0 = hub.clone();
+    let webhooks0 = webhooks.clone();
 
     tokio::spawn(async move {
-        loop {
-            while let Some(event) = &rx.recv().await {
-                for (session_type, (tx, _)) in sessions0.lock().unwrap().values() {
-                    tracing::info!("Sending event to connected sessions");
-
-                    match session_type {
-                        SessionType::Child(id) => {
-                            if id == &event.id {
-                                tx.try_send(event.clone()).ok();
-                            }
-                        }
-                        SessionType::Main => {
-                            tx.try_send(event.clone()).ok();
-                        }
-                    };
-                }
-            }
+        while let Some(event) = rx.recv().await {
+            webhooks0.publish(&event.id, &WsMessage::Data(event.clone()));
+            hub0.publish(HubEvent::Data(event));
         }
     });
 
-    let sessions0 = sessions.clone();
+    tokio::spawn(retention::run(
+        backend.clone(),
+        config.retention.clone(),
+        devices,
+    ));
+
+    let devices_config = config.devices.clone();
 
     tokio::spawn(async move {
+        let online_threshold = chrono::Duration::from_std(devices_config.online_threshold())
+            .expect("Online threshold out of range");
+
         loop {
             let backend = backend.clone();
 
@@ -170,7 +275,7 @@ async fn main() {
             for (d_id, last_updated) in records {
                 let diff = Local::now().signed_duration_since(last_updated);
 
-                let active = diff < chrono::Duration::seconds(10);
+                let active = diff < online_threshold;
 
                 let current_status = match backend.get_device_active_status(&mut conn, &d_id).await
                 {
@@ -185,7 +290,11 @@ async fn main() {
                     continue;
                 }
 
-                if let Err(_) = backend.change_device_active(&mut conn, &d_id, active).await {
+                if backend
+                    .change_device_active(&mut conn, &d_id, active)
+                    .await
+                    .is_err()
+                {
                     tracing::error!("Failed to change the active status of device: {d_id}");
                 };
 
@@ -194,43 +303,368 @@ async fn main() {
                     if active { "Online" } else { "Offline" }
                 );
 
-                for (session_type, (_, tx)) in sessions0.lock().unwrap().values() {
-                    handle_active(session_type, &d_id, tx, active);
-                }
+                let event = ESPActiveEvent {
+                    id: d_id.clone(),
+                    active,
+                };
+
+                webhooks.publish(&d_id, &WsMessage::DeviceActive(event.clone()));
+                hub.publish(HubEvent::DeviceActive(event));
             }
 
             drop(conn);
 
-            tokio::time::sleep(Duration::from_secs(5)).await;
+            tokio::time::sleep(devices_config.monitor_interval()).await;
         }
     });
 
-    axum::serve(
-        ws_listener,
-        router.into_make_service_with_connect_info::<SocketAddr>(),
+    let app = router.into_make_service_with_connect_info::<SocketAddr>();
+
+    match ws_tls {
+        Some(config) => axum_server::from_tcp_rustls(ws_listener.into_std().unwrap(), config)
+            .serve(app)
+            .await
+            .unwrap(),
+        None => axum::serve(ws_listener, app).await.unwrap(),
+    }
+}
+
+/// The HTTP and WebSocket API.
+fn router(
+    backend: Backend,
+    webhooks: Arc<Webhooks>,
+    esp_ctx: Arc<EspContext>,
+    tx: Sender<ESPRecievedEvent>,
+    hub: Hub,
+    cors_origins: &[String],
+) -> Router {
+    let cors = CorsLayer::new()
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PATCH,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            HeaderName::from_static("x-api-key"),
+        ])
+        .allow_origin(
+            cors_origins
+                .iter()
+                // Checked when the config was loaded.
+                .filter_map(|o| o.parse::<HeaderValue>().ok())
+                .collect::<Vec<_>>(),
+        );
+
+    let viewer = Router::new()
+        .route("/hub", get(routes::get_hub_stats))
+        .route_layer(middleware::from_fn_with_state(Role::Viewer, auth::require));
+
+    let operator = Router::new()
+        .route(
+            "/webhooks",
+            get(routes::get_webhooks).post(routes::create_webhook),
+        )
+        .route("/webhooks/:id", delete(routes::delete_webhook))
+        .route(
+            "/webhooks/:id/dead_letters",
+            get(routes::get_webhook_dead_letters),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            Role::Operator,
+            auth::require,
+        ));
+
+    let admin = Router::new()
+        .route("/devices", post(routes::create_device))
+        .route(
+            "/devices/:id",
+            patch(routes::update_device).delete(routes::delete_device),
+        )
+        .route_layer(middleware::from_fn_with_state(Role::Admin, auth::require));
+
+    // Routes reading devices check the access of the caller themselves,
+    // devices flagged public are readable without a key.
+    Router::new()
+        .route("/ws", any(session_ws::handler))
+        .route("/", get(routes::root))
+        .route(
+            "/devices_last_reading",
+            get(routes::get_devices_last_reading),
+        )
+        .route("/devices_aqi", get(routes::get_devices_aqi))
+        .route("/devices", get(routes::get_devices))
+        .route("/devices/:id", get(routes::get_device))
+        .route(
+            "/devices/:id/readings",
+            get(routes::get_device_last_reading).post(routes::post_device_readings),
+        )
+        .route("/devices/:id/aqi", get(routes::get_device_aqi))
+        .route("/export", get(routes::export_readings))
+        .merge(viewer)
+        .merge(operator)
+        .merge(admin)
+        .layer(middleware::from_fn(auth::authenticate))
+        .layer(Extension(backend))
+        .layer(Extension(webhooks))
+        .layer(Extension(esp_ctx))
+        .layer(Extension(tx))
+        .layer(Extension(hub))
+        .layer(cors)
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(make_span)
+                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+        )
+}
+
+/// Like `DefaultMakeSpan`, without the API key a WebSocket sends in its
+/// query.
+fn make_span(request: &Request) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %auth::redacted_uri(request.uri()),
+        version = ?request.version(),
     )
-    .await
-    .unwrap();
 }
 
-fn send_active_event(tx: &Sender<ESPActiveEvent>, id: &str, active: bool) {
-    if let Err(why) = tx.try_send(ESPActiveEvent {
-        id: id.to_string(),
-        active,
-    }) {
-        tracing::error!("Error sending active event: {}", why);
+/// The files were read when the config was loaded, what is left to fail here
+/// is a key not matching its certificate.
+fn tls_config(settings: &TlsSettings, alpn: &[&[u8]]) -> Arc<rustls::ServerConfig> {
+    match tls::server_config(settings, alpn) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("Invalid TLS settings: {}", e);
+            std::process::exit(1);
+        }
     }
 }
 
-fn handle_active(
-    session_type: &SessionType,
-    d_id: &str,
-    tx: &Sender<ESPActiveEvent>,
-    active: bool,
-) {
-    match session_type {
-        SessionType::Child(id) if id == d_id => send_active_event(tx, id, active),
-        SessionType::Main => send_active_event(tx, d_id, active),
-        _ => (),
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use common::{
+        alerts::AlertEngine, backend::device::DeviceFilter, config::WebhooksConfig,
+        validation::Validator,
+    };
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::*;
+    use crate::process_esp::tests::context;
+
+    /// Serves the router on a free local port.
+    async fn serve(backend: &Backend) -> SocketAddr {
+        let (ctx, _alerts) = context(Validator::default(), AlertEngine::default());
+        let app = router(
+            backend.clone(),
+            Webhooks::new(backend.clone(), WebhooksConfig::default()),
+            Arc::new(ctx),
+            channel(1).0,
+            Hub::new(4, 0),
+            &[],
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database at DATABASE_URL"]
+    async fn anonymous_callers_do_not_see_private_devices() {
+        let backend = Backend::new().await;
+        let mut conn = backend.get_connection().await.unwrap();
+
+        let (id, _) = backend
+            .create_device(
+                &mut conn,
+                "private".to_string(),
+                "test".to_string(),
+                0.0,
+                0.0,
+                false,
+                &[],
+            )
+            .await
+            .unwrap();
+
+        drop(conn);
+
+        let addr = serve(&backend).await;
+        let client = reqwest::Client::new();
+        let status = |path: String| {
+            let request = client.get(format!("http://{addr}{path}"));
+            async move { request.send().await.unwrap().status() }
+        };
+
+        assert_eq!(
+            status(format!("/devices/{id}")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(format!("/export?devices={id}")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status("/webhooks".to_string()).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+
+        let identify = json!({ "type": "identify", "data": { "type": "child", "id": id } });
+        ws.send(Message::Text(identify.to_string())).await.unwrap();
+
+        let reply = match ws.next().await {
+            Some(Ok(Message::Text(text))) => {
+                serde_json::from_str::<serde_json::Value>(&text).unwrap()
+            }
+            other => panic!("unexpected {other:?}"),
+        };
+
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["data"]["message"], "Device not found");
+        assert!(!matches!(ws.next().await, Some(Ok(Message::Text(_)))));
+
+        let mut conn = backend.get_connection().await.unwrap();
+        backend.delete_device(&mut conn, &id).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database at DATABASE_URL"]
+    async fn only_admins_manage_devices_and_deleted_devices_are_gone() {
+        let backend = Backend::new().await;
+        let mut conn = backend.get_connection().await.unwrap();
+
+        let (viewer, viewer_key) = backend
+            .create_api_key(&mut conn, "test viewer".to_string(), Role::Viewer)
+            .await
+            .unwrap();
+        let (admin, admin_key) = backend
+            .create_api_key(&mut conn, "test admin".to_string(), Role::Admin)
+            .await
+            .unwrap();
+
+        let addr = serve(&backend).await;
+        let client = reqwest::Client::new();
+        let send = |method: reqwest::Method, path: &str, key: Option<&str>, body| {
+            let mut request = client
+                .request(method, format!("http://{addr}{path}"))
+                .json(&body);
+            if let Some(key) = key {
+                request = request.bearer_auth(key);
+            }
+            async move {
+                let response = request.send().await.unwrap();
+                let status = response.status();
+                let body = response.json::<serde_json::Value>().await.unwrap();
+                (status, body)
+            }
+        };
+
+        let device = json!({ "name": "managed", "box": "test", "lat": 37.5, "long": 127.0 });
+
+        for (key, status) in [
+            (None, StatusCode::UNAUTHORIZED),
+            (Some(viewer_key.as_str()), StatusCode::FORBIDDEN),
+        ] {
+            let (got, _) = send(reqwest::Method::POST, "/devices", key, device.clone()).await;
+            assert_eq!(got, status);
+        }
+
+        let (status, body) = send(
+            reqwest::Method::POST,
+            "/devices",
+            Some(&admin_key),
+            device.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(body["data"]["secret"].is_string());
+        let id = body["data"]["id"].as_str().unwrap().to_string();
+        let path = format!("/devices/{id}");
+
+        let rename = json!({ "name": "renamed" });
+        for method in [reqwest::Method::PATCH, reqwest::Method::DELETE] {
+            let (status, _) = send(method, &path, Some(&viewer_key), rename.clone()).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+
+        let (status, body) = send(
+            reqwest::Method::PATCH,
+            &path,
+            Some(&admin_key),
+            json!({ "lat": 95.0 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "lat must be between -90 and 90");
+
+        let (status, body) = send(
+            reqwest::Method::PATCH,
+            &path,
+            Some(&admin_key),
+            rename.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["name"], "renamed");
+
+        let (status, _) = send(
+            reqwest::Method::PATCH,
+            "/devices/missing",
+            Some(&admin_key),
+            rename.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = send(reqwest::Method::DELETE, &path, Some(&admin_key), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+
+        for method in [reqwest::Method::PATCH, reqwest::Method::DELETE] {
+            let (status, body) = send(method, &path, Some(&admin_key), rename.clone()).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(body["message"], "Device not found");
+        }
+
+        let listed = backend
+            .list_devices(&mut conn, None, false, 10_000)
+            .await
+            .unwrap();
+        assert!(listed.data.iter().all(|d| d.id != id));
+
+        let filter = DeviceFilter {
+            ids: vec![id.clone()],
+            ..Default::default()
+        };
+        let found = backend.find_devices(&mut conn, &filter, false).await;
+        assert!(found.unwrap().is_empty());
+        assert!(backend
+            .get_device_secret(&mut conn, &id)
+            .await
+            .unwrap()
+            .is_none());
+
+        backend.revoke_api_key(&mut conn, &viewer.id).await.unwrap();
+        backend.revoke_api_key(&mut conn, &admin.id).await.unwrap();
     }
 }
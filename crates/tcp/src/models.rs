@@ -1,9 +1,19 @@
+use chrono::{DateTime, Local};
+use common::{
+    alerts::AlertTransition, aqi::Aqi, device::DeviceFilter, metric::Metric,
+    records::DevicesReading, signing::SignatureError,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ESPRecievedEvent {
     pub id: String,
     pub data: PmValues,
+    /// When the reading was taken.
+    pub time: DateTime<Local>,
+    /// US EPA index over the recent averages of the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aqi: Option<Aqi>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -12,26 +22,64 @@ pub struct ESPActiveEvent {
     pub active: bool,
 }
 
+/// Metrics without a value, as those the device has no sensor for, are left
+/// out when serialized.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PmValues {
-    pub co: f32,
-    pub co2: f32,
-    pub temperature: f32,
-    pub humidity: f32,
-    pub noise: f32,
-    pub pm_10: f32,
-    pub pm_25: f32,
-    pub pm_100: f32,
-    pub pm_particles_03: f32,
-    pub pm_particles_05: f32,
-    pub pm_particles_10: f32,
-    pub pm_particles_25: f32,
-    pub pm_particles_50: f32,
-    pub pm_particles_100: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub co: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub co2: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noise: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_10: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_25: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_100: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_particles_03: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_particles_05: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_particles_10: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_particles_25: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_particles_50: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_particles_100: Option<f32>,
 }
 
-impl From<[f32; 14]> for PmValues {
-    fn from(value: [f32; 14]) -> Self {
+impl PmValues {
+    /// The values in frame order.
+    pub fn values(&self) -> [Option<f32>; 14] {
+        [
+            self.co,
+            self.co2,
+            self.temperature,
+            self.humidity,
+            self.noise,
+            self.pm_10,
+            self.pm_25,
+            self.pm_100,
+            self.pm_particles_03,
+            self.pm_particles_05,
+            self.pm_particles_10,
+            self.pm_particles_25,
+            self.pm_particles_50,
+            self.pm_particles_100,
+        ]
+    }
+}
+
+impl From<[Option<f32>; 14]> for PmValues {
+    fn from(value: [Option<f32>; 14]) -> Self {
         Self {
             co: value[0],
             co2: value[1],
@@ -51,15 +99,86 @@ impl From<[f32; 14]> for PmValues {
     }
 }
 
+/// One reading posted over HTTP, alone or in a batch. Devices which buffer
+/// readings while offline send when each was taken.
+#[derive(Debug, Deserialize)]
+pub struct PostedReading {
+    #[serde(flatten)]
+    pub values: PmValues,
+    pub time: Option<DateTime<Local>>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type", content = "data", rename_all = "snake_case")]
 pub enum WsMessage {
     Identify(SessionType),
+    /// Start watching more devices, at any point of the session.
+    Subscribe(DeviceFilter),
+    Unsubscribe(DeviceFilter),
+    Subscribed(SubscriptionAck),
+    Unsubscribed(SubscriptionAck),
+    /// The last records of the devices just watched.
+    Snapshot(Vec<DevicesReading>),
+    /// Replay what the watched devices sent since a point the client got
+    /// to before reconnecting.
+    Resume {
+        resume_from: ResumeFrom,
+    },
+    /// Every event is replayed, `complete` is `false` when some since the
+    /// point could not be recovered.
+    Resumed {
+        replayed: usize,
+        complete: bool,
+    },
     Data(ESPRecievedEvent),
     DeviceActive(ESPActiveEvent),
+    /// An alert rule started or stopped firing for a device.
+    Alert(AlertTransition),
+    /// The session fell behind and `missed` events were dropped.
+    Lagged {
+        missed: u64,
+    },
+    /// A message of the client could not be handled, the session goes on.
+    Error {
+        message: String,
+    },
     KeepAlive,
 }
 
+/// Where to replay from, the `seq` of the last event received or a time.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ResumeFrom {
+    Seq(u64),
+    Time(DateTime<Local>),
+}
+
+/// A message to a session. Events of the hub carry their `seq` to resume
+/// from.
+#[derive(Debug, Serialize)]
+pub struct Outgoing {
+    #[serde(flatten)]
+    pub message: WsMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl From<WsMessage> for Outgoing {
+    fn from(message: WsMessage) -> Self {
+        Self { message, seq: None }
+    }
+}
+
+/// Reply to a subscribe or unsubscribe message.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SubscriptionAck {
+    /// Devices the filter matched.
+    pub ids: Vec<String>,
+    /// Requested ids that are not devices.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unknown: Vec<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case", tag = "type", content = "id")]
 pub enum SessionType {
@@ -67,11 +186,42 @@ pub enum SessionType {
     Child(String),
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
-pub enum LastReading {
-    Last,
-    #[serde(rename = "24H")]
-    Hours24,
-    #[serde(rename = "7D")]
-    Days7,
+/// Status line written back to the ESP after every frame.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ESPReply {
+    Ok,
+    /// The signature, timestamp or nonce of the frame was not accepted. An
+    /// unknown device id is answered as a bad signature, there is no
+    /// `ERR UNKNOWN_DEVICE` any more, so replies do not reveal which ids
+    /// exist.
+    Unauthorized(SignatureError),
+    /// Wrong number of `;` separated components, carries the count found.
+    BadFrame(usize),
+    /// A named field of a v2 frame is malformed, unknown or repeated.
+    BadField(String),
+    /// A value broke a metric rule whose policy is to reject the frame.
+    BadValue(Metric),
+    /// The time the reading was taken at is unreadable, after the frame was
+    /// signed or older than `esp.max_buffer_age_secs`.
+    BadTime,
+    FrameTooLong,
+    /// The frame was valid but could not be stored, the device should retry.
+    Db,
+}
+
+impl std::fmt::Display for ESPReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ok => write!(f, "OK"),
+            Self::Unauthorized(SignatureError::BadSignature) => write!(f, "ERR BAD_SIGNATURE"),
+            Self::Unauthorized(SignatureError::Stale) => write!(f, "ERR STALE"),
+            Self::Unauthorized(SignatureError::Replayed) => write!(f, "ERR REPLAY"),
+            Self::BadFrame(n) => write!(f, "ERR BAD_FRAME {n}"),
+            Self::BadField(field) => write!(f, "ERR BAD_FIELD {field}"),
+            Self::BadValue(metric) => write!(f, "ERR BAD_VALUE {metric}"),
+            Self::BadTime => write!(f, "ERR BAD_TIME"),
+            Self::FrameTooLong => write!(f, "ERR FRAME_TOO_LONG"),
+            Self::Db => write!(f, "ERR DB"),
+        }
+    }
 }
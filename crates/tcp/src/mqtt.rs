@@ -0,0 +1,384 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use chrono::Local;
+use common::{config::MqttConfig, metric::METRIC_COUNT};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use rumqttd::{Broker, ConnectionSettings, RouterConfig, ServerSettings};
+use serde::Deserialize;
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    models::{ESPRecievedEvent, PmValues},
+    process_esp::{self, DeviceStore, EspContext},
+};
+
+/// Topics devices publish their readings to, `devices/{id}/readings`.
+const READINGS_TOPIC: &str = "devices/+/readings";
+/// Wait before polling again after the broker connection failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Starts a broker in process on `config.port`, taking only the accounts of
+/// `config.users`. It runs on its own threads for as long as the server does.
+pub fn start_broker(config: &MqttConfig) {
+    let server = ServerSettings {
+        name: "v4".to_string(),
+        listen: SocketAddr::from(([0, 0, 0, 0], config.port)),
+        tls: None,
+        next_connection_delay_ms: 1,
+        connections: ConnectionSettings {
+            connection_timeout_ms: 60000,
+            max_payload_size: 20480,
+            max_inflight_count: 100,
+            // Config validation makes sure there are accounts.
+            auth: Some(config.users.clone()),
+            external_auth: None,
+            dynamic_filters: true,
+        },
+    };
+
+    let mut broker = Broker::new(rumqttd::Config {
+        router: RouterConfig {
+            max_connections: 10000,
+            max_outgoing_packet_count: 200,
+            max_segment_size: 1024 * 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        },
+        v4: Some(HashMap::from([("1".to_string(), server)])),
+        ..Default::default()
+    });
+
+    std::thread::spawn(move || {
+        if let Err(e) = broker.start() {
+            tracing::error!("MQTT broker stopped: {:?}", e);
+        }
+    });
+}
+
+/// Subscribes to the readings topics and ingests every reading published to
+/// them, reconnecting whenever the broker goes away. Never returns.
+pub async fn run<D: DeviceStore>(
+    store: &D,
+    ctx: &EspContext,
+    config: &MqttConfig,
+    tx: &Sender<ESPRecievedEvent>,
+) {
+    let host = match config.embedded {
+        true => "127.0.0.1",
+        false => &config.host,
+    };
+
+    let mut options = MqttOptions::new(&config.client_id, host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker at {}:{}", host, config.port);
+
+                // Subscriptions do not survive a clean session, so they are
+                // renewed on every connection.
+                if let Err(e) = client.try_subscribe(READINGS_TOPIC, QoS::AtLeastOnce) {
+                    tracing::error!("Failed subscribing to {}: {:?}", READINGS_TOPIC, e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                handle_publish(store, ctx, &publish.topic, &publish.payload, tx).await;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::error!("MQTT connection error: {:?}", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// A reading signed like an HTTP request, over `{id};{timestamp};{nonce};{payload}`
+/// with the secret of the device of the topic, so broker accounts cannot
+/// publish for other devices.
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    timestamp: i64,
+    nonce: String,
+    signature: String,
+    /// JSON or CSV, see [`parse_payload`].
+    payload: String,
+}
+
+async fn handle_publish<D: DeviceStore>(
+    store: &D,
+    ctx: &EspContext,
+    topic: &str,
+    payload: &[u8],
+    tx: &Sender<ESPRecievedEvent>,
+) {
+    let Some(device_id) = device_id(topic) else {
+        tracing::error!("[mqtt] Unexpected topic: {}", topic);
+        return;
+    };
+
+    let envelope = match serde_json::from_slice::<Envelope>(payload) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("[mqtt] Unsigned reading from {}: {}", device_id, e);
+            return;
+        }
+    };
+
+    let timestamp = envelope.timestamp.to_string();
+
+    if process_esp::authenticate(
+        store,
+        ctx,
+        "mqtt",
+        device_id,
+        &format!(
+            "{device_id};{timestamp};{};{}",
+            envelope.nonce, envelope.payload
+        ),
+        &envelope.signature,
+        &timestamp,
+        &envelope.nonce,
+    )
+    .await
+    .is_err()
+    {
+        return;
+    }
+
+    let raw = match parse_payload(envelope.payload.as_bytes()) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("[mqtt] Unreadable reading from {}: {}", device_id, e);
+            return;
+        }
+    };
+
+    process_esp::ingest(store, ctx, "mqtt", device_id, Local::now(), raw, tx).await;
+}
+
+/// The device of a `devices/{id}/readings` topic.
+fn device_id(topic: &str) -> Option<&str> {
+    match topic.split('/').collect::<Vec<_>>()[..] {
+        ["devices", id, "readings"] if !id.is_empty() => Some(id),
+        _ => None,
+    }
+}
+
+/// Reads a JSON object with the fields of [`PmValues`], missing ones being
+/// absent, or the 14 values comma separated in frame order, empty ones being
+/// absent. Garbled CSV values are passed on as NaN for the validator to flag,
+/// like garbled TCP frame values.
+fn parse_payload(payload: &[u8]) -> Result<[Option<f32>; METRIC_COUNT], String> {
+    let payload = std::str::from_utf8(payload)
+        .map_err(|e| e.to_string())?
+        .trim();
+
+    if payload.starts_with('{') {
+        let values = serde_json::from_str::<PmValues>(payload).map_err(|e| e.to_string())?;
+
+        return Ok(values.values());
+    }
+
+    let values = payload
+        .split(',')
+        .map(|v| match v.trim() {
+            "" => None,
+            v => Some(v.parse::<f32>().unwrap_or(f32::NAN)),
+        })
+        .collect::<Vec<_>>();
+
+    let count = values.len();
+
+    values
+        .try_into()
+        .map_err(|_| format!("expected {METRIC_COUNT} values, found {count}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use common::{alerts::AlertEngine, signing, validation::Validator};
+    use tokio::{sync::mpsc::channel, time::timeout};
+
+    use crate::process_esp::tests::{context, MemoryStore, DEVICE, SECRET};
+
+    use super::*;
+
+    /// `payload` in an envelope for `id` signed with `SECRET`.
+    fn signed(id: &str, payload: &str) -> String {
+        let timestamp = Utc::now().timestamp();
+        let nonce = rand::random::<u64>().to_string();
+
+        serde_json::json!({
+            "timestamp": timestamp,
+            "nonce": nonce,
+            "signature": signing::sign(SECRET, &format!("{id};{timestamp};{nonce};{payload}")),
+            "payload": payload,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn topic_names_the_device() {
+        assert_eq!(device_id("devices/abc/readings"), Some("abc"));
+        assert_eq!(device_id("devices//readings"), None);
+        assert_eq!(device_id("devices/abc/status"), None);
+        assert_eq!(device_id("devices/abc/readings/x"), None);
+    }
+
+    #[test]
+    fn json_payload_may_skip_metrics() {
+        let values = parse_payload(br#"{"co": 1.5, "pm_25": 12}"#).unwrap();
+
+        assert_eq!(values[0], Some(1.5));
+        assert_eq!(values[6], Some(12.0));
+        assert_eq!(values.iter().flatten().count(), 2);
+    }
+
+    #[test]
+    fn csv_payload_keeps_frame_order() {
+        let values = parse_payload(b"1,2,,4,5,6,7,8,9,10,11,12,13,abc\n").unwrap();
+
+        assert_eq!(values[0], Some(1.0));
+        assert_eq!(values[2], None);
+        assert!(values[13].unwrap().is_nan());
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        assert!(parse_payload(b"1,2,3").is_err());
+        assert!(parse_payload(b"{\"co\": \"high\"}").is_err());
+        assert!(parse_payload(&[0xff, 0xfe]).is_err());
+    }
+
+    #[tokio::test]
+    async fn readings_flow_through_embedded_broker() {
+        // Ask the OS for a free port, the broker needs a fixed one.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let config = MqttConfig {
+            enabled: true,
+            embedded: true,
+            port,
+            username: Some("server".to_string()),
+            password: Some("secret".to_string()),
+            users: HashMap::from([
+                ("server".to_string(), "secret".to_string()),
+                ("device".to_string(), "device-secret".to_string()),
+            ]),
+            ..Default::default()
+        };
+
+        start_broker(&config);
+
+        while tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_err()
+        {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let store = MemoryStore::default();
+        let (ctx, _) = context(Validator::default(), AlertEngine::default());
+        let (tx, mut rx) = channel::<ESPRecievedEvent>(16);
+
+        let publisher = async {
+            let mut options = MqttOptions::new("test-device", "127.0.0.1", port);
+            options.set_keep_alive(Duration::from_secs(5));
+            options.set_credentials("device", "device-secret");
+
+            let (client, mut eventloop) = AsyncClient::new(options, 16);
+            tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+
+            let topic = format!("devices/{DEVICE}/readings");
+
+            // The server may not have subscribed yet, so publish until the
+            // first reading comes through.
+            let first = loop {
+                client
+                    .publish(
+                        &topic,
+                        QoS::AtLeastOnce,
+                        false,
+                        signed(DEVICE, r#"{"co": 1.5}"#),
+                    )
+                    .await
+                    .unwrap();
+
+                if let Ok(Some(event)) = timeout(Duration::from_millis(200), rx.recv()).await {
+                    break event;
+                }
+            };
+
+            // Unsigned, and signed for another device than the topic names.
+            client
+                .publish(&topic, QoS::AtLeastOnce, false, r#"{"co": 2.5}"#)
+                .await
+                .unwrap();
+            client
+                .publish(
+                    "devices/nobody/readings",
+                    QoS::AtLeastOnce,
+                    false,
+                    signed(DEVICE, r#"{"co": 3.5}"#),
+                )
+                .await
+                .unwrap();
+            client
+                .publish(
+                    &topic,
+                    QoS::AtLeastOnce,
+                    false,
+                    signed(DEVICE, "9,,,,,,,,,,,,,14"),
+                )
+                .await
+                .unwrap();
+
+            let second = loop {
+                let event = timeout(Duration::from_secs(5), rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                if event.data.co != Some(1.5) {
+                    break event;
+                }
+            };
+
+            (first, second)
+        };
+
+        let (first, second) = tokio::select! {
+            _ = run(&store, &ctx, &config, &tx) => unreachable!(),
+            events = timeout(Duration::from_secs(20), publisher) => events.unwrap(),
+        };
+
+        assert_eq!(first.id, DEVICE);
+        assert_eq!(second.data.co, Some(9.0));
+        assert!(store
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(_, _, record, _)| record.values[0] != Some(2.5)));
+        assert_eq!(second.data.pm_particles_100, Some(14.0));
+        assert!(store
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(id, _, _, _)| id == DEVICE));
+    }
+}
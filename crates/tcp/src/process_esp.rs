@@ -1,25 +1,258 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use common::Backend;
-use tokio::{io::AsyncReadExt, net::TcpStream, sync::mpsc::Sender, time::timeout};
+use chrono::{DateTime, Local, SubsecRound, Utc};
+use common::{
+    alerts::{AlertEngine, AlertTransition},
+    calibration::{calibrate, Calibration},
+    metric::{Metric, METRIC_COUNT},
+    signing::{self, ReplayGuard, SignatureError},
+    validation::{Validated, Validator},
+    Backend,
+};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc::{error::TrySendError, Sender},
+    time::timeout,
+};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
 
 use crate::{
+    cache::DeviceCache,
     error::{XError, XResult},
-    models::{ESPRecievedEvent, PmValues},
+    frame::Frame,
+    models::{ESPRecievedEvent, ESPReply, PmValues},
 };
 
-pub async fn process(
-    backend: &Backend,
+/// Longest frame accepted, anything longer closes the connection.
+const MAX_FRAME_LENGTH: usize = 1024;
+
+/// Settings and state shared by every ESP connection.
+pub struct EspContext {
+    pub validator: Validator,
+    pub replay: ReplayGuard,
+    /// How long a connection may stay silent before it is closed.
+    pub idle_timeout: Duration,
+    /// How old a buffered reading may be.
+    pub max_buffer_age: Duration,
+    pub alerts: AlertEngine,
+    /// Where alert transitions go to be stored and delivered.
+    pub alert_tx: Sender<AlertTransition>,
+    pub clocks: ClockMonitor,
+    /// Until when devices never issued a secret may send unsigned v1 frames.
+    pub unsigned_until: Option<DateTime<Utc>>,
+    pub devices: Arc<DeviceCache>,
+}
+
+/// The clock of a device as of a signed timestamp, used to move the times
+/// readings are stamped with onto the server clock.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceClock {
+    signed_at: DateTime<Utc>,
+    received_at: DateTime<Local>,
+}
+
+impl DeviceClock {
+    pub fn new(signed_at: DateTime<Utc>, received_at: DateTime<Local>) -> Self {
+        Self {
+            signed_at,
+            received_at,
+        }
+    }
+
+    /// Seconds the device clock is behind the server clock, negative when
+    /// it is ahead.
+    pub fn skew(&self) -> i64 {
+        (self.received_at.to_utc() - self.signed_at).num_seconds()
+    }
+
+    /// Server time of a reading the device took at `taken_at` by its own
+    /// clock, `None` when that is after the device signed it.
+    pub fn server_time(&self, taken_at: DateTime<Utc>) -> Option<DateTime<Local>> {
+        let age = self.signed_at - taken_at;
+
+        // Signed timestamps only have whole seconds.
+        (age > -chrono::Duration::seconds(1))
+            .then(|| self.received_at - age.max(chrono::Duration::zero()))
+    }
+}
+
+/// Warns once when the clock of a device drifts further than `max_skew`
+/// from the server clock and once it is back.
+#[derive(Debug)]
+pub struct ClockMonitor {
+    max_skew: i64,
+    skewed: Mutex<HashSet<String>>,
+}
+
+impl ClockMonitor {
+    pub fn new(max_skew: Duration) -> Self {
+        Self {
+            max_skew: max_skew.as_secs() as i64,
+            skewed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether the clock of the device is off by more than allowed.
+    pub fn observe(&self, device_id: &str, clock: &DeviceClock) -> bool {
+        let skew = clock.skew();
+        let off = skew.abs() > self.max_skew;
+        let mut skewed = self.skewed.lock().unwrap();
+
+        if off && skewed.insert(device_id.to_string()) {
+            tracing::warn!(
+                "Clock of {} is {}s {}",
+                device_id,
+                skew.abs(),
+                if skew > 0 { "behind" } else { "ahead" }
+            );
+        } else if !off && skewed.remove(device_id) {
+            tracing::info!("Clock of {} is back in sync", device_id);
+        }
+
+        off
+    }
+}
+
+/// The storage side of ingestion, split out so the socket handling can be
+/// exercised without a database.
+pub trait DeviceStore {
+    /// The signing secret of a device, `None` if it does not exist or was
+    /// never issued one.
+    async fn device_secret(&self, id: &str) -> XResult<Option<String>>;
+
+    async fn device_exists(&self, id: &str) -> XResult<bool>;
+
+    /// The metrics the device has a sensor for.
+    async fn sensors(&self, id: &str) -> XResult<Vec<Metric>>;
+
+    /// Every calibration version of the device, oldest first.
+    async fn calibrations(&self, id: &str) -> XResult<Vec<Calibration>>;
+
+    /// `true` when the reading is the newest of the device, late ones only
+    /// go into its history. `raw` holds the values as sent when the record
+    /// was calibrated.
+    async fn create_record(
+        &self,
+        id: &str,
+        time: DateTime<Local>,
+        record: &Validated,
+        raw: Option<[Option<f32>; METRIC_COUNT]>,
+    ) -> XResult<bool>;
+}
+
+impl DeviceStore for Backend {
+    async fn device_secret(&self, id: &str) -> XResult<Option<String>> {
+        let mut conn = self
+            .get_connection()
+            .await
+            .map_err(|e| XError::DB(e.to_string()))?;
+
+        self.get_device_secret(&mut conn, id)
+            .await
+            .map_err(|e| XError::DB(e.to_string()))
+    }
+
+    async fn device_exists(&self, id: &str) -> XResult<bool> {
+        let mut conn = self
+            .get_connection()
+            .await
+            .map_err(|e| XError::DB(e.to_string()))?;
+
+        self.check_device_exists(&mut conn, id)
+            .await
+            .map_err(|e| XError::DB(e.to_string()))
+    }
+
+    async fn sensors(&self, id: &str) -> XResult<Vec<Metric>> {
+        let mut conn = self
+            .get_connection()
+            .await
+            .map_err(|e| XError::DB(e.to_string()))?;
+
+        let sensors = self
+            .get_device_sensors(&mut conn, id)
+            .await
+            .map_err(|e| XError::DB(e.to_string()))?;
+
+        Ok(sensors.into_iter().map(|s| s.metric).collect())
+    }
+
+    async fn calibrations(&self, id: &str) -> XResult<Vec<Calibration>> {
+        let mut conn = self
+            .get_connection()
+            .await
+            .map_err(|e| XError::DB(e.to_string()))?;
+
+        self.get_device_calibrations(&mut conn, id)
+            .await
+            .map_err(|e| XError::DB(e.to_string()))
+    }
+
+    async fn create_record(
+        &self,
+        id: &str,
+        time: DateTime<Local>,
+        record: &Validated,
+        raw: Option<[Option<f32>; METRIC_COUNT]>,
+    ) -> XResult<bool> {
+        let mut conn = self
+            .get_connection()
+            .await
+            .map_err(|e| XError::DB(e.to_string()))?;
+
+        Backend::create_record(
+            self,
+            &mut conn,
+            id.to_string(),
+            time,
+            record.values,
+            raw,
+            record.quality,
+        )
+        .await
+        .map_err(|e| XError::DB(e.to_string()))
+    }
+}
+
+/// Reads newline delimited frames, see [`Frame::parse`], from the socket
+/// until the device disconnects or goes idle, answering each one with an [`ESPReply`]
+/// line. A device which sends a single frame without a trailing newline and
+/// closes the socket is still handled.
+pub async fn process<D, S>(
+    store: &D,
+    ctx: &EspContext,
     addr: SocketAddr,
-    mut socket: TcpStream,
+    socket: S,
     tx: Sender<ESPRecievedEvent>,
-) -> XResult<()> {
-    let mut msg = String::new();
+) -> XResult<()>
+where
+    D: DeviceStore,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut frames = Framed::new(socket, LinesCodec::new_with_max_length(MAX_FRAME_LENGTH));
 
-    match timeout(Duration::from_secs(5), socket.read_to_string(&mut msg)).await {
-        Ok(n) => match n {
-            Ok(_) => {}
-            Err(e) => {
+    loop {
+        let reply = match timeout(ctx.idle_timeout, frames.next()).await {
+            Ok(Some(Ok(msg))) if msg.trim().is_empty() => continue,
+            Ok(Some(Ok(msg))) => process_frame(store, ctx, addr, &msg, &tx).await,
+            Ok(Some(Err(LinesCodecError::MaxLineLengthExceeded))) => {
+                // The codec does not recover after an error, so the device
+                // has to reconnect.
+                tracing::error!(
+                    "[{}] Frame longer than {} bytes, closing connection",
+                    addr,
+                    MAX_FRAME_LENGTH
+                );
+                frames.send(ESPReply::FrameTooLong.to_string()).await.ok();
+                return Ok(());
+            }
+            Ok(Some(Err(LinesCodecError::Io(e)))) => {
                 tracing::error!(
                     "[{}] Error while reading message from client: {:?}",
                     addr,
@@ -27,69 +260,894 @@ pub async fn process(
                 );
                 return Ok(());
             }
-        },
-        Err(_) => {
-            tracing::error!("[{}] Timed out while reading message from client", addr);
+            Ok(None) => {
+                tracing::info!("[{}] ESP socket disconnected", addr);
+                return Ok(());
+            }
+            Err(_) => {
+                tracing::error!("[{}] Timed out while reading message from client", addr);
+                return Ok(());
+            }
+        };
+
+        if let Err(e) = frames.send(reply.to_string()).await {
+            tracing::error!("[{}] Failed to send reply to client: {:?}", addr, e);
             return Ok(());
         }
+    }
+}
+
+async fn process_frame<D: DeviceStore>(
+    store: &D,
+    ctx: &EspContext,
+    addr: SocketAddr,
+    msg: &str,
+    tx: &Sender<ESPRecievedEvent>,
+) -> ESPReply {
+    let msg = msg.trim();
+
+    let frame = match Frame::parse(msg) {
+        Ok(v) => v,
+        Err(reply) => {
+            tracing::error!("[{}] Malformed frame ({}): {}", addr, reply, msg);
+            return reply;
+        }
     };
 
-    let data = msg.trim().split(";").collect::<Vec<_>>();
+    if frame.unsigned {
+        return match accept_unsigned(store, ctx, addr, frame.device_id).await {
+            Ok(()) => {
+                ingest(
+                    store,
+                    ctx,
+                    addr,
+                    frame.device_id,
+                    Local::now(),
+                    frame.values,
+                    tx,
+                )
+                .await
+            }
+            Err(reply) => reply,
+        };
+    }
+
+    let clock = match authenticate(
+        store,
+        ctx,
+        addr,
+        frame.device_id,
+        frame.signed,
+        frame.signature,
+        frame.timestamp,
+        frame.nonce,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(reply) => return reply,
+    };
 
-    if data.len() != 15 {
+    // Readings buffered while offline carry the unix time they were taken at.
+    let time = match frame.taken_at {
+        Some(taken_at) => match taken_at
+            .parse::<i64>()
+            .ok()
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+            .and_then(|t| clock.server_time(t))
+        {
+            Some(v) => v,
+            None => {
+                tracing::error!(
+                    "[{}] Bad reading time from {}: {}",
+                    addr,
+                    frame.device_id,
+                    taken_at
+                );
+                return ESPReply::BadTime;
+            }
+        },
+        None => Local::now(),
+    };
+
+    ingest(store, ctx, addr, frame.device_id, time, frame.values, tx).await
+}
+
+/// Lets an unsigned v1 frame through while the grace period lasts, only for a
+/// device which was never issued a secret and so cannot sign yet.
+async fn accept_unsigned<D: DeviceStore>(
+    store: &D,
+    ctx: &EspContext,
+    addr: SocketAddr,
+    device_id: &str,
+) -> Result<(), ESPReply> {
+    let rejected = || {
+        tracing::error!("[{}] Rejected unsigned frame from {}", addr, device_id);
+        Err(ESPReply::Unauthorized(SignatureError::BadSignature))
+    };
+
+    if ctx.unsigned_until.is_none_or(|until| until <= Utc::now()) {
+        return rejected();
+    }
+
+    let unsigned = match store.device_secret(device_id).await {
+        Ok(Some(_)) => Ok(false),
+        Ok(None) => store.device_exists(device_id).await,
+        Err(e) => Err(e),
+    };
+
+    match unsigned {
+        Ok(true) => Ok(()),
+        Ok(false) => rejected(),
+        Err(e) => {
+            tracing::error!("[{}] Failed checking device ID: {:?}", addr, e);
+            Err(ESPReply::Db)
+        }
+    }
+}
+
+/// Checks that the device exists, that `signature` is its HMAC of `signed`
+/// and that the timestamp and nonce are fresh, returning the clock of the
+/// device. Shared by every signed ingestion path, `source` only shows up in
+/// the logs.
+#[allow(clippy::too_many_arguments)]
+pub async fn authenticate<D: DeviceStore>(
+    store: &D,
+    ctx: &EspContext,
+    source: impl std::fmt::Display,
+    device_id: &str,
+    signed: &str,
+    signature: &str,
+    timestamp: &str,
+    nonce: &str,
+) -> Result<DeviceClock, ESPReply> {
+    let secret = match store.device_secret(device_id).await {
+        Ok(Some(secret)) => secret,
+        // Answered like a bad signature, so device ids cannot be probed.
+        Ok(None) => {
+            tracing::error!("[{}] No secret for device ID: {}", source, device_id);
+            return Err(ESPReply::Unauthorized(SignatureError::BadSignature));
+        }
+        Err(e) => {
+            tracing::error!("[{}] Failed checking device ID: {:?}", source, e);
+            return Err(ESPReply::Db);
+        }
+    };
+
+    let checked = signing::verify(&secret, signed, signature).and_then(|_| {
+        let signed_at = timestamp
+            .parse::<i64>()
+            .ok()
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+            .ok_or(SignatureError::Stale)?;
+
+        let clock = DeviceClock::new(signed_at, Local::now());
+
+        // Before the replay window, so a clock drifted past it is still
+        // logged rather than only rejected as stale.
+        ctx.clocks.observe(device_id, &clock);
+        ctx.replay.check(device_id, signed_at.timestamp(), nonce)?;
+
+        Ok(clock)
+    });
+
+    match checked {
+        Ok(clock) => Ok(clock),
+        Err(e) => {
+            tracing::error!("[{}] Rejected reading from {}: {:?}", source, device_id, e);
+            Err(ESPReply::Unauthorized(e))
+        }
+    }
+}
+
+/// Validates and stores one reading of a known device taken at `time`, then
+/// hands it to the alert engine and the connected sessions unless the device
+/// already sent a newer one. Every ingestion path ends here, `source` only
+/// shows up in the logs.
+pub async fn ingest<D: DeviceStore>(
+    store: &D,
+    ctx: &EspContext,
+    source: impl std::fmt::Display,
+    device_id: &str,
+    time: DateTime<Local>,
+    mut raw: [Option<f32>; METRIC_COUNT],
+    tx: &Sender<ESPRecievedEvent>,
+) -> ESPReply {
+    // Postgres keeps microseconds, the event carries the time as stored so
+    // sessions can match it with the readings they replay.
+    let time = time.trunc_subsecs(6);
+
+    // Older readings could land in buckets whose raw readings were pruned.
+    if Local::now() - time > chrono::Duration::from_std(ctx.max_buffer_age).unwrap_or_default() {
         tracing::error!(
-            "[{}] Expected 15 components. Found {}. Message got: {}",
-            addr,
-            data.len(),
-            msg
+            "[{}] Rejected reading from {} taken at {}, older than {:?}",
+            source,
+            device_id,
+            time,
+            ctx.max_buffer_age
         );
-
-        return Ok(());
+        return ESPReply::BadTime;
     }
 
-    let device_id = data[0];
+    let profile = match ctx.devices.profile(store, device_id).await {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("Failed getting the profile of {}: {:?}", device_id, why);
+            return ESPReply::Db;
+        }
+    };
 
-    let mut conn = backend
-        .get_connection()
-        .await
-        .map_err(|e| XError::DB(e.to_string()))?;
+    // v1 frames carry every metric, devices put placeholders in those they
+    // cannot measure.
+    for metric in Metric::ALL {
+        if !profile.sensors.contains(&metric) {
+            raw[metric.index()] = None;
+        }
+    }
 
-    if !backend
-        .check_device_exists(&mut conn, device_id)
-        .await
-        .map_err(|e| XError::DB(e.to_string()))?
-    {
-        tracing::error!("[{}] Invalid device ID: {}", addr, device_id);
-        return Ok(());
+    let mut record = match ctx.validator.validate(raw) {
+        Ok(v) => v,
+        Err(metric) => {
+            tracing::error!(
+                "[{}] Rejected reading from {}, invalid {}: {:?}",
+                source,
+                device_id,
+                metric,
+                raw[metric.index()]
+            );
+            return ESPReply::BadValue(metric);
+        }
+    };
+
+    if record.quality != 0 {
+        tracing::warn!(
+            "[{}] Dropped or clamped invalid values from {}: {:?}",
+            source,
+            device_id,
+            raw
+        );
     }
 
-    let values: [f32; 14] = data[1..]
-        .iter()
-        .map(|x| x.parse::<f32>().unwrap_or_else(|_| f32::NAN))
-        .collect::<Vec<_>>()
-        .try_into()
-        .unwrap();
+    // The validation ranges are those of the sensors, so values are only
+    // corrected once they passed. The values as sent are kept alongside to
+    // recalibrate them later.
+    let sent = (!profile.calibrations.is_empty()).then_some(record.values);
+    record.values = calibrate(&record.values, &profile.calibrations, time);
 
-    let values0 = values.clone();
+    let newest = match store.create_record(device_id, time, &record, sent).await {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("Error while creating a record: {:?}", why);
+            return ESPReply::Db;
+        }
+    };
 
-    let values: PmValues = values.into();
+    if !newest {
+        tracing::info!(
+            "[{}] Backfilled reading of {} at {}",
+            source,
+            device_id,
+            time
+        );
+        return ESPReply::Ok;
+    }
+
+    // Ingestion never waits on alert delivery, a transition the dispatcher
+    // has no room for is dropped.
+    for transition in ctx.alerts.evaluate(device_id, &record.values, time) {
+        match ctx.alert_tx.try_send(transition) {
+            Ok(()) => (),
+            Err(TrySendError::Full(transition)) => tracing::error!(
+                "Alert dispatcher is behind, dropped '{}' {:?} for {}",
+                transition.rule,
+                transition.state,
+                device_id
+            ),
+            Err(TrySendError::Closed(_)) => {
+                tracing::error!("Failed to send alert to dispatcher: channel closed")
+            }
+        }
+    }
 
     if let Err(why) = tx
         .send(ESPRecievedEvent {
             id: device_id.to_string(),
-            data: values.clone(),
+            data: PmValues::from(record.values),
+            time,
+            aqi: ctx.devices.aqi(device_id),
         })
         .await
     {
         tracing::error!("Failed to send values to thread: {}", why.to_string());
     };
 
-    if let Err(why) = backend
-        .create_record(&mut conn, device_id.to_string(), values0)
-        .await
-    {
-        tracing::error!("Error while creating a record: {:?}", why);
+    ESPReply::Ok
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use chrono::Utc;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        sync::mpsc::{channel, Receiver},
     };
 
-    Ok(())
+    use common::{
+        alerts::{AlertRule, AlertState, Comparator},
+        validation::{InvalidPolicy, MetricRule},
+    };
+
+    use super::*;
+
+    pub(crate) const DEVICE: &str = "abcdefghijklmno";
+    pub(crate) const SECRET: &str = "0123456789abcdefghijklmnopqrstuv";
+    /// A device which was never issued a secret.
+    const UNSIGNED_DEVICE: &str = "onmlkjihgfedcba";
+    const VALUES: &str = "1;2;3;4;5;6;7;8;9;10;11;12;13;14";
+
+    /// A frame signed with `SECRET`, stamped now with a random nonce.
+    fn frame(id: &str, values: &str) -> String {
+        signed_frame(
+            id,
+            values,
+            Utc::now().timestamp(),
+            &rand::random::<u64>().to_string(),
+        )
+    }
+
+    fn signed_frame(id: &str, values: &str, timestamp: i64, nonce: &str) -> String {
+        let payload = format!("{id};{values};{timestamp};{nonce}");
+        let signature = signing::sign(SECRET, &payload);
+
+        format!("{payload};{signature}\n")
+    }
+
+    /// Device, reading time, the calibrated record and the values as sent.
+    type StoredRecord = (
+        String,
+        DateTime<Local>,
+        Validated,
+        Option<[Option<f32>; METRIC_COUNT]>,
+    );
+
+    #[derive(Default)]
+    pub(crate) struct MemoryStore {
+        pub fail: bool,
+        /// Every metric when `None`.
+        pub sensors: Option<Vec<Metric>>,
+        pub calibrations: Vec<Calibration>,
+        pub records: Mutex<Vec<StoredRecord>>,
+    }
+
+    impl DeviceStore for MemoryStore {
+        async fn device_secret(&self, id: &str) -> XResult<Option<String>> {
+            Ok((id == DEVICE).then(|| SECRET.to_string()))
+        }
+
+        async fn device_exists(&self, id: &str) -> XResult<bool> {
+            Ok(id == DEVICE || id == UNSIGNED_DEVICE)
+        }
+
+        async fn sensors(&self, _id: &str) -> XResult<Vec<Metric>> {
+            Ok(self.sensors.clone().unwrap_or(Metric::ALL.to_vec()))
+        }
+
+        async fn calibrations(&self, _id: &str) -> XResult<Vec<Calibration>> {
+            Ok(self.calibrations.clone())
+        }
+
+        async fn create_record(
+            &self,
+            id: &str,
+            time: DateTime<Local>,
+            record: &Validated,
+            raw: Option<[Option<f32>; METRIC_COUNT]>,
+        ) -> XResult<bool> {
+            if self.fail {
+                return Err(XError::DB("unavailable".to_string()));
+            }
+
+            let mut records = self.records.lock().unwrap();
+            let newest = records.iter().all(|(i, t, _, _)| i != id || *t <= time);
+
+            records.push((id.to_string(), time, record.clone(), raw));
+            Ok(newest)
+        }
+    }
+
+    /// Writes `input` to a fresh connection and collects every reply line.
+    async fn exchange(store: &MemoryStore, input: &str) -> (Vec<String>, Vec<ESPRecievedEvent>) {
+        exchange_with(store, Validator::default(), input).await
+    }
+
+    async fn exchange_with(
+        store: &MemoryStore,
+        validator: Validator,
+        input: &str,
+    ) -> (Vec<String>, Vec<ESPRecievedEvent>) {
+        let (ctx, _) = context(validator, AlertEngine::default());
+
+        exchange_ctx(store, &ctx, input).await
+    }
+
+    pub(crate) fn context(
+        validator: Validator,
+        alerts: AlertEngine,
+    ) -> (EspContext, Receiver<AlertTransition>) {
+        let (alert_tx, alert_rx) = channel(16);
+
+        let ctx = EspContext {
+            validator,
+            replay: ReplayGuard::new(Duration::from_secs(60)),
+            idle_timeout: Duration::from_secs(5),
+            max_buffer_age: Duration::from_secs(3600),
+            alerts,
+            alert_tx,
+            clocks: ClockMonitor::new(Duration::from_secs(10)),
+            unsigned_until: None,
+            devices: Arc::default(),
+        };
+
+        (ctx, alert_rx)
+    }
+
+    async fn exchange_ctx(
+        store: &MemoryStore,
+        ctx: &EspContext,
+        input: &str,
+    ) -> (Vec<String>, Vec<ESPRecievedEvent>) {
+        let (client, server) = tokio::io::duplex(4096);
+        let (tx, mut rx) = channel(16);
+
+        let (mut read, mut write) = tokio::io::split(client);
+        write.write_all(input.as_bytes()).await.unwrap();
+        write.shutdown().await.unwrap();
+
+        process(store, ctx, "127.0.0.1:2442".parse().unwrap(), server, tx)
+            .await
+            .unwrap();
+
+        let mut lines = BufReader::new(&mut read).lines();
+        let mut replies = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            replies.push(line);
+        }
+
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+
+        (replies, events)
+    }
+
+    #[tokio::test]
+    async fn valid_frame_is_stored_and_acknowledged() {
+        let store = MemoryStore::default();
+
+        let (replies, events) = exchange(&store, &frame(DEVICE, VALUES)).await;
+
+        assert_eq!(replies, ["OK"]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id, DEVICE);
+        assert_eq!(store.records.lock().unwrap()[0].2.values[13], Some(14.0));
+    }
+
+    #[tokio::test]
+    async fn frames_share_one_connection() {
+        let store = MemoryStore::default();
+
+        let input = format!("{}\n{}", frame(DEVICE, VALUES), frame(DEVICE, VALUES));
+        let (replies, events) = exchange(&store, &input).await;
+
+        assert_eq!(replies, ["OK", "OK"]);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn frame_without_newline_is_handled() {
+        let store = MemoryStore::default();
+
+        let input = format!("\n\n{}", frame(DEVICE, VALUES).trim_end());
+        let (replies, events) = exchange(&store, &input).await;
+
+        assert_eq!(replies, ["OK"]);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn silent_connection_is_closed() {
+        let store = MemoryStore::default();
+        let (mut ctx, _) = context(Validator::default(), AlertEngine::default());
+        ctx.idle_timeout = Duration::from_millis(50);
+
+        let (client, server) = tokio::io::duplex(4096);
+        let (tx, _rx) = channel(16);
+
+        let (mut read, mut write) = tokio::io::split(client);
+        write
+            .write_all(frame(DEVICE, VALUES).as_bytes())
+            .await
+            .unwrap();
+
+        // The client keeps its end open, only the idle timeout ends this.
+        process(&store, &ctx, "127.0.0.1:2442".parse().unwrap(), server, tx)
+            .await
+            .unwrap();
+
+        let mut lines = BufReader::new(&mut read).lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("OK"));
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn wrong_component_count_is_rejected() {
+        let store = MemoryStore::default();
+
+        let (replies, events) = exchange(&store, &frame(DEVICE, "1;2;3")).await;
+
+        assert_eq!(replies, ["ERR BAD_FRAME 7"]);
+        assert!(events.is_empty());
+        assert!(store.records.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_device_is_rejected() {
+        let store = MemoryStore::default();
+
+        let (replies, events) = exchange(&store, &frame("nobody", VALUES)).await;
+
+        assert_eq!(replies, ["ERR BAD_SIGNATURE"]);
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsigned_frame_is_accepted_during_grace_period() {
+        let store = MemoryStore::default();
+        let (mut ctx, _) = context(Validator::default(), AlertEngine::default());
+
+        let input = format!("{UNSIGNED_DEVICE};{VALUES}\n{DEVICE};{VALUES}\nnobody;{VALUES}\n");
+
+        let (replies, _) = exchange_ctx(&store, &ctx, &input).await;
+        assert_eq!(replies, ["ERR BAD_SIGNATURE"; 3]);
+
+        ctx.unsigned_until = Some(Utc::now() + chrono::Duration::hours(1));
+
+        // Devices which were issued a secret have to sign.
+        let (replies, events) = exchange_ctx(&store, &ctx, &input).await;
+        assert_eq!(replies, ["OK", "ERR BAD_SIGNATURE", "ERR BAD_SIGNATURE"]);
+        assert_eq!(events[0].id, UNSIGNED_DEVICE);
+    }
+
+    #[tokio::test]
+    async fn storage_failure_is_reported() {
+        let store = MemoryStore {
+            fail: true,
+            ..Default::default()
+        };
+
+        let (replies, events) = exchange(&store, &frame(DEVICE, VALUES)).await;
+
+        assert_eq!(replies, ["ERR DB"]);
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_rejected() {
+        let store = MemoryStore::default();
+
+        let input = format!(
+            "{}\n{}",
+            "x".repeat(MAX_FRAME_LENGTH + 1),
+            frame(DEVICE, VALUES)
+        );
+        let (replies, _) = exchange(&store, &input).await;
+
+        assert_eq!(replies, ["ERR FRAME_TOO_LONG"]);
+        assert!(store.records.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn garbled_value_is_dropped_and_flagged() {
+        let store = MemoryStore::default();
+
+        let input = frame(DEVICE, "abc;2;3;4;5;6;7;8;9;10;11;12;13;14");
+        let (replies, events) = exchange(&store, &input).await;
+
+        assert_eq!(replies, ["OK"]);
+        assert_eq!(events[0].data.co, None);
+
+        let records = store.records.lock().unwrap();
+        assert_eq!(records[0].2.values[0], None);
+        assert_eq!(records[0].2.quality, 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_value_rejects_frame_when_configured() {
+        let store = MemoryStore::default();
+        let mut validator = Validator::default();
+        validator.set_rule(
+            Metric::Co,
+            MetricRule {
+                min: 0.0,
+                max: 100.0,
+                policy: InvalidPolicy::Reject,
+            },
+        );
+
+        let input = frame(DEVICE, "500;2;3;4;5;6;7;8;9;10;11;12;13;14");
+        let (replies, events) = exchange_with(&store, validator, &input).await;
+
+        assert_eq!(replies, ["ERR BAD_VALUE co"]);
+        assert!(events.is_empty());
+        assert!(store.records.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tampered_frame_is_rejected() {
+        let store = MemoryStore::default();
+
+        let input = frame(DEVICE, VALUES).replacen(";1;", ";9;", 1);
+        let (replies, events) = exchange(&store, &input).await;
+
+        assert_eq!(replies, ["ERR BAD_SIGNATURE"]);
+        assert!(events.is_empty());
+        assert!(store.records.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn replayed_frame_is_rejected() {
+        let store = MemoryStore::default();
+
+        let input = frame(DEVICE, VALUES);
+        let (replies, _) = exchange(&store, &format!("{input}{input}")).await;
+
+        assert_eq!(replies, ["OK", "ERR REPLAY"]);
+        assert_eq!(store.records.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_frame_is_rejected() {
+        let store = MemoryStore::default();
+
+        let old = Utc::now().timestamp() - 3600;
+        let (replies, _) = exchange(&store, &signed_frame(DEVICE, VALUES, old, "1")).await;
+
+        assert_eq!(replies, ["ERR STALE"]);
+        assert!(store.records.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn clock_off_by_more_than_the_replay_window_is_reported() {
+        let store = MemoryStore::default();
+        let (ctx, _) = context(Validator::default(), AlertEngine::default());
+
+        let old = Utc::now().timestamp() - 3600;
+        let (replies, _) =
+            exchange_ctx(&store, &ctx, &signed_frame(DEVICE, VALUES, old, "1")).await;
+
+        assert_eq!(replies, ["ERR STALE"]);
+        assert!(ctx.clocks.skewed.lock().unwrap().contains(DEVICE));
+
+        // Only a signed timestamp says anything about the clock.
+        let forged = signed_frame(UNSIGNED_DEVICE, VALUES, old, "2");
+        exchange_ctx(&store, &ctx, &forged).await;
+        assert!(!ctx.clocks.skewed.lock().unwrap().contains(UNSIGNED_DEVICE));
+    }
+
+    #[tokio::test]
+    async fn breached_rule_emits_alert() {
+        let store = MemoryStore::default();
+        let rule = AlertRule {
+            name: "high-co".to_string(),
+            metric: Metric::Co,
+            comparator: Comparator::Above,
+            threshold: 35.0,
+            duration_secs: 0,
+            hysteresis: 0.0,
+            devices: vec![DEVICE.to_string()],
+            group: None,
+        };
+        let (ctx, mut alert_rx) = context(
+            Validator::default(),
+            AlertEngine::new(&[rule], &Default::default()),
+        );
+
+        let input = format!(
+            "{}{}{}",
+            frame(DEVICE, VALUES),
+            frame(DEVICE, "40;2;3;4;5;6;7;8;9;10;11;12;13;14"),
+            frame(DEVICE, "41;2;3;4;5;6;7;8;9;10;11;12;13;14")
+        );
+        let (replies, _) = exchange_ctx(&store, &ctx, &input).await;
+
+        assert_eq!(replies, ["OK", "OK", "OK"]);
+
+        let transition = alert_rx.try_recv().unwrap();
+        assert_eq!(transition.state, AlertState::Firing);
+        assert_eq!(transition.value, 40.0);
+        assert!(alert_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_alert_queue_does_not_hold_up_readings() {
+        let store = MemoryStore::default();
+        let rule = AlertRule {
+            name: "high-co".to_string(),
+            metric: Metric::Co,
+            comparator: Comparator::Above,
+            threshold: 35.0,
+            duration_secs: 0,
+            hysteresis: 0.0,
+            devices: vec![DEVICE.to_string()],
+            group: None,
+        };
+        let (ctx, mut alert_rx) = context(
+            Validator::default(),
+            AlertEngine::new(&[rule], &Default::default()),
+        );
+
+        let queued = AlertTransition {
+            rule: "other".to_string(),
+            device_id: DEVICE.to_string(),
+            metric: Metric::Co,
+            state: AlertState::Resolved,
+            value: 0.0,
+            threshold: 0.0,
+            at: Local::now(),
+        };
+        while ctx.alert_tx.try_send(queued.clone()).is_ok() {}
+
+        let input = frame(DEVICE, "40;2;3;4;5;6;7;8;9;10;11;12;13;14");
+        let (replies, events) = exchange_ctx(&store, &ctx, &input).await;
+
+        assert_eq!(replies, ["OK"]);
+        assert_eq!(events.len(), 1);
+        while let Ok(transition) = alert_rx.try_recv() {
+            assert_eq!(transition.rule, "other");
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_frame_sends_only_some_sensors() {
+        let store = MemoryStore::default();
+
+        let input = frame(&format!("v2;{DEVICE}"), "co=1.5;pm_25=8");
+        let (replies, events) = exchange(&store, &input).await;
+
+        assert_eq!(replies, ["OK"]);
+        assert_eq!(events[0].data.co, Some(1.5));
+        assert_eq!(events[0].data.pm_25, Some(8.0));
+        assert_eq!(events[0].data.co2, None);
+        assert_eq!(store.records.lock().unwrap()[0].2.quality, 0);
+    }
+
+    #[tokio::test]
+    async fn metrics_without_sensor_are_dropped() {
+        let store = MemoryStore {
+            sensors: Some(vec![Metric::Co, Metric::Pm25]),
+            ..Default::default()
+        };
+
+        let (replies, events) = exchange(&store, &frame(DEVICE, VALUES)).await;
+
+        assert_eq!(replies, ["OK"]);
+        assert_eq!(events[0].data.values().iter().flatten().count(), 2);
+        assert_eq!(
+            serde_json::to_value(&events[0].data).unwrap(),
+            serde_json::json!({ "co": 1.0, "pm_25": 7.0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn calibrated_reading_keeps_sent_values() {
+        let store = MemoryStore {
+            calibrations: vec![Calibration {
+                metric: Metric::Co,
+                coefficients: vec![-0.5, 2.0],
+                humidity_kappa: None,
+                effective_from: Local::now() - chrono::Duration::days(1),
+            }],
+            ..Default::default()
+        };
+
+        let (replies, events) = exchange(&store, &frame(DEVICE, VALUES)).await;
+
+        assert_eq!(replies, ["OK"]);
+        assert_eq!(events[0].data.co, Some(1.5));
+        assert_eq!(events[0].data.co2, Some(2.0));
+
+        let records = store.records.lock().unwrap();
+        assert_eq!(records[0].2.values[0], Some(1.5));
+        assert_eq!(records[0].3.unwrap()[0], Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn buffered_reading_keeps_its_time() {
+        let store = MemoryStore::default();
+
+        let taken_at = Utc::now().timestamp() - 600;
+        let (replies, events) =
+            exchange(&store, &frame(DEVICE, &format!("{VALUES};{taken_at}"))).await;
+
+        assert_eq!(replies, ["OK"]);
+        assert_eq!(events.len(), 1);
+
+        let age = Local::now() - store.records.lock().unwrap()[0].1;
+        assert!((599..=602).contains(&age.num_seconds()));
+    }
+
+    #[tokio::test]
+    async fn late_reading_only_goes_into_history() {
+        let store = MemoryStore::default();
+
+        let taken_at = Utc::now().timestamp() - 600;
+        let input = format!(
+            "{}{}",
+            frame(DEVICE, VALUES),
+            frame(DEVICE, &format!("{VALUES};{taken_at}"))
+        );
+        let (replies, events) = exchange(&store, &input).await;
+
+        assert_eq!(replies, ["OK", "OK"]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(store.records.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reading_older_than_the_buffer_age_is_rejected() {
+        let store = MemoryStore::default();
+
+        let taken_at = Utc::now().timestamp() - 3601;
+        let (replies, _) = exchange(&store, &frame(DEVICE, &format!("{VALUES};{taken_at}"))).await;
+
+        assert_eq!(replies, ["ERR BAD_TIME"]);
+        assert!(store.records.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reading_from_the_future_is_rejected() {
+        let store = MemoryStore::default();
+
+        let taken_at = Utc::now().timestamp() + 60;
+        let (replies, _) = exchange(&store, &frame(DEVICE, &format!("{VALUES};{taken_at}"))).await;
+
+        assert_eq!(replies, ["ERR BAD_TIME"]);
+        assert!(store.records.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn device_time_is_moved_onto_server_clock() {
+        let received_at = Local::now();
+        // The device clock is 30 seconds behind.
+        let signed_at = received_at.to_utc() - chrono::Duration::seconds(30);
+        let clock = DeviceClock::new(signed_at, received_at);
+
+        assert_eq!(clock.skew(), 30);
+        assert_eq!(
+            clock.server_time(signed_at - chrono::Duration::seconds(10)),
+            Some(received_at - chrono::Duration::seconds(10))
+        );
+        assert_eq!(clock.server_time(signed_at), Some(received_at));
+        assert_eq!(
+            clock.server_time(signed_at + chrono::Duration::seconds(5)),
+            None
+        );
+    }
+
+    #[test]
+    fn skewed_clock_is_reported_once() {
+        let monitor = ClockMonitor::new(Duration::from_secs(10));
+        let now = Local::now();
+        let skewed = DeviceClock::new(now.to_utc() - chrono::Duration::seconds(60), now);
+        let synced = DeviceClock::new(now.to_utc(), now);
+
+        assert!(monitor.observe(DEVICE, &skewed));
+        assert!(monitor.observe(DEVICE, &skewed));
+        assert!(monitor.skewed.lock().unwrap().contains(DEVICE));
+        assert!(!monitor.observe(DEVICE, &synced));
+        assert!(monitor.skewed.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,205 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Local};
+use common::{aggregates::FIVE_MINUTES, aqi::AqiStandard, config::RetentionConfig, Backend};
+use diesel_async::{pooled_connection::bb8::PooledConnection, AsyncPgConnection};
+
+use crate::cache::DeviceCache;
+
+/// Rolls new readings up, refreshes the cached index of every device from
+/// the new hourly buckets and deletes what is past its retention, forever.
+pub async fn run(backend: Backend, config: RetentionConfig, devices: Arc<DeviceCache>) {
+    refresh_aqi(&backend, &devices).await;
+
+    loop {
+        tokio::time::sleep(config.interval()).await;
+
+        let mut conn = match backend.get_connection().await {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!("Failed to get connection: {:?}", e);
+                continue;
+            }
+        };
+
+        let now = Local::now();
+
+        // Raw readings are only deleted once they made it into a rollup.
+        if let Err(e) = backend
+            .roll_up(
+                &mut conn,
+                now - config.raw_age(),
+                now - config.five_minute_age(),
+            )
+            .await
+        {
+            tracing::error!("Failed rolling up readings: {:?}", e);
+            continue;
+        }
+
+        refresh_aqi(&backend, &devices).await;
+        prune(&backend, &mut conn, &config, now).await;
+    }
+}
+
+/// Deletes raw readings and 5 minute buckets past their retention at `now`.
+async fn prune(
+    backend: &Backend,
+    conn: &mut PooledConnection<'static, AsyncPgConnection>,
+    config: &RetentionConfig,
+    now: DateTime<Local>,
+) {
+    match backend
+        .delete_readings_before(conn, now - config.raw_age())
+        .await
+    {
+        Ok(0) => (),
+        Ok(n) => tracing::info!("Deleted {n} expired readings"),
+        Err(e) => tracing::error!("Failed deleting expired readings: {:?}", e),
+    }
+
+    match backend
+        .delete_aggregates_before(conn, FIVE_MINUTES, now - config.five_minute_age())
+        .await
+    {
+        Ok(0) => (),
+        Ok(n) => tracing::info!("Deleted {n} expired 5 minute buckets"),
+        Err(e) => tracing::error!("Failed deleting expired 5 minute buckets: {:?}", e),
+    }
+}
+
+/// The index only changes with the hourly buckets, so readings are sent
+/// out with the one computed after the last rollup.
+async fn refresh_aqi(backend: &Backend, devices: &DeviceCache) {
+    let mut conn = match backend.get_connection().await {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("Failed to get connection: {:?}", e);
+            return;
+        }
+    };
+
+    match backend.get_devices_aqi(&mut conn, AqiStandard::UsEpa).await {
+        Ok(all) => devices.set_aqi(
+            all.into_iter()
+                .filter_map(|d| Some((d.id, d.aqi?)))
+                .collect::<HashMap<_, _>>(),
+        ),
+        Err(e) => tracing::error!("Failed computing the AQI of the devices: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use common::{
+        aggregates::Resolution,
+        metric::{Metric, METRIC_COUNT},
+    };
+    use diesel_async::AsyncConnection;
+
+    use super::*;
+
+    async fn buckets(
+        backend: &Backend,
+        conn: &mut PooledConnection<'static, AsyncPgConnection>,
+        id: &str,
+        resolution: Resolution,
+    ) -> usize {
+        let from = Local::now() - Duration::days(2);
+
+        backend
+            .get_device_history(conn, id, from, Local::now(), resolution, &[], None, 100)
+            .await
+            .unwrap()
+            .data
+            .len()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the database at DATABASE_URL"]
+    async fn rollups_outlive_the_readings_they_were_built_from() {
+        let backend = Backend::new().await;
+        let mut conn = backend.get_connection().await.unwrap();
+
+        let (id, _) = backend
+            .create_device(
+                &mut conn,
+                "retention".to_string(),
+                "test".to_string(),
+                0.0,
+                0.0,
+                false,
+                &[],
+            )
+            .await
+            .unwrap();
+
+        let now = Local::now();
+        let mut values = [None; METRIC_COUNT];
+        values[Metric::Co2.index()] = Some(400.0);
+
+        for time in [now - Duration::hours(20), now - Duration::minutes(10)] {
+            backend
+                .add_reading(&mut conn, &id, time, &values, None, 0)
+                .await
+                .unwrap();
+        }
+
+        backend
+            .mark_backfill(&mut conn, &id, now - Duration::hours(20))
+            .await
+            .unwrap();
+
+        let config = RetentionConfig {
+            raw_days: 1,
+            five_minute_days: 2,
+            ..Default::default()
+        };
+
+        backend
+            .roll_up(
+                &mut conn,
+                now - config.raw_age(),
+                now - config.five_minute_age(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            buckets(&backend, &mut conn, &id, Resolution::FiveMinutes).await,
+            2
+        );
+
+        // Pruning deletes the expired data of every device, so none of it is
+        // committed. The rollups it works on have to be, they take a
+        // transaction of their own.
+        backend.delete_device(&mut conn, &id).await.unwrap();
+        conn.begin_test_transaction().await.unwrap();
+
+        // Five hours on, the older reading is past the raw retention.
+        prune(&backend, &mut conn, &config, now + Duration::hours(5)).await;
+
+        assert_eq!(buckets(&backend, &mut conn, &id, Resolution::Raw).await, 1);
+        assert_eq!(
+            buckets(&backend, &mut conn, &id, Resolution::FiveMinutes).await,
+            2
+        );
+
+        // Two days on, only the hourly buckets are left.
+        prune(
+            &backend,
+            &mut conn,
+            &config,
+            now + Duration::days(2) + Duration::hours(1),
+        )
+        .await;
+
+        assert_eq!(buckets(&backend, &mut conn, &id, Resolution::Raw).await, 0);
+        assert_eq!(
+            buckets(&backend, &mut conn, &id, Resolution::FiveMinutes).await,
+            0
+        );
+        assert_eq!(buckets(&backend, &mut conn, &id, Resolution::Hour).await, 2);
+    }
+}
@@ -1,12 +1,34 @@
 use axum::{
-    extract::{Path, Query},
-    http::StatusCode,
-    response::IntoResponse,
+    body::Body,
+    extract::{rejection::QueryRejection, Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Extension, Json,
 };
-use common::{records::LastReading, Backend};
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Local};
+use common::{
+    aggregates::{LegacyReadings, Resolution},
+    aqi::AqiStandard,
+    device::DeviceUpdate,
+    export::{ExportError, ExportFormat, ExportPages, ExportQuery, Exporter},
+    metric::{Metric, Sensor},
+    pagination::HistoryCursor,
+    records::LastReading,
+    Backend,
+};
 use serde::Deserialize;
 use serde_json::json;
+use tokio::sync::mpsc::{channel, Sender};
+
+use crate::{
+    auth::Access,
+    hub::{Hub, HubEvent},
+    models::{ESPActiveEvent, ESPRecievedEvent, ESPReply, PostedReading},
+    process_esp::{self, DeviceStore, EspContext},
+    webhooks::Webhooks,
+};
 
 macro_rules! success {
     ($dfn:expr, $msg:expr) => {
@@ -24,32 +46,261 @@ macro_rules! success {
     };
 }
 
+/// Takes the parsed query string, answering a malformed one in the JSON
+/// shape of every other error instead of axum's plain text.
+macro_rules! query {
+    ($query:expr) => {
+        match $query {
+            Ok(Query(v)) => v,
+            Err(e) => return bad_request(e.body_text()),
+        }
+    };
+}
+
+/// Rejects callers without a key unless the device is public. Private
+/// devices look like missing ones to them.
+macro_rules! readable {
+    ($backend:expr, $conn:expr, $access:expr, $id:expr) => {
+        if $access.public_only()
+            && !success!(
+                $backend.check_device_public(&mut $conn, $id).await,
+                "Failed getting device"
+            )
+        {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "success": false, "message": "Device not found" })),
+            );
+        }
+    };
+}
+
+const DEFAULT_DEVICES_LIMIT: i64 = 100;
+const DEFAULT_HISTORY_LIMIT: i64 = 1000;
+const DEFAULT_DEAD_LETTERS_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 10000;
+/// Most readings accepted in one `POST /devices/:id/readings`.
+const MAX_BATCH: usize = 1000;
+
+/// Unix time the device signed the request at.
+pub const DEVICE_TIMESTAMP_HEADER: &str = "X-Device-Timestamp";
+/// Single use value, like the nonce of a TCP frame.
+pub const DEVICE_NONCE_HEADER: &str = "X-Device-Nonce";
+/// Hex HMAC-SHA256 of `{id};{timestamp};{nonce};{body}` keyed with the
+/// device secret.
+pub const DEVICE_SIGNATURE_HEADER: &str = "X-Device-Signature";
+
 #[derive(Debug, Deserialize)]
-pub struct LastReadingSelect {
-    select: LastReading,
+pub struct PageQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Either a `select` preset or a range, by default the last 24 hours at a
+/// resolution fitting the range. `metrics` is a comma separated list.
+#[derive(Debug, Deserialize)]
+pub struct ReadingsQuery {
+    select: Option<LastReading>,
+    from: Option<DateTime<Local>>,
+    to: Option<DateTime<Local>>,
+    resolution: Option<Resolution>,
+    metrics: Option<String>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+/// The part of a history a [`ReadingsQuery`] asks for.
+#[derive(Debug, PartialEq)]
+struct HistoryRange {
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+    resolution: Resolution,
+    /// Empty for every metric.
+    metrics: Vec<Metric>,
+}
+
+impl ReadingsQuery {
+    /// The range asked for at `now`, the presets being hourly rows of the
+    /// last day or week. Not for `select=Last`.
+    fn range(&self, now: DateTime<Local>) -> Result<HistoryRange, String> {
+        let to = self.to.unwrap_or(now);
+
+        let (from, preset) = match self.select {
+            Some(LastReading::Hours24) => (to - Duration::days(1), Some(Resolution::Hour)),
+            Some(LastReading::Days7) => (to - Duration::days(7), Some(Resolution::Hour)),
+            _ => (self.from.unwrap_or(to - Duration::days(1)), None),
+        };
+
+        if from >= to {
+            return Err("from must be before to".to_string());
+        }
+
+        let resolution = self
+            .resolution
+            .or(preset)
+            .unwrap_or_else(|| Resolution::for_range(from, to));
+
+        let metrics = self
+            .metrics
+            .iter()
+            .flat_map(|m| m.split(','))
+            .map(|m| m.trim())
+            .filter(|m| !m.is_empty())
+            .map(|m| m.parse::<Metric>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(HistoryRange {
+            from,
+            to,
+            resolution,
+            metrics,
+        })
+    }
+}
+
+/// `devices` is a comma separated list of device ids.
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    devices: String,
+    from: Option<DateTime<Local>>,
+    to: Option<DateTime<Local>>,
+    resolution: Option<Resolution>,
+    format: Option<ExportFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AqiQuery {
+    #[serde(default)]
+    standard: AqiStandard,
+}
+
+/// Body of `POST /webhooks`. Without `device_id` the webhook receives the
+/// events of every device.
+#[derive(Debug, Deserialize)]
+pub struct NewWebhook {
+    url: String,
+    device_id: Option<String>,
+    #[serde(default = "default_true")]
+    data: bool,
+    #[serde(default = "default_true")]
+    device_active: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Body of `POST /devices`. Without `sensors` the device gets every metric
+/// of the stock box.
+#[derive(Debug, Deserialize)]
+pub struct NewDevice {
+    name: String,
+    #[serde(rename = "box")]
+    box_: String,
+    lat: f32,
+    long: f32,
+    #[serde(default)]
+    public: bool,
+    sensors: Option<Vec<Sensor>>,
+}
+
+/// Checks the fields of a new or changed device against the columns they
+/// are stored in.
+fn check_device(
+    name: Option<&str>,
+    box_: Option<&str>,
+    lat: Option<f32>,
+    long: Option<f32>,
+) -> Result<(), String> {
+    if let Some(name) = name {
+        if name.trim().is_empty() || name.len() > 255 {
+            return Err("name must be between 1 and 255 bytes".to_string());
+        }
+    }
+
+    if let Some(box_) = box_ {
+        if box_.trim().is_empty() || box_.len() > 100 {
+            return Err("box must be between 1 and 100 bytes".to_string());
+        }
+    }
+
+    if lat.is_some_and(|v| !(-90.0..=90.0).contains(&v)) {
+        return Err("lat must be between -90 and 90".to_string());
+    }
+
+    if long.is_some_and(|v| !(-180.0..=180.0).contains(&v)) {
+        return Err("long must be between -180 and 180".to_string());
+    }
+
+    Ok(())
 }
 
 pub async fn root() -> &'static str {
     "Hello, World!"
 }
 
-pub async fn get_devices(backend: Extension<Backend>) -> impl IntoResponse {
+/// Counters of the event hub, how many events sessions missed by falling
+/// behind among them.
+pub async fn get_hub_stats(hub: Extension<Hub>) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(json!({ "success": true, "data": hub.stats() })),
+    )
+}
+
+pub async fn get_devices(
+    backend: Extension<Backend>,
+    access: Extension<Access>,
+    query: Result<Query<PageQuery>, QueryRejection>,
+) -> impl IntoResponse {
+    let q = query!(query);
+
+    let limit = match page_limit(q.limit, DEFAULT_DEVICES_LIMIT) {
+        Ok(v) => v,
+        Err(e) => return bad_request(e),
+    };
+
     let mut conn = success!(backend.get_connection().await, "Failed to get connection");
 
-    let data = success!(
-        backend.list_devices(&mut conn).await,
+    let page = success!(
+        backend
+            .list_devices(&mut conn, q.cursor.as_deref(), access.public_only(), limit)
+            .await,
         "Failed to get devices"
     );
 
     (
         StatusCode::OK,
-        Json(json!({ "success": true, "data": data })),
+        Json(json!({
+            "success": true,
+            "data": page.data,
+            "limit": limit,
+            "next_cursor": page.next_cursor,
+        })),
     )
 }
 
-pub async fn get_device(backend: Extension<Backend>, Path(id): Path<String>) -> impl IntoResponse {
+pub async fn get_device(
+    backend: Extension<Backend>,
+    access: Extension<Access>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
     let mut conn = success!(backend.get_connection().await, "Failed getting connection");
 
+    readable!(backend, conn, access, &id);
+
+    let exists = success!(
+        backend.check_device_exists(&mut conn, &id).await,
+        "Failed getting device"
+    );
+
+    if !exists {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "success": false, "message": "Device not found" })),
+        );
+    }
+
     let data = success!(
         backend.get_device(&mut conn, &id).await,
         "Failed getting device"
@@ -61,14 +312,145 @@ pub async fn get_device(backend: Extension<Backend>, Path(id): Path<String>) ->
     )
 }
 
+/// Creates a device. The response is the only place its signing secret is
+/// shown.
+pub async fn create_device(
+    backend: Extension<Backend>,
+    Json(body): Json<NewDevice>,
+) -> impl IntoResponse {
+    if let Err(e) = check_device(
+        Some(&body.name),
+        Some(&body.box_),
+        Some(body.lat),
+        Some(body.long),
+    ) {
+        return bad_request(e);
+    }
+
+    let sensors = match body.sensors {
+        Some(v) if v.is_empty() => return bad_request("sensors must not be empty".to_string()),
+        Some(v) => v,
+        None => Sensor::all(),
+    };
+
+    let mut conn = success!(backend.get_connection().await, "Failed getting connection");
+
+    let (id, secret) = success!(
+        backend
+            .create_device(
+                &mut conn,
+                body.name,
+                body.box_,
+                body.long,
+                body.lat,
+                body.public,
+                &sensors
+            )
+            .await,
+        "Failed creating device"
+    );
+
+    let device = success!(
+        backend.get_device(&mut conn, &id).await,
+        "Failed getting device"
+    );
+
+    let mut data = json!(device);
+    data["secret"] = json!(secret);
+
+    (
+        StatusCode::CREATED,
+        Json(json!({ "success": true, "data": data })),
+    )
+}
+
+/// Renames, regroups or relocates a device.
+pub async fn update_device(
+    backend: Extension<Backend>,
+    Path(id): Path<String>,
+    Json(body): Json<DeviceUpdate>,
+) -> impl IntoResponse {
+    if let Err(e) = check_device(
+        body.name.as_deref(),
+        body.box_.as_deref(),
+        body.lat,
+        body.long,
+    ) {
+        return bad_request(e);
+    }
+
+    let mut conn = success!(backend.get_connection().await, "Failed getting connection");
+
+    let updated = success!(
+        backend.update_device(&mut conn, &id, &body).await,
+        "Failed updating device"
+    );
+
+    if !updated {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "success": false, "message": "Device not found" })),
+        );
+    }
+
+    let device = success!(
+        backend.get_device(&mut conn, &id).await,
+        "Failed getting device"
+    );
+
+    (
+        StatusCode::OK,
+        Json(json!({ "success": true, "data": device })),
+    )
+}
+
+/// Deletes a device, keeping its history. Sessions watching it see it go
+/// offline and its readings are rejected from then on.
+pub async fn delete_device(
+    backend: Extension<Backend>,
+    webhooks: Extension<Arc<Webhooks>>,
+    hub: Extension<Hub>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let mut conn = success!(backend.get_connection().await, "Failed getting connection");
+
+    let deleted = success!(
+        backend.delete_device(&mut conn, &id).await,
+        "Failed deleting device"
+    );
+
+    if !deleted {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "success": false, "message": "Device not found" })),
+        );
+    }
+
+    if let Err(e) = webhooks.reload().await {
+        tracing::error!("Failed reloading webhooks: {:?}", e);
+    }
+
+    hub.publish(HubEvent::DeviceActive(ESPActiveEvent { id, active: false }));
+
+    (StatusCode::OK, Json(json!({ "success": true })))
+}
+
+/// The last record or the history of a device. `select=24H` and `7D` keep
+/// answering with the flat hourly rows they did before rollups, everything
+/// else pages through buckets of the resolution asked for.
 pub async fn get_device_last_reading(
     backend: Extension<Backend>,
+    access: Extension<Access>,
     Path(id): Path<String>,
-    Query(q): Query<LastReadingSelect>,
+    query: Result<Query<ReadingsQuery>, QueryRejection>,
 ) -> impl IntoResponse {
+    let q = query!(query);
+
     let mut conn = success!(backend.get_connection().await, "Failed getting connection");
 
-    if q.select == LastReading::Last {
+    readable!(backend, conn, access, &id);
+
+    if q.select == Some(LastReading::Last) {
         let last_reading = success!(
             backend.get_device_last_record(&mut conn, &id).await,
             "Failed getting last record"
@@ -80,22 +462,283 @@ pub async fn get_device_last_reading(
         );
     }
 
-    let data = success!(
-        backend.get_device_records(&mut conn, q.select, &id).await,
+    let range = match q.range(Local::now()) {
+        Ok(v) => v,
+        Err(e) => return bad_request(e),
+    };
+
+    let cursor = match q.cursor.as_deref().map(str::parse::<HistoryCursor>) {
+        Some(Err(e)) => return bad_request(e),
+        Some(Ok(v)) => Some(v),
+        None => None,
+    };
+
+    let limit = match page_limit(q.limit, DEFAULT_HISTORY_LIMIT) {
+        Ok(v) => v,
+        Err(e) => return bad_request(e),
+    };
+
+    let page = success!(
+        backend
+            .get_device_history(
+                &mut conn,
+                &id,
+                range.from,
+                range.to,
+                range.resolution,
+                &range.metrics,
+                cursor,
+                limit,
+            )
+            .await,
         "Failed to get records"
     );
 
+    if q.select.is_some() {
+        let data = page
+            .data
+            .into_iter()
+            .map(LegacyReadings::from)
+            .collect::<Vec<_>>();
+
+        return (
+            StatusCode::OK,
+            Json(json!({ "success": true, "data": data })),
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "data": page.data,
+            "limit": limit,
+            "next_cursor": page.next_cursor,
+        })),
+    )
+}
+
+/// Ingests readings signed by the device, the same way as readings sent over
+/// the ESP socket. A batch is stored oldest first and every reading in it is
+/// validated on its own, the ones rejected are listed with their index. The
+/// response also holds how many seconds the device clock is behind.
+pub async fn post_device_readings(
+    backend: Extension<Backend>,
+    ctx: Extension<Arc<EspContext>>,
+    tx: Extension<Sender<ESPRecievedEvent>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> impl IntoResponse {
+    store_readings(&*backend, &ctx, &tx, &id, &headers, &body).await
+}
+
+/// [`post_device_readings`] over any store, so it runs without a database in
+/// the tests.
+async fn store_readings<D: DeviceStore>(
+    store: &D,
+    ctx: &EspContext,
+    tx: &Sender<ESPRecievedEvent>,
+    id: &str,
+    headers: &HeaderMap,
+    body: &str,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+    };
+
+    let timestamp = header(DEVICE_TIMESTAMP_HEADER);
+    let nonce = header(DEVICE_NONCE_HEADER);
+
+    let clock = match process_esp::authenticate(
+        store,
+        ctx,
+        "http",
+        id,
+        &format!("{id};{timestamp};{nonce};{body}"),
+        header(DEVICE_SIGNATURE_HEADER),
+        timestamp,
+        nonce,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(reply) => return reply_error(reply),
+    };
+
+    let readings = match body.trim_start().starts_with('[') {
+        true => serde_json::from_str::<Vec<PostedReading>>(body),
+        false => serde_json::from_str::<PostedReading>(body).map(|r| vec![r]),
+    };
+
+    let readings = match readings {
+        Ok(v) => v,
+        Err(e) => return bad_request(e.to_string()),
+    };
+
+    if readings.is_empty() || readings.len() > MAX_BATCH {
+        return bad_request(format!("a batch holds between 1 and {MAX_BATCH} readings"));
+    }
+
+    let now = Local::now();
+    let mut accepted = 0;
+    let mut rejected = Vec::new();
+
+    // Reading times are moved from the device clock onto the server clock.
+    let mut readings = readings
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, r)| {
+            let time = match r.time {
+                Some(t) => clock.server_time(t.to_utc()),
+                None => Some(now),
+            };
+
+            if time.is_none() {
+                rejected.push(json!({ "index": index, "error": ESPReply::BadTime.to_string() }));
+            }
+
+            Some((index, time?, r.values.values()))
+        })
+        .collect::<Vec<_>>();
+    readings.sort_by_key(|(_, time, _)| *time);
+
+    for (index, time, raw) in readings {
+        match process_esp::ingest(store, ctx, "http", id, time, raw, tx).await {
+            ESPReply::Ok => accepted += 1,
+            ESPReply::Db => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({
+                        "success": false,
+                        "message": "Failed storing readings",
+                        "data": { "accepted": accepted },
+                    })),
+                );
+            }
+            reply => rejected.push(json!({ "index": index, "error": reply.to_string() })),
+        }
+    }
+
+    let status = match accepted {
+        0 => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::OK,
+    };
+
+    (
+        status,
+        Json(json!({
+            "success": accepted > 0,
+            "data": {
+                "accepted": accepted,
+                "rejected": rejected,
+                "clock_skew": clock.skew(),
+            },
+        })),
+    )
+}
+
+/// Status of a request whose device or signature was not accepted, the
+/// message is the reply an ESP would get.
+fn reply_error(reply: ESPReply) -> (StatusCode, Json<serde_json::Value>) {
+    let status = match reply {
+        ESPReply::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (
+        status,
+        Json(json!({ "success": false, "message": reply.to_string() })),
+    )
+}
+
+fn page_limit(limit: Option<i64>, default: i64) -> Result<i64, String> {
+    match limit.unwrap_or(default) {
+        limit @ 1..=MAX_LIMIT => Ok(limit),
+        _ => Err(format!("limit must be between 1 and {MAX_LIMIT}")),
+    }
+}
+
+fn bad_request(message: String) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({ "success": false, "message": message })),
+    )
+}
+
+pub async fn get_device_aqi(
+    backend: Extension<Backend>,
+    access: Extension<Access>,
+    Path(id): Path<String>,
+    query: Result<Query<AqiQuery>, QueryRejection>,
+) -> impl IntoResponse {
+    let q = query!(query);
+
+    let mut conn = success!(backend.get_connection().await, "Failed getting connection");
+
+    readable!(backend, conn, access, &id);
+
+    let data = success!(
+        backend.get_device_aqi(&mut conn, &id, q.standard).await,
+        "Failed getting AQI"
+    );
+
     (
         StatusCode::OK,
         Json(json!({ "success": true, "data": data })),
     )
 }
 
-pub async fn get_devices_last_reading(backend: Extension<Backend>) -> impl IntoResponse {
+pub async fn get_devices_aqi(
+    backend: Extension<Backend>,
+    access: Extension<Access>,
+    query: Result<Query<AqiQuery>, QueryRejection>,
+) -> impl IntoResponse {
+    let q = query!(query);
+
     let mut conn = success!(backend.get_connection().await, "Failed getting connection");
 
+    let mut data = success!(
+        backend.get_devices_aqi(&mut conn, q.standard).await,
+        "Failed getting AQI"
+    );
+
+    if access.public_only() {
+        let public = success!(
+            backend.get_public_devices(&mut conn).await,
+            "Failed getting devices"
+        );
+
+        data.retain(|d| public.contains(&d.id));
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({ "success": true, "data": data })),
+    )
+}
+
+pub async fn get_devices_last_reading(
+    backend: Extension<Backend>,
+    access: Extension<Access>,
+) -> impl IntoResponse {
+    let mut conn = success!(backend.get_connection().await, "Failed getting connection");
+
+    let public = match access.public_only() {
+        true => Some(success!(
+            backend.get_public_devices(&mut conn).await,
+            "Failed getting devices"
+        )),
+        false => None,
+    };
+
     let data = success!(
-        backend.get_devices_last_records(&mut conn).await,
+        backend
+            .get_devices_last_records(&mut conn, public.as_deref())
+            .await,
         "Failed getting records"
     );
 
@@ -104,3 +747,546 @@ pub async fn get_devices_last_reading(backend: Extension<Backend>) -> impl IntoR
         Json(json!({ "success": true, "data": data })),
     )
 }
+
+/// Streams the history of the devices in the requested format, one database
+/// page at a time.
+pub async fn export_readings(
+    backend: Extension<Backend>,
+    access: Extension<Access>,
+    query: Result<Query<ExportParams>, QueryRejection>,
+) -> Response {
+    let q = match query {
+        Ok(Query(v)) => v,
+        Err(e) => return bad_request(e.body_text()).into_response(),
+    };
+
+    let devices = q
+        .devices
+        .split(',')
+        .map(|d| d.trim().to_string())
+        .filter(|d| !d.is_empty())
+        .collect::<Vec<_>>();
+
+    if devices.is_empty() {
+        return bad_request("devices must not be empty".to_string()).into_response();
+    }
+
+    let to = q.to.unwrap_or_else(Local::now);
+    let from = q.from.unwrap_or(to - Duration::days(1));
+
+    if from >= to {
+        return bad_request("from must be before to".to_string()).into_response();
+    }
+
+    let format = q.format.unwrap_or(ExportFormat::Csv);
+
+    let query = ExportQuery {
+        devices,
+        from,
+        to,
+        resolution: q
+            .resolution
+            .unwrap_or_else(|| Resolution::for_range(from, to)),
+    };
+
+    let mut conn = match backend.get_connection().await {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("An error has occured: Failed getting connection, {:?}", e);
+
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "success": false, "message": "Failed getting connection" })),
+            )
+                .into_response();
+        }
+    };
+
+    if access.public_only() {
+        let public = match backend.get_public_devices(&mut conn).await {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::error!("An error has occured: Failed getting devices, {:?}", e);
+
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({ "success": false, "message": "Failed getting devices" })),
+                )
+                    .into_response();
+            }
+        };
+
+        if let Some(id) = query.devices.iter().find(|d| !public.contains(d)) {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "success": false, "message": format!("Device {id} not found") })),
+            )
+                .into_response();
+        }
+    }
+
+    let (tx, rx) = channel::<Result<Vec<u8>, ExportError>>(4);
+
+    tokio::spawn(async move {
+        let result = async {
+            let mut exporter = Exporter::new(format, Vec::new())?;
+            let mut pages = ExportPages::new(query);
+
+            loop {
+                let mut conn = backend.get_connection().await?;
+
+                let Some(rows) = pages.next(&backend, &mut conn).await? else {
+                    break;
+                };
+
+                drop(conn);
+
+                exporter.write(&rows)?;
+
+                let chunk = std::mem::take(exporter.get_mut());
+
+                if tx.send(Ok(chunk)).await.is_err() {
+                    // The client went away.
+                    return Ok(());
+                }
+            }
+
+            tx.send(Ok(exporter.finish()?)).await.ok();
+
+            Result::<(), ExportError>::Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            tracing::error!("Failed exporting readings: {:?}", e);
+            tx.send(Err(e)).await.ok();
+        }
+    });
+
+    let body = Body::from_stream(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"readings.{}\"", format.extension()),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+pub async fn get_webhooks(backend: Extension<Backend>) -> impl IntoResponse {
+    let mut conn = success!(backend.get_connection().await, "Failed getting connection");
+
+    let data = success!(
+        backend.list_webhooks(&mut conn).await,
+        "Failed getting webhooks"
+    );
+
+    (
+        StatusCode::OK,
+        Json(json!({ "success": true, "data": data })),
+    )
+}
+
+/// Registers a webhook. The response is the only place its signing secret
+/// is shown.
+pub async fn create_webhook(
+    backend: Extension<Backend>,
+    webhooks: Extension<Arc<Webhooks>>,
+    Json(body): Json<NewWebhook>,
+) -> impl IntoResponse {
+    if !body.url.starts_with("http://") && !body.url.starts_with("https://") {
+        return bad_request("url must start with http(s)://".to_string());
+    }
+
+    if !body.data && !body.device_active {
+        return bad_request("a webhook needs at least one event".to_string());
+    }
+
+    let mut conn = success!(backend.get_connection().await, "Failed getting connection");
+
+    if let Some(id) = &body.device_id {
+        let exists = success!(
+            backend.check_device_exists(&mut conn, id).await,
+            "Failed checking device"
+        );
+
+        if !exists {
+            return bad_request(format!("device '{id}' does not exist"));
+        }
+    }
+
+    let webhook = success!(
+        backend
+            .create_webhook(
+                &mut conn,
+                body.url,
+                body.device_id,
+                body.data,
+                body.device_active
+            )
+            .await,
+        "Failed creating webhook"
+    );
+
+    if let Err(e) = webhooks.reload().await {
+        tracing::error!("Failed reloading webhooks: {:?}", e);
+    }
+
+    let mut data = json!(webhook);
+    data["secret"] = json!(webhook.secret);
+
+    (
+        StatusCode::CREATED,
+        Json(json!({ "success": true, "data": data })),
+    )
+}
+
+pub async fn delete_webhook(
+    backend: Extension<Backend>,
+    webhooks: Extension<Arc<Webhooks>>,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    let mut conn = success!(backend.get_connection().await, "Failed getting connection");
+
+    let deleted = success!(
+        backend.delete_webhook(&mut conn, id).await,
+        "Failed deleting webhook"
+    );
+
+    if !deleted {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "success": false, "message": "Webhook not found" })),
+        );
+    }
+
+    if let Err(e) = webhooks.reload().await {
+        tracing::error!("Failed reloading webhooks: {:?}", e);
+    }
+
+    (StatusCode::OK, Json(json!({ "success": true })))
+}
+
+pub async fn get_webhook_dead_letters(
+    backend: Extension<Backend>,
+    Path(id): Path<i32>,
+    query: Result<Query<PageQuery>, QueryRejection>,
+) -> impl IntoResponse {
+    let q = query!(query);
+
+    let cursor = match q.cursor.as_deref().map(str::parse::<i64>) {
+        Some(Err(_)) => return bad_request("invalid cursor".to_string()),
+        Some(Ok(v)) => Some(v),
+        None => None,
+    };
+
+    let limit = match page_limit(q.limit, DEFAULT_DEAD_LETTERS_LIMIT) {
+        Ok(v) => v,
+        Err(e) => return bad_request(e),
+    };
+
+    let mut conn = success!(backend.get_connection().await, "Failed getting connection");
+
+    let page = success!(
+        backend
+            .get_webhook_dead_letters(&mut conn, id, cursor, limit)
+            .await,
+        "Failed getting dead letters"
+    );
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "data": page.data,
+            "limit": limit,
+            "next_cursor": page.next_cursor,
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use common::{
+        alerts::AlertEngine,
+        signing,
+        validation::{InvalidPolicy, MetricRule, Validator},
+    };
+
+    use crate::process_esp::tests::{context, MemoryStore, DEVICE, SECRET};
+
+    use super::*;
+
+    /// Headers signing `body` for `DEVICE`, stamped now with a random nonce.
+    fn signed(body: &str) -> HeaderMap {
+        let timestamp = Utc::now().timestamp().to_string();
+        let nonce = rand::random::<u64>().to_string();
+        let signature = signing::sign(SECRET, &format!("{DEVICE};{timestamp};{nonce};{body}"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(DEVICE_TIMESTAMP_HEADER, timestamp.parse().unwrap());
+        headers.insert(DEVICE_NONCE_HEADER, nonce.parse().unwrap());
+        headers.insert(DEVICE_SIGNATURE_HEADER, signature.parse().unwrap());
+        headers
+    }
+
+    /// A reading with `co` taken `secs_ago` by the device clock.
+    fn reading(co: f32, secs_ago: i64) -> serde_json::Value {
+        json!({ "co": co, "time": (Utc::now() - Duration::seconds(secs_ago)).to_rfc3339() })
+    }
+
+    async fn post(
+        store: &MemoryStore,
+        ctx: &EspContext,
+        headers: &HeaderMap,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let (tx, _rx) = channel(MAX_BATCH);
+        let (status, Json(response)) = store_readings(store, ctx, &tx, DEVICE, headers, body).await;
+
+        (status, response)
+    }
+
+    #[tokio::test]
+    async fn readings_must_be_signed() {
+        let store = MemoryStore::default();
+        let (ctx, _) = context(Validator::default(), AlertEngine::default());
+        let body = r#"{"co": 1.5}"#;
+        let headers = signed(body);
+
+        let (status, _) = post(&store, &ctx, &HeaderMap::new(), body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = post(&store, &ctx, &headers, r#"{"co": 9.5}"#).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, response) = post(&store, &ctx, &headers, body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["data"]["accepted"], 1);
+
+        // The nonce was used up.
+        let (status, _) = post(&store, &ctx, &headers, body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        assert_eq!(store.records.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn batch_is_stored_oldest_first() {
+        let store = MemoryStore::default();
+        let (ctx, _) = context(Validator::default(), AlertEngine::default());
+        let body = json!([reading(1.0, 10), reading(2.0, 60), reading(3.0, 30)]).to_string();
+
+        let (status, response) = post(&store, &ctx, &signed(&body), &body).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["data"]["accepted"], 3);
+
+        let records = store.records.lock().unwrap();
+        let co = records
+            .iter()
+            .map(|(_, _, record, _)| record.values[Metric::Co.index()])
+            .collect::<Vec<_>>();
+
+        assert_eq!(co, [Some(2.0), Some(3.0), Some(1.0)]);
+        assert!(records.windows(2).all(|w| w[0].1 < w[1].1));
+    }
+
+    #[tokio::test]
+    async fn batch_size_is_limited() {
+        let store = MemoryStore::default();
+        let (ctx, _) = context(Validator::default(), AlertEngine::default());
+
+        let (status, _) = post(&store, &ctx, &signed("[]"), "[]").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let body = json!(vec![json!({ "co": 1.0 }); MAX_BATCH + 1]).to_string();
+        let (status, _) = post(&store, &ctx, &signed(&body), &body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let body = json!(vec![json!({ "co": 1.0 }); MAX_BATCH]).to_string();
+        let (status, response) = post(&store, &ctx, &signed(&body), &body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["data"]["accepted"], MAX_BATCH);
+    }
+
+    #[tokio::test]
+    async fn rejected_readings_keep_their_index() {
+        let store = MemoryStore::default();
+        let mut validator = Validator::default();
+        validator.set_rule(
+            Metric::Co,
+            MetricRule {
+                min: 0.0,
+                max: 100.0,
+                policy: InvalidPolicy::Reject,
+            },
+        );
+        let (ctx, _) = context(validator, AlertEngine::default());
+
+        // Taken after it was signed, out of range, then too old. Unreadable
+        // times are rejected before the batch is stored oldest first.
+        let body = json!([
+            reading(1.0, 20),
+            reading(1.0, -3600),
+            reading(500.0, 10),
+            reading(1.0, 7200)
+        ])
+        .to_string();
+        let (status, response) = post(&store, &ctx, &signed(&body), &body).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["data"]["accepted"], 1);
+        assert_eq!(
+            response["data"]["rejected"],
+            json!([
+                { "index": 1, "error": ESPReply::BadTime.to_string() },
+                { "index": 3, "error": ESPReply::BadTime.to_string() },
+                { "index": 2, "error": ESPReply::BadValue(Metric::Co).to_string() },
+            ])
+        );
+
+        let body = json!([reading(500.0, 10)]).to_string();
+        let (status, response) = post(&store, &ctx, &signed(&body), &body).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response["success"], false);
+    }
+
+    #[tokio::test]
+    async fn failed_store_stops_the_batch() {
+        let store = MemoryStore {
+            fail: true,
+            ..Default::default()
+        };
+        let (ctx, _) = context(Validator::default(), AlertEngine::default());
+        let body = json!([reading(1.0, 20), reading(2.0, 10)]).to_string();
+
+        let (status, response) = post(&store, &ctx, &signed(&body), &body).await;
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response["data"]["accepted"], 0);
+    }
+
+    fn range(query: &str) -> Result<HistoryRange, String> {
+        let uri = format!("/devices/a/readings?{query}").parse().unwrap();
+        let Query(q) = Query::<ReadingsQuery>::try_from_uri(&uri).unwrap();
+
+        q.range(at(12))
+    }
+
+    fn at(hour: u32) -> DateTime<Local> {
+        DateTime::parse_from_rfc3339(&format!("2026-10-18T{hour:02}:00:00Z"))
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    fn range_of(query: &str) -> HistoryRange {
+        range(query).unwrap()
+    }
+
+    #[test]
+    fn history_defaults_to_the_last_day() {
+        let range = range_of("");
+
+        assert_eq!((range.from, range.to), (at(12) - Duration::days(1), at(12)));
+        assert_eq!(
+            range.resolution,
+            Resolution::for_range(range.from, range.to)
+        );
+        assert!(range.metrics.is_empty());
+
+        let range = range_of("to=2026-10-18T06:00:00Z");
+        assert_eq!((range.from, range.to), (at(6) - Duration::days(1), at(6)));
+    }
+
+    #[test]
+    fn presets_are_hourly_unless_a_resolution_is_given() {
+        let day = range_of("select=24H");
+        assert_eq!(day.from, at(12) - Duration::days(1));
+        assert_eq!(day.resolution, Resolution::Hour);
+
+        let week = range_of("select=7D");
+        assert_eq!(week.from, at(12) - Duration::days(7));
+        assert_eq!(week.resolution, Resolution::Hour);
+
+        // A preset wins over from.
+        let week = range_of("select=7D&from=2026-10-18T11:00:00Z&resolution=1d");
+        assert_eq!(week.from, at(12) - Duration::days(7));
+        assert_eq!(week.resolution, Resolution::Day);
+    }
+
+    #[test]
+    fn range_resolution_and_metrics_are_parsed() {
+        let range = range_of(
+            "from=2026-10-18T01:00:00Z&to=2026-10-18T03:00:00Z&resolution=5m&metrics=co2,%20pm_25,",
+        );
+
+        assert_eq!((range.from, range.to), (at(1), at(3)));
+        assert_eq!(range.resolution, Resolution::FiveMinutes);
+        assert_eq!(range.metrics, vec![Metric::Co2, Metric::Pm25]);
+    }
+
+    #[test]
+    fn bad_ranges_and_metrics_are_rejected() {
+        for query in [
+            "from=2026-10-18T03:00:00Z&to=2026-10-18T01:00:00Z",
+            "from=2026-10-18T03:00:00Z&to=2026-10-18T03:00:00Z",
+            "from=2026-10-18T13:00:00Z",
+        ] {
+            assert_eq!(range(query).unwrap_err(), "from must be before to");
+        }
+
+        assert_eq!(
+            range("metrics=co2,ozone").unwrap_err(),
+            "unknown metric 'ozone'"
+        );
+    }
+
+    #[test]
+    fn device_fields_are_checked_against_their_columns() {
+        assert_eq!(check_device(None, None, None, None), Ok(()));
+        assert_eq!(
+            check_device(Some("kitchen"), Some("v2"), Some(-90.0), Some(180.0)),
+            Ok(())
+        );
+
+        let long_name = "a".repeat(256);
+        for name in ["", "   ", long_name.as_str()] {
+            assert_eq!(
+                check_device(Some(name), None, None, None).unwrap_err(),
+                "name must be between 1 and 255 bytes"
+            );
+        }
+
+        let long_box = "a".repeat(101);
+        for box_ in ["", long_box.as_str()] {
+            assert_eq!(
+                check_device(None, Some(box_), None, None).unwrap_err(),
+                "box must be between 1 and 100 bytes"
+            );
+        }
+
+        for lat in [90.5, -91.0, f32::NAN] {
+            assert_eq!(
+                check_device(None, None, Some(lat), None).unwrap_err(),
+                "lat must be between -90 and 90"
+            );
+        }
+
+        for long in [180.5, -181.0, f32::NAN] {
+            assert_eq!(
+                check_device(None, None, None, Some(long)).unwrap_err(),
+                "long must be between -180 and 180"
+            );
+        }
+    }
+}
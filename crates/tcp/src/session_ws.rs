@@ -1,9 +1,4 @@
-use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
 
 use axum::{
     extract::{
@@ -13,113 +8,104 @@ use axum::{
     response::IntoResponse,
     Extension,
 };
-use common::Backend;
-use futures::{future, SinkExt, StreamExt, TryStreamExt};
-use tokio::sync::mpsc::{channel, Sender};
+use chrono::{DateTime, Local};
+use common::{device::DeviceFilter, readings::RawReading, Backend};
+use futures::{future, Sink, SinkExt, StreamExt, TryStreamExt};
 
 use crate::{
-    error::XError,
-    models::{ESPActiveEvent, ESPRecievedEvent, SessionType, WsMessage},
+    auth::Access,
+    error::{XError, XResult},
+    hub::{Hub, HubEvent, Received, Watcher},
+    models::{
+        ESPRecievedEvent, Outgoing, PmValues, ResumeFrom, SessionType, SubscriptionAck, WsMessage,
+    },
 };
 
-enum EventType {
-    Data(ESPRecievedEvent),
-    Active(ESPActiveEvent),
-}
+/// Most stored readings replayed to a resuming session.
+const REPLAY_LIMIT: i64 = 1000;
 
 pub async fn handler(
     ws: WebSocketUpgrade,
     backend: Extension<Backend>,
-    sessions: Extension<
-        Arc<
-            Mutex<
-                HashMap<
-                    u64,
-                    (
-                        SessionType,
-                        (Sender<ESPRecievedEvent>, Sender<ESPActiveEvent>),
-                    ),
-                >,
-            >,
-        >,
-    >,
+    hub: Extension<Hub>,
+    Extension(access): Extension<Access>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
 ) -> impl IntoResponse {
     tracing::info!("Session connected");
 
-    ws.on_upgrade(move |socket| handle_socket(socket, sessions, backend, addr))
+    ws.on_upgrade(move |socket| handle_socket(socket, hub, backend, access, addr))
 }
 
+/// Sessions without an API key only watch the devices flagged public.
 pub async fn handle_socket(
     socket: WebSocket,
-    sessions: Extension<
-        Arc<
-            Mutex<
-                HashMap<
-                    u64,
-                    (
-                        SessionType,
-                        (Sender<ESPRecievedEvent>, Sender<ESPActiveEvent>),
-                    ),
-                >,
-            >,
-        >,
-    >,
+    hub: Extension<Hub>,
     backend: Extension<Backend>,
+    access: Access,
     addr: SocketAddr,
 ) {
-    loop {
-        let session_id = rand::random();
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
 
-        let (tx, mut rx) = channel::<ESPRecievedEvent>(1);
-        let (tx2, mut rx2) = channel::<ESPActiveEvent>(2);
+    interval.tick().await;
 
-        let mut interval = tokio::time::interval(Duration::from_secs(5));
+    let mut stream = socket
+        // Pings and close frames are answered by axum itself.
+        .try_filter(|x| future::ready(matches!(x, Message::Text(_) | Message::Binary(_))))
+        .and_then(|x| {
+            future::ok(serde_json::from_slice::<WsMessage>(
+                x.into_data().as_slice(),
+            ))
+        })
+        .map::<Result<_, XError>, _>(|x| {
+            x.map_err(XError::Axum)
+                .and_then(|x| x.map_err(XError::Serde))
+        })
+        .with::<Outgoing, _, _, _>(|x| {
+            future::ok::<_, XError>({
+                let json = serde_json::to_string(&x).unwrap();
 
-        interval.tick().await;
-
-        let mut stream = socket
-            .and_then(|x| {
-                future::ok(serde_json::from_slice::<WsMessage>(
-                    x.into_data().as_slice(),
-                ))
-            })
-            .map::<Result<_, XError>, _>(|x| {
-                x.map_err(|e| XError::Axum(e))
-                    .and_then(|x| x.map_err(|e| XError::Serde(e)))
+                Message::Text(json)
             })
-            .with::<WsMessage, _, _, _>(|x| {
-                future::ok::<_, XError>({
-                    let json = serde_json::to_string(&x).unwrap();
+        });
 
-                    Message::Text(json)
-                })
-            });
+    let msg = match stream
+        .next()
+        .await
+        .ok_or(XError::ConnectionBroken)
+        .and_then(|v| v)
+    {
+        Ok(n) => n,
+        Err(e) => {
+            tracing::error!("Failed to read message from stream: {:?}", e);
+            return;
+        }
+    };
 
-        let msg = match stream
-            .next()
-            .await
-            .ok_or(XError::ConnectionBroken)
-            .and_then(|v| v)
-        {
-            Ok(n) => n,
-            Err(e) => {
-                tracing::error!("Failed to read message from stream: {:?}", e);
-                return;
-            }
-        };
+    let mut watcher = Watcher::new(hub.0.clone());
 
-        let session_type = match msg {
-            WsMessage::Identify(v) => v,
-            _ => {
-                tracing::error!("Unexpected message/type");
-                return;
-            }
-        };
+    // The first message says what to watch, by identifying as before or by
+    // subscribing. Either way the last records of those devices follow.
+    let replies = match msg {
+        WsMessage::Identify(SessionType::Main) if access.public_only() => {
+            let ids = match public_devices(&backend).await {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::error!("Failed to get public devices: {:?}", e);
+                    return;
+                }
+            };
 
-        // Validation
+            for id in &ids {
+                watcher.watch(id);
+            }
 
-        if let SessionType::Child(id) = &session_type {
+            vec![snapshot(&backend, Some(&ids)).await]
+        }
+        WsMessage::Identify(SessionType::Main) => {
+            watcher.watch_all();
+            vec![snapshot(&backend, None).await]
+        }
+        WsMessage::Identify(SessionType::Child(id)) => {
             let mut conn = match backend.get_connection().await {
                 Ok(c) => c,
                 Err(e) => {
@@ -128,10 +114,20 @@ pub async fn handle_socket(
                 }
             };
 
-            match backend.check_device_exists(&mut conn, &id).await {
+            let readable = match access.public_only() {
+                true => backend.check_device_public(&mut conn, &id).await,
+                false => backend.check_device_exists(&mut conn, &id).await,
+            };
+
+            match readable {
                 Ok(c) => {
                     if !c {
                         tracing::error!("Device with the id of '{id}' does not exist.");
+                        // Private devices look like missing ones to callers
+                        // without a key, as over HTTP.
+                        send_all(&mut stream, vec![error("Device not found")])
+                            .await
+                            .ok();
                         return;
                     }
                 }
@@ -140,62 +136,285 @@ pub async fn handle_socket(
                     return;
                 }
             }
+
+            drop(conn);
+
+            watcher.watch(&id);
+            vec![snapshot(&backend, Some(&[id])).await]
+        }
+        WsMessage::Subscribe(filter) => {
+            change_subscription(&backend, access, &mut watcher, filter, true).await
         }
+        _ => {
+            tracing::error!("Unexpected message/type");
+            return;
+        }
+    };
 
-        sessions
-            .lock()
-            .unwrap()
-            .insert(session_id, (session_type, (tx, tx2)));
-
-        loop {
-            let event = tokio::select! {
-                _ = interval.tick() => {
-                    match stream.send(WsMessage::KeepAlive).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            tracing::warn!("[{}] Lost connection: {:?}", addr, e);
-                            sessions.lock().unwrap().remove(&session_id);
-                            return;
-                        }
-                    };
-                    continue;
-                },
-                v1 = rx.recv() => match v1 {
-                    Some(msg) => EventType::Data(msg),
-                    None => {
-                        tracing::warn!("[{}] RX closed", addr);
-                        sessions.lock().unwrap().remove(&session_id);
-                        return;
-                    }
-                },
-                v2 = rx2.recv() => match v2 {
-                    Some(msg) => EventType::Active(msg),
-                    None => {
-                        tracing::warn!("[{}] RX2 closed", addr);
-                        sessions.lock().unwrap().remove(&session_id);
-                        return;
-                    }
+    if let Err(e) = send_all(&mut stream, replies).await {
+        tracing::warn!("[{}] Lost connection: {:?}", addr, e);
+        return;
+    }
+
+    loop {
+        let replies = tokio::select! {
+            _ = interval.tick() => vec![WsMessage::KeepAlive.into()],
+            received = watcher.recv() => match received {
+                Received::Event(published) => vec![(*published).into()],
+                Received::Missed(missed) => {
+                    tracing::warn!("[{}] Session fell behind, dropped {} events", addr, missed);
+                    vec![WsMessage::Lagged { missed }.into()]
                 }
-            };
+            },
+            incoming = stream.next() => match incoming {
+                Some(Ok(message)) => handle_message(&backend, &hub, access, &mut watcher, message).await,
+                Some(Err(XError::Serde(e))) => vec![error(&e.to_string())],
+                Some(Err(e)) => {
+                    tracing::warn!("[{}] Lost connection: {:?}", addr, e);
+                    return;
+                }
+                None => {
+                    tracing::info!("[{}] Session closed", addr);
+                    return;
+                }
+            },
+        };
 
-            match event {
-                EventType::Data(msg) => match stream.send(WsMessage::Data(msg)).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        tracing::warn!("[{}] Lost connection: {:?}", addr, e);
-                        sessions.lock().unwrap().remove(&session_id);
-                        return;
-                    }
-                },
-                EventType::Active(msg) => match stream.send(WsMessage::DeviceActive(msg)).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        tracing::warn!("[{}] Lost connection: {:?}", addr, e);
-                        sessions.lock().unwrap().remove(&session_id);
-                        return;
-                    }
-                },
+        if let Err(e) = send_all(&mut stream, replies).await {
+            tracing::warn!("[{}] Lost connection: {:?}", addr, e);
+            return;
+        }
+    }
+}
+
+async fn send_all<S>(stream: &mut S, messages: Vec<Outgoing>) -> Result<(), XError>
+where
+    S: Sink<Outgoing, Error = XError> + Unpin,
+{
+    for message in messages {
+        stream.feed(message).await?;
+    }
+
+    stream.flush().await
+}
+
+fn error(message: &str) -> Outgoing {
+    WsMessage::Error {
+        message: message.to_string(),
+    }
+    .into()
+}
+
+/// The replies to a message sent once the session started.
+async fn handle_message(
+    backend: &Backend,
+    hub: &Hub,
+    access: Access,
+    watcher: &mut Watcher,
+    message: WsMessage,
+) -> Vec<Outgoing> {
+    match message {
+        WsMessage::Subscribe(filter) => {
+            change_subscription(backend, access, watcher, filter, true).await
+        }
+        WsMessage::Unsubscribe(filter) => {
+            change_subscription(backend, access, watcher, filter, false).await
+        }
+        WsMessage::Resume { resume_from } => resume(backend, hub, watcher, resume_from).await,
+        WsMessage::KeepAlive => Vec::new(),
+        WsMessage::Identify(_) => vec![error("session already identified")],
+        _ => vec![error("unexpected message")],
+    }
+}
+
+/// The last records of `ids`, of every device when `None`.
+async fn snapshot(backend: &Backend, ids: Option<&[String]>) -> Outgoing {
+    let records = async {
+        let mut conn = backend
+            .get_connection()
+            .await
+            .map_err(|e| XError::DB(e.to_string()))?;
+
+        backend
+            .get_devices_last_records(&mut conn, ids)
+            .await
+            .map_err(|e| XError::DB(e.to_string()))
+    };
+
+    match records.await {
+        Ok(records) => WsMessage::Snapshot(records).into(),
+        Err(e) => {
+            tracing::error!("Failed to get last records: {:?}", e);
+            error("failed to get last records")
+        }
+    }
+}
+
+/// Replays the events of the watched devices since `from`, those buffered
+/// by the hub and, for a time before the buffer, the stored readings. Status
+/// changes and alerts older than the buffer are lost, as are readings past
+/// the oldest [`REPLAY_LIMIT`].
+async fn resume(
+    backend: &Backend,
+    hub: &Hub,
+    watcher: &Watcher,
+    from: ResumeFrom,
+) -> Vec<Outgoing> {
+    let (replay, since) = match from {
+        ResumeFrom::Seq(seq) => match hub.replay_after(seq) {
+            Some(replay) => (replay, None),
+            None => return vec![error("unknown seq, resume from a time instead")],
+        },
+        ResumeFrom::Time(since) => (hub.replay_since(since), Some(since)),
+    };
+
+    let mut replies = Vec::new();
+    let mut truncated = false;
+
+    if let (false, Some(since)) = (replay.complete, since) {
+        // Readings stored before the first buffered event was published may
+        // still be buffered, those are left to the buffer.
+        let buffered = replay
+            .events
+            .iter()
+            .filter_map(|p| match &p.event {
+                HubEvent::Data(data) => Some((data.id.as_str(), data.time)),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        let until = replay.events.first().map_or_else(Local::now, |p| p.at);
+
+        match stored_readings(backend, watcher.devices(), since, until).await {
+            Ok(readings) => {
+                truncated = readings.len() as i64 >= REPLAY_LIMIT;
+
+                replies.extend(
+                    readings
+                        .into_iter()
+                        .filter(|(id, reading)| {
+                            watcher.watches(id)
+                                && !buffered.contains(&(id.as_str(), reading.created_at))
+                        })
+                        .map(|(id, reading)| {
+                            WsMessage::Data(ESPRecievedEvent {
+                                id,
+                                data: PmValues::from(reading.values()),
+                                time: reading.created_at,
+                                aqi: None,
+                            })
+                            .into()
+                        }),
+                );
+            }
+            Err(e) => {
+                truncated = true;
+                tracing::error!("Failed to get readings to replay: {:?}", e);
             }
         }
     }
+
+    replies.extend(
+        replay
+            .events
+            .into_iter()
+            .filter(|p| watcher.watches(p.event.device_id()))
+            .map(Outgoing::from),
+    );
+
+    replies.push(
+        WsMessage::Resumed {
+            replayed: replies.len(),
+            complete: replay.complete && !truncated,
+        }
+        .into(),
+    );
+
+    replies
+}
+
+async fn stored_readings(
+    backend: &Backend,
+    ids: Option<Vec<String>>,
+    since: DateTime<Local>,
+    until: DateTime<Local>,
+) -> XResult<Vec<(String, RawReading)>> {
+    let mut conn = backend
+        .get_connection()
+        .await
+        .map_err(|e| XError::DB(e.to_string()))?;
+
+    backend
+        .get_readings_between(&mut conn, ids.as_deref(), since, until, REPLAY_LIMIT)
+        .await
+        .map_err(|e| XError::DB(e.to_string()))
+}
+
+/// Starts or stops watching the devices matching `filter`.
+async fn change_subscription(
+    backend: &Backend,
+    access: Access,
+    watcher: &mut Watcher,
+    filter: DeviceFilter,
+    subscribe: bool,
+) -> Vec<Outgoing> {
+    let ids = match find_devices(backend, &filter, access.public_only()).await {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("Failed to look up devices: {:?}", e);
+            return vec![error("failed to look up devices")];
+        }
+    };
+
+    for id in &ids {
+        match subscribe {
+            true => watcher.watch(id),
+            false => watcher.unwatch(id),
+        }
+    }
+
+    let unknown = filter
+        .ids
+        .into_iter()
+        .filter(|id| !ids.contains(id))
+        .collect();
+
+    if !subscribe {
+        return vec![WsMessage::Unsubscribed(SubscriptionAck { ids, unknown }).into()];
+    }
+
+    let snapshot = snapshot(backend, Some(&ids)).await;
+
+    vec![
+        WsMessage::Subscribed(SubscriptionAck { ids, unknown }).into(),
+        snapshot,
+    ]
+}
+
+async fn find_devices(
+    backend: &Backend,
+    filter: &DeviceFilter,
+    public_only: bool,
+) -> XResult<Vec<String>> {
+    let mut conn = backend
+        .get_connection()
+        .await
+        .map_err(|e| XError::DB(e.to_string()))?;
+
+    backend
+        .find_devices(&mut conn, filter, public_only)
+        .await
+        .map_err(|e| XError::DB(e.to_string()))
+}
+
+async fn public_devices(backend: &Backend) -> XResult<Vec<String>> {
+    let mut conn = backend
+        .get_connection()
+        .await
+        .map_err(|e| XError::DB(e.to_string()))?;
+
+    backend
+        .get_public_devices(&mut conn)
+        .await
+        .map_err(|e| XError::DB(e.to_string()))
 }
@@ -0,0 +1,189 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use common::config::TlsSettings;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+
+/// Builds the rustls config for a listener, advertising `alpn`.
+pub fn server_config(settings: &TlsSettings, alpn: &[&[u8]]) -> Result<Arc<ServerConfig>, String> {
+    let certs = load_certs(&settings.cert)?;
+    let key = load_key(&settings.key)?;
+
+    let builder = ServerConfig::builder();
+
+    let builder = match &settings.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("Invalid client CA {}: {e}", path.display()))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| format!("Invalid client CA {}: {e}", path.display()))?;
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate or key: {e}"))?;
+
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed opening {}: {e}", path.display()))?;
+
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed reading {}: {e}", path.display()))
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed opening {}: {e}", path.display()))?;
+
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("Failed reading {}: {e}", path.display()))?
+        .ok_or_else(|| format!("No private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedKey, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::{pki_types::ServerName, ClientConfig};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::*;
+
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tls-test-{}-{name}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Self-signed server certificate, a client CA and a client certificate
+    /// it signed.
+    struct Certs {
+        server: CertifiedKey,
+        ca: CertifiedKey,
+        client: CertifiedKey,
+    }
+
+    impl Certs {
+        fn generate() -> Self {
+            let server = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca_key = KeyPair::generate().unwrap();
+            let ca = CertifiedKey {
+                cert: params.self_signed(&ca_key).unwrap(),
+                key_pair: ca_key,
+            };
+
+            let mut params = CertificateParams::new(vec!["device".to_string()]).unwrap();
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let client_key = KeyPair::generate().unwrap();
+            let client = CertifiedKey {
+                cert: params
+                    .signed_by(&client_key, &ca.cert, &ca.key_pair)
+                    .unwrap(),
+                key_pair: client_key,
+            };
+
+            Self { server, ca, client }
+        }
+
+        fn settings(&self, prefix: &str, client_ca: bool) -> TlsSettings {
+            TlsSettings {
+                cert: temp_file(&format!("{prefix}-cert.pem"), &self.server.cert.pem()),
+                key: temp_file(
+                    &format!("{prefix}-key.pem"),
+                    &self.server.key_pair.serialize_pem(),
+                ),
+                client_ca: client_ca
+                    .then(|| temp_file(&format!("{prefix}-ca.pem"), &self.ca.cert.pem())),
+            }
+        }
+
+        fn client(&self, with_cert: bool) -> Arc<ClientConfig> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.server.cert.der().clone()).unwrap();
+
+            let builder = ClientConfig::builder().with_root_certificates(roots);
+
+            let config = match with_cert {
+                true => builder
+                    .with_client_auth_cert(
+                        vec![self.client.cert.der().clone()],
+                        PrivateKeyDer::try_from(self.client.key_pair.serialize_der()).unwrap(),
+                    )
+                    .unwrap(),
+                false => builder.with_no_client_auth(),
+            };
+
+            Arc::new(config)
+        }
+    }
+
+    /// Whether the server accepted a handshake with the client.
+    async fn handshake(server: Arc<ServerConfig>, client: Arc<ClientConfig>) -> bool {
+        let (server_io, client_io) = tokio::io::duplex(16 * 1024);
+        let name = ServerName::try_from("localhost").unwrap();
+
+        let (accepted, _) = tokio::join!(
+            TlsAcceptor::from(server).accept(server_io),
+            TlsConnector::from(client).connect(name, client_io),
+        );
+
+        accepted.is_ok()
+    }
+
+    fn remove(settings: TlsSettings) {
+        for path in [Some(settings.cert), Some(settings.key), settings.client_ca]
+            .into_iter()
+            .flatten()
+        {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn self_signed_certificate_completes_a_handshake() {
+        let certs = Certs::generate();
+        let settings = certs.settings("plain", false);
+
+        let config = server_config(&settings, &[b"http/1.1"]).unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+        assert!(handshake(config, certs.client(false)).await);
+
+        remove(settings);
+    }
+
+    #[tokio::test]
+    async fn client_certificate_is_required_with_a_client_ca() {
+        let certs = Certs::generate();
+        let settings = certs.settings("mtls", true);
+
+        let config = server_config(&settings, &[]).unwrap();
+        assert!(handshake(config.clone(), certs.client(true)).await);
+        assert!(!handshake(config, certs.client(false)).await);
+
+        remove(settings);
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use chrono::Utc;
+use common::{config::WebhooksConfig, signing, webhooks::Webhook, Backend};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::{
+    error::{XError, XResult},
+    models::WsMessage,
+};
+
+/// Header with the id of the webhook a delivery belongs to.
+pub const ID_HEADER: &str = "X-Webhook-Id";
+/// Header with the unix time the delivery was signed at.
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+/// Header with the hex HMAC-SHA256 of `{timestamp}.{body}`, keyed with the
+/// secret of the webhook.
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Delivers `Data` and `DeviceActive` events to the subscribed webhooks. The
+/// subscriptions are kept in memory and reloaded whenever they change.
+pub struct Webhooks {
+    backend: Backend,
+    client: reqwest::Client,
+    config: WebhooksConfig,
+    subscriptions: RwLock<Vec<Subscription>>,
+}
+
+/// A webhook with the queue of the task delivering to it, which ends once
+/// the subscription is dropped and the queue is drained.
+struct Subscription {
+    webhook: Webhook,
+    queue: Sender<Arc<String>>,
+}
+
+impl Webhooks {
+    pub fn new(backend: Backend, config: WebhooksConfig) -> Arc<Self> {
+        Arc::new(Self {
+            backend,
+            client: reqwest::Client::builder()
+                .timeout(config.timeout())
+                .build()
+                .expect("Failed building HTTP client"),
+            config,
+            subscriptions: RwLock::new(Vec::new()),
+        })
+    }
+
+    /// Loads the webhooks again, keeping the queues of those still there.
+    pub async fn reload(self: &Arc<Self>) -> XResult<()> {
+        let mut conn = self
+            .backend
+            .get_connection()
+            .await
+            .map_err(|e| XError::DB(e.to_string()))?;
+
+        let webhooks = self
+            .backend
+            .list_webhooks(&mut conn)
+            .await
+            .map_err(|e| XError::DB(e.to_string()))?;
+
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        let mut previous = std::mem::take(&mut *subscriptions);
+
+        *subscriptions = webhooks
+            .into_iter()
+            .map(
+                |webhook| match previous.iter().position(|s| s.webhook.id == webhook.id) {
+                    Some(i) => previous.swap_remove(i),
+                    None => {
+                        let (queue, rx) = channel(self.config.queue_size);
+                        tokio::spawn(self.clone().work(webhook.clone(), rx));
+
+                        Subscription { webhook, queue }
+                    }
+                },
+            )
+            .collect();
+
+        Ok(())
+    }
+
+    /// Queues the event for every webhook subscribed to it. Other messages
+    /// are ignored.
+    pub fn publish(&self, device_id: &str, message: &WsMessage) {
+        let subscribed = |w: &Webhook| match message {
+            WsMessage::Data(_) => w.data,
+            WsMessage::DeviceActive(_) => w.device_active,
+            _ => false,
+        };
+
+        let subscriptions = self.subscriptions.read().unwrap();
+        let mut body = None;
+
+        for Subscription { webhook, queue } in subscriptions
+            .iter()
+            .filter(|s| {
+                s.webhook
+                    .device_id
+                    .as_deref()
+                    .is_none_or(|id| id == device_id)
+            })
+            .filter(|s| subscribed(&s.webhook))
+        {
+            let body = body
+                .get_or_insert_with(|| Arc::new(serde_json::to_string(message).unwrap()))
+                .clone();
+
+            if queue.try_send(body).is_err() {
+                tracing::error!(
+                    "Webhook {} is {} events behind, dropped an event of {}",
+                    webhook.id,
+                    self.config.queue_size,
+                    device_id
+                );
+            }
+        }
+    }
+
+    /// Delivers the queued events of a webhook one after the other.
+    async fn work(self: Arc<Self>, webhook: Webhook, mut rx: Receiver<Arc<String>>) {
+        while let Some(body) = rx.recv().await {
+            self.deliver(&webhook, &body).await;
+        }
+    }
+
+    /// Tries the delivery until it succeeds or runs out of attempts, in which
+    /// case the payload is stored as a dead letter.
+    async fn deliver(&self, webhook: &Webhook, body: &str) {
+        let mut attempt = 1;
+
+        let error = loop {
+            let error = match send(&self.client, webhook, body).await {
+                Ok(_) => return,
+                Err(e) => e.to_string(),
+            };
+
+            if attempt >= self.config.max_attempts {
+                break error;
+            }
+
+            tracing::warn!(
+                "Webhook {} delivery attempt {} failed: {}",
+                webhook.id,
+                attempt,
+                error
+            );
+
+            tokio::time::sleep(backoff(self.config.initial_backoff(), attempt)).await;
+            attempt += 1;
+        };
+
+        tracing::error!(
+            "Webhook {} delivery failed after {} attempts: {}",
+            webhook.id,
+            attempt,
+            error
+        );
+
+        let mut conn = match self.backend.get_connection().await {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!("Failed to get connection: {:?}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self
+            .backend
+            .add_webhook_dead_letter(&mut conn, webhook.id, body, attempt as i32, &error)
+            .await
+        {
+            tracing::error!("Failed storing dead letter: {:?}", e);
+        }
+    }
+}
+
+/// Wait before the retry following `attempt`, doubling every time.
+fn backoff(initial: Duration, attempt: u32) -> Duration {
+    initial * 2u32.pow(attempt - 1)
+}
+
+/// A single signed POST of `body`.
+async fn send(client: &reqwest::Client, webhook: &Webhook, body: &str) -> XResult<()> {
+    let timestamp = Utc::now().timestamp();
+    let signature = signing::sign(&webhook.secret, &format!("{timestamp}.{body}"));
+
+    client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(ID_HEADER, webhook.id)
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, signature)
+        .body(body.to_string())
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| XError::Sink(e.to_string()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::HeaderMap, routing::post, Extension, Router};
+    use chrono::Local;
+    use tokio::{
+        net::TcpListener,
+        sync::mpsc::{channel, Sender},
+    };
+
+    use super::*;
+
+    fn webhook(url: String) -> Webhook {
+        Webhook {
+            id: 1,
+            url,
+            device_id: None,
+            data: true,
+            device_active: true,
+            secret: signing::generate_secret(),
+            created_at: Local::now(),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let initial = Duration::from_secs(1);
+
+        assert_eq!(
+            (1..=4)
+                .map(|a| backoff(initial, a).as_secs())
+                .collect::<Vec<_>>(),
+            [1, 2, 4, 8]
+        );
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        let (tx, mut rx) = channel::<(HeaderMap, String)>(1);
+
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |tx: Extension<Sender<(HeaderMap, String)>>,
+                     headers: HeaderMap,
+                     body: String| async move {
+                        tx.send((headers, body)).await.unwrap();
+                    },
+                ),
+            )
+            .layer(Extension(tx));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let webhook = webhook(format!("http://{addr}/hook"));
+        send(
+            &reqwest::Client::new(),
+            &webhook,
+            "{\"type\":\"keep_alive\"}",
+        )
+        .await
+        .unwrap();
+
+        let (headers, body) = rx.recv().await.unwrap();
+        let header = |name| headers[name].to_str().unwrap();
+
+        assert_eq!(header(ID_HEADER), "1");
+        assert_eq!(body, "{\"type\":\"keep_alive\"}");
+        assert!(signing::verify(
+            &webhook.secret,
+            &format!("{}.{}", header(TIMESTAMP_HEADER), body),
+            header(SIGNATURE_HEADER)
+        )
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, Router::new()).await.unwrap() });
+
+        let webhook = webhook(format!("http://{addr}/missing"));
+
+        assert!(send(&reqwest::Client::new(), &webhook, "{}").await.is_err());
+    }
+}
@@ -0,0 +1,66 @@
+-- This file should undo anything in `up.sql`
+UPDATE hour_records SET
+    co = COALESCE(co, 'NaN'),
+    co2 = COALESCE(co2, 'NaN'),
+    noise = COALESCE(noise, 'NaN'),
+    temperature = COALESCE(temperature, 'NaN'),
+    humidity = COALESCE(humidity, 'NaN'),
+    pm_10 = COALESCE(pm_10, 'NaN'),
+    pm_25 = COALESCE(pm_25, 'NaN'),
+    pm_100 = COALESCE(pm_100, 'NaN'),
+    pm_particles_03 = COALESCE(pm_particles_03, 'NaN'),
+    pm_particles_05 = COALESCE(pm_particles_05, 'NaN'),
+    pm_particles_10 = COALESCE(pm_particles_10, 'NaN'),
+    pm_particles_25 = COALESCE(pm_particles_25, 'NaN'),
+    pm_particles_50 = COALESCE(pm_particles_50, 'NaN'),
+    pm_particles_100 = COALESCE(pm_particles_100, 'NaN');
+
+ALTER TABLE hour_records
+    ALTER COLUMN co SET NOT NULL,
+    ALTER COLUMN co2 SET NOT NULL,
+    ALTER COLUMN noise SET NOT NULL,
+    ALTER COLUMN temperature SET NOT NULL,
+    ALTER COLUMN humidity SET NOT NULL,
+    ALTER COLUMN pm_10 SET NOT NULL,
+    ALTER COLUMN pm_25 SET NOT NULL,
+    ALTER COLUMN pm_100 SET NOT NULL,
+    ALTER COLUMN pm_particles_03 SET NOT NULL,
+    ALTER COLUMN pm_particles_05 SET NOT NULL,
+    ALTER COLUMN pm_particles_10 SET NOT NULL,
+    ALTER COLUMN pm_particles_25 SET NOT NULL,
+    ALTER COLUMN pm_particles_50 SET NOT NULL,
+    ALTER COLUMN pm_particles_100 SET NOT NULL,
+    DROP COLUMN quality;
+
+UPDATE last_record SET
+    co = COALESCE(co, 'NaN'),
+    co2 = COALESCE(co2, 'NaN'),
+    noise = COALESCE(noise, 'NaN'),
+    temperature = COALESCE(temperature, 'NaN'),
+    humidity = COALESCE(humidity, 'NaN'),
+    pm_10 = COALESCE(pm_10, 'NaN'),
+    pm_25 = COALESCE(pm_25, 'NaN'),
+    pm_100 = COALESCE(pm_100, 'NaN'),
+    pm_particles_03 = COALESCE(pm_particles_03, 'NaN'),
+    pm_particles_05 = COALESCE(pm_particles_05, 'NaN'),
+    pm_particles_10 = COALESCE(pm_particles_10, 'NaN'),
+    pm_particles_25 = COALESCE(pm_particles_25, 'NaN'),
+    pm_particles_50 = COALESCE(pm_particles_50, 'NaN'),
+    pm_particles_100 = COALESCE(pm_particles_100, 'NaN');
+
+ALTER TABLE last_record
+    ALTER COLUMN co SET NOT NULL,
+    ALTER COLUMN co2 SET NOT NULL,
+    ALTER COLUMN noise SET NOT NULL,
+    ALTER COLUMN temperature SET NOT NULL,
+    ALTER COLUMN humidity SET NOT NULL,
+    ALTER COLUMN pm_10 SET NOT NULL,
+    ALTER COLUMN pm_25 SET NOT NULL,
+    ALTER COLUMN pm_100 SET NOT NULL,
+    ALTER COLUMN pm_particles_03 SET NOT NULL,
+    ALTER COLUMN pm_particles_05 SET NOT NULL,
+    ALTER COLUMN pm_particles_10 SET NOT NULL,
+    ALTER COLUMN pm_particles_25 SET NOT NULL,
+    ALTER COLUMN pm_particles_50 SET NOT NULL,
+    ALTER COLUMN pm_particles_100 SET NOT NULL,
+    DROP COLUMN quality;
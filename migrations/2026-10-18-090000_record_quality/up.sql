@@ -0,0 +1,68 @@
+-- Values that failed validation are stored as NULL, `quality` has bit n set
+-- when the nth value of the frame was dropped or clamped.
+
+ALTER TABLE hour_records
+    ALTER COLUMN co DROP NOT NULL,
+    ALTER COLUMN co2 DROP NOT NULL,
+    ALTER COLUMN noise DROP NOT NULL,
+    ALTER COLUMN temperature DROP NOT NULL,
+    ALTER COLUMN humidity DROP NOT NULL,
+    ALTER COLUMN pm_10 DROP NOT NULL,
+    ALTER COLUMN pm_25 DROP NOT NULL,
+    ALTER COLUMN pm_100 DROP NOT NULL,
+    ALTER COLUMN pm_particles_03 DROP NOT NULL,
+    ALTER COLUMN pm_particles_05 DROP NOT NULL,
+    ALTER COLUMN pm_particles_10 DROP NOT NULL,
+    ALTER COLUMN pm_particles_25 DROP NOT NULL,
+    ALTER COLUMN pm_particles_50 DROP NOT NULL,
+    ALTER COLUMN pm_particles_100 DROP NOT NULL,
+    ADD COLUMN quality INTEGER NOT NULL DEFAULT 0;
+
+UPDATE hour_records SET
+    co = NULLIF(co, 'NaN'),
+    co2 = NULLIF(co2, 'NaN'),
+    noise = NULLIF(noise, 'NaN'),
+    temperature = NULLIF(temperature, 'NaN'),
+    humidity = NULLIF(humidity, 'NaN'),
+    pm_10 = NULLIF(pm_10, 'NaN'),
+    pm_25 = NULLIF(pm_25, 'NaN'),
+    pm_100 = NULLIF(pm_100, 'NaN'),
+    pm_particles_03 = NULLIF(pm_particles_03, 'NaN'),
+    pm_particles_05 = NULLIF(pm_particles_05, 'NaN'),
+    pm_particles_10 = NULLIF(pm_particles_10, 'NaN'),
+    pm_particles_25 = NULLIF(pm_particles_25, 'NaN'),
+    pm_particles_50 = NULLIF(pm_particles_50, 'NaN'),
+    pm_particles_100 = NULLIF(pm_particles_100, 'NaN');
+
+ALTER TABLE last_record
+    ALTER COLUMN co DROP NOT NULL,
+    ALTER COLUMN co2 DROP NOT NULL,
+    ALTER COLUMN noise DROP NOT NULL,
+    ALTER COLUMN temperature DROP NOT NULL,
+    ALTER COLUMN humidity DROP NOT NULL,
+    ALTER COLUMN pm_10 DROP NOT NULL,
+    ALTER COLUMN pm_25 DROP NOT NULL,
+    ALTER COLUMN pm_100 DROP NOT NULL,
+    ALTER COLUMN pm_particles_03 DROP NOT NULL,
+    ALTER COLUMN pm_particles_05 DROP NOT NULL,
+    ALTER COLUMN pm_particles_10 DROP NOT NULL,
+    ALTER COLUMN pm_particles_25 DROP NOT NULL,
+    ALTER COLUMN pm_particles_50 DROP NOT NULL,
+    ALTER COLUMN pm_particles_100 DROP NOT NULL,
+    ADD COLUMN quality INTEGER NOT NULL DEFAULT 0;
+
+UPDATE last_record SET
+    co = NULLIF(co, 'NaN'),
+    co2 = NULLIF(co2, 'NaN'),
+    noise = NULLIF(noise, 'NaN'),
+    temperature = NULLIF(temperature, 'NaN'),
+    humidity = NULLIF(humidity, 'NaN'),
+    pm_10 = NULLIF(pm_10, 'NaN'),
+    pm_25 = NULLIF(pm_25, 'NaN'),
+    pm_100 = NULLIF(pm_100, 'NaN'),
+    pm_particles_03 = NULLIF(pm_particles_03, 'NaN'),
+    pm_particles_05 = NULLIF(pm_particles_05, 'NaN'),
+    pm_particles_10 = NULLIF(pm_particles_10, 'NaN'),
+    pm_particles_25 = NULLIF(pm_particles_25, 'NaN'),
+    pm_particles_50 = NULLIF(pm_particles_50, 'NaN'),
+    pm_particles_100 = NULLIF(pm_particles_100, 'NaN');
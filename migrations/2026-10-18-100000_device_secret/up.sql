@@ -0,0 +1,9 @@
+-- Key for the HMAC every ingestion frame is signed with. Existing devices get
+-- a random secret which has to be rotated with `rotate-device-secret` before
+-- their firmware can be provisioned with it.
+
+ALTER TABLE devices ADD COLUMN secret VARCHAR(64);
+
+UPDATE devices SET secret = replace(gen_random_uuid()::text || gen_random_uuid()::text, '-', '');
+
+ALTER TABLE devices ALTER COLUMN secret SET NOT NULL;
@@ -0,0 +1,23 @@
+-- Rollups of every reading, one row per device, bucket size, metric and
+-- bucket. `sum` and `sum_squares` are kept instead of the mean and deviation
+-- so rows can be updated one reading at a time and merged into larger buckets.
+--
+-- The sampled hourly history in `hour_records` is not copied in, one sample
+-- an hour is not a rollup of the readings of that hour. It is left as it
+-- was, dropping it is up to a migration of its own.
+
+CREATE TABLE aggregates (
+    fk_device_id                VARCHAR(255)                NOT NULL,
+    bucket_secs                 INTEGER                     NOT NULL,
+    bucket                      TIMESTAMP(6) WITH TIME ZONE NOT NULL,
+    metric                      VARCHAR(32)                 NOT NULL,
+
+    count                       INTEGER                     NOT NULL,
+    sum                         DOUBLE PRECISION            NOT NULL,
+    sum_squares                 DOUBLE PRECISION            NOT NULL,
+    min                         REAL                        NOT NULL,
+    max                         REAL                        NOT NULL,
+
+    PRIMARY KEY (fk_device_id, bucket_secs, metric, bucket),
+    FOREIGN KEY (fk_device_id)  REFERENCES devices (id)
+);
@@ -0,0 +1,31 @@
+-- Every accepted reading, kept until the retention job deletes it. The job
+-- rolls these up into 5 minute aggregates, those into hourly and the hourly
+-- ones into daily buckets.
+
+CREATE TABLE readings (
+    id                          BIGSERIAL                   PRIMARY KEY,
+    fk_device_id                VARCHAR(255)                NOT NULL,
+
+    co                          REAL,
+    co2                         REAL,
+    temperature                 REAL,
+    humidity                    REAL,
+    noise                       REAL,
+    pm_10                       REAL,
+    pm_25                       REAL,
+    pm_100                      REAL,
+    pm_particles_03             REAL,
+    pm_particles_05             REAL,
+    pm_particles_10             REAL,
+    pm_particles_25             REAL,
+    pm_particles_50             REAL,
+    pm_particles_100            REAL,
+    quality                     INTEGER                     NOT NULL DEFAULT 0,
+
+    created_at                  TIMESTAMP(6) WITH TIME ZONE NOT NULL,
+
+    FOREIGN KEY (fk_device_id)  REFERENCES devices (id)
+);
+
+CREATE INDEX readings_device_created_at ON readings (fk_device_id, created_at);
+CREATE INDEX readings_created_at ON readings (created_at);
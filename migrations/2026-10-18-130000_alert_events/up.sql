@@ -0,0 +1,18 @@
+-- Every time an alert rule started or stopped firing for a device. The last
+-- event of a rule and device is its current state.
+
+CREATE TABLE alert_events (
+    id                          BIGSERIAL                   PRIMARY KEY,
+    rule                        VARCHAR(64)                 NOT NULL,
+    fk_device_id                VARCHAR(255)                NOT NULL,
+    metric                      VARCHAR(32)                 NOT NULL,
+    state                       VARCHAR(16)                 NOT NULL,
+    value                       REAL                        NOT NULL,
+    threshold                   REAL                        NOT NULL,
+
+    created_at                  TIMESTAMP(6) WITH TIME ZONE NOT NULL,
+
+    FOREIGN KEY (fk_device_id)  REFERENCES devices (id)
+);
+
+CREATE INDEX alert_events_rule_device ON alert_events (rule, fk_device_id, created_at);
@@ -0,0 +1,30 @@
+-- Outgoing webhook subscriptions. A subscription without a device receives
+-- the events of every device.
+
+CREATE TABLE webhooks (
+    id                          SERIAL                      PRIMARY KEY,
+    url                         TEXT                        NOT NULL,
+    fk_device_id                VARCHAR(255),
+    data                        BOOLEAN                     NOT NULL DEFAULT TRUE,
+    device_active               BOOLEAN                     NOT NULL DEFAULT TRUE,
+    secret                      VARCHAR(64)                 NOT NULL,
+
+    created_at                  TIMESTAMP(6) WITH TIME ZONE NOT NULL DEFAULT NOW(),
+
+    FOREIGN KEY (fk_device_id)  REFERENCES devices (id)
+);
+
+-- Deliveries that still failed after the last retry.
+CREATE TABLE webhook_dead_letters (
+    id                          BIGSERIAL                   PRIMARY KEY,
+    fk_webhook_id               INTEGER                     NOT NULL,
+    payload                     TEXT                        NOT NULL,
+    attempts                    INTEGER                     NOT NULL,
+    error                       TEXT                        NOT NULL,
+
+    created_at                  TIMESTAMP(6) WITH TIME ZONE NOT NULL,
+
+    FOREIGN KEY (fk_webhook_id) REFERENCES webhooks (id) ON DELETE CASCADE
+);
+
+CREATE INDEX webhook_dead_letters_webhook ON webhook_dead_letters (fk_webhook_id, id);
@@ -0,0 +1,9 @@
+-- Devices with readings stored after the rollup already moved past their
+-- bucket. The next rollup recomputes the buckets of the device from `since`.
+
+CREATE TABLE rollup_backfills (
+    fk_device_id                VARCHAR(255)                PRIMARY KEY,
+    since                       TIMESTAMP(6) WITH TIME ZONE NOT NULL,
+
+    FOREIGN KEY (fk_device_id)  REFERENCES devices (id)
+);
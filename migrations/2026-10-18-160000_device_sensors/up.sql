@@ -0,0 +1,23 @@
+-- The metrics each device reports. Values of metrics a device has no sensor
+-- for are not stored. Every existing device keeps the stock profile.
+
+CREATE TABLE device_sensors (
+    fk_device_id                VARCHAR(255)                NOT NULL,
+    metric                      VARCHAR(32)                 NOT NULL,
+    unit                        VARCHAR(16)                 NOT NULL,
+    model                       VARCHAR(64),
+
+    PRIMARY KEY (fk_device_id, metric),
+    FOREIGN KEY (fk_device_id)  REFERENCES devices (id)
+);
+
+INSERT INTO device_sensors (fk_device_id, metric, unit)
+    SELECT devices.id, m.metric, m.unit
+    FROM devices
+    CROSS JOIN (VALUES
+        ('co', 'ppm'), ('co2', 'ppm'), ('temperature', '°C'), ('humidity', '%'),
+        ('noise', 'dB'), ('pm_10', 'µg/m³'), ('pm_25', 'µg/m³'), ('pm_100', 'µg/m³'),
+        ('pm_particles_03', '/0.1L'), ('pm_particles_05', '/0.1L'),
+        ('pm_particles_10', '/0.1L'), ('pm_particles_25', '/0.1L'),
+        ('pm_particles_50', '/0.1L'), ('pm_particles_100', '/0.1L')
+    ) AS m(metric, unit);
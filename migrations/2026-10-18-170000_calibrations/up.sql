@@ -0,0 +1,23 @@
+-- Versions of the correction of each metric of a device. A reading is
+-- corrected with the newest version of every metric effective by the time
+-- it was taken.
+
+CREATE TABLE calibrations (
+    id                          SERIAL                      PRIMARY KEY,
+    fk_device_id                VARCHAR(255)                NOT NULL,
+    metric                      VARCHAR(32)                 NOT NULL,
+
+    coefficients                REAL[]                      NOT NULL,
+    humidity_kappa              REAL,
+
+    effective_from              TIMESTAMP(6) WITH TIME ZONE NOT NULL,
+    created_at                  TIMESTAMP(6) WITH TIME ZONE NOT NULL DEFAULT NOW(),
+
+    UNIQUE (fk_device_id, metric, effective_from),
+    FOREIGN KEY (fk_device_id)  REFERENCES devices (id)
+);
+
+-- The values as the device sent them, in frame order, when any of them was
+-- corrected. Readings without are stored as sent.
+
+ALTER TABLE readings ADD COLUMN raw REAL[];
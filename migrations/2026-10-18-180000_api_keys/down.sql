@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE devices DROP COLUMN public;
+
+DROP TABLE api_keys;
@@ -0,0 +1,18 @@
+-- Keys for the REST API and the WebSocket. Only the SHA-256 of a key is
+-- kept, it is shown once when created.
+
+CREATE TABLE api_keys (
+    id                          VARCHAR(25)                 PRIMARY KEY,
+    name                        VARCHAR(255)                NOT NULL,
+    role                        VARCHAR(16)                 NOT NULL,
+    key_hash                    VARCHAR(64)                 NOT NULL UNIQUE,
+
+    created_at                  TIMESTAMP(6) WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    revoked_at                  TIMESTAMP(6) WITH TIME ZONE
+);
+
+-- Public devices are readable without a key. Every existing device was
+-- readable by anyone and stays so, new ones are private until flagged.
+ALTER TABLE devices ADD COLUMN public BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE devices SET public = TRUE;
@@ -0,0 +1,4 @@
+-- Deleted devices are kept along with their readings, rollups and alerts,
+-- they only stop being listed and accepting readings.
+
+ALTER TABLE devices ADD COLUMN deleted_at TIMESTAMP(6) WITH TIME ZONE;
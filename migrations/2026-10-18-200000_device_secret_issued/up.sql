@@ -0,0 +1,5 @@
+-- When the secret of a device was last handed out. Devices from before frames
+-- were signed got a random secret nobody has, they stay without one until it
+-- is rotated and may send unsigned v1 frames while `esp.unsigned_until` lasts.
+
+ALTER TABLE devices ADD COLUMN secret_issued_at TIMESTAMP(6) WITH TIME ZONE;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER calibrations_changed ON calibrations;
+DROP TRIGGER device_sensors_changed ON device_sensors;
+DROP FUNCTION notify_device_changed();
@@ -0,0 +1,22 @@
+-- Servers keep the sensors and calibrations of every device in memory, they
+-- forget those of a device when it is notified here, whoever changed them.
+
+CREATE FUNCTION notify_device_changed() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('device_changed', OLD.fk_device_id);
+    ELSE
+        PERFORM pg_notify('device_changed', NEW.fk_device_id);
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER device_sensors_changed
+    AFTER INSERT OR UPDATE OR DELETE ON device_sensors
+    FOR EACH ROW EXECUTE FUNCTION notify_device_changed();
+
+CREATE TRIGGER calibrations_changed
+    AFTER INSERT OR UPDATE OR DELETE ON calibrations
+    FOR EACH ROW EXECUTE FUNCTION notify_device_changed();